@@ -1,38 +1,176 @@
 #[mockall_double::double]
 use super::db::Database;
-use crate::models::{auth::*, generic::Error};
+use super::local;
+use super::oidc::{pkce_challenge, pkce_verifier, Provider};
+use crate::models::{
+    auth::*,
+    generic::{Error, ErrorKind},
+};
 use actix_identity::RequestIdentity;
+use actix_web::web::Data;
 use actix_web::{
-    body::MessageBody,
+    body::{BoxBody, MessageBody},
     dev::{ServiceRequest, ServiceResponse},
     error::Error as AError,
-    HttpResponse,
+    http::Method,
+    HttpResponse, ResponseError,
 };
 use actix_web_lab::middleware::Next;
-use chrono::prelude::*;
-use jsonwebtoken::{decode, decode_header, DecodingKey, Validation};
+use chrono::{prelude::*, Duration};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
 use rand::{distributions::Alphanumeric, Rng};
-use ureq::get;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use url::Url;
 
+/// Sessions are renewed with their refresh token this many seconds before they expire.
+const RENEW_BEFORE: i64 = 300;
+
+/// Expired sessions with a refresh token are kept this many hours, in case it renews them.
+const RENEWABLE_FOR: i64 = 24;
+
+/// Logins not finished within this many minutes are swept.
+const LOGIN_TIMEOUT: i64 = 60;
+
+/// The default leeway, in seconds, for the time claims of ID tokens.
+pub const DEFAULT_LEEWAY: u64 = 60;
+
+/// API tokens start with this, which tells them from session keys.
+pub const TOKEN_PREFIX: &str = "irt_";
+
+/// Whether the identity of a request is an API token rather than a session key.
+pub fn is_api_token(identity: &str) -> bool {
+    identity.starts_with(TOKEN_PREFIX)
+}
+
+/// The hex SHA-256 of an API token, as it is stored.
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// The claims of an ID token, as far as we use them.
+#[derive(Deserialize)]
+struct IdClaims {
+    /// Only in the tokens issued for a login, not in the refreshed ones.
+    nonce: Option<String>,
+    iat: i64,
+    #[serde(flatten)]
+    token: Token,
+    /// The other claims, among them the groups of the user.
+    #[serde(flatten)]
+    other: HashMap<String, Value>,
+}
+
+/// A validated ID token, and the groups of the user it lists.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdToken {
+    pub token: Token,
+    pub groups: Vec<String>,
+}
+
+/// The tokens a session was renewed with.
+struct Renewed {
+    token: Token,
+    groups: Vec<String>,
+    access_token: Option<String>,
+    refresh_token: Option<String>,
+}
+
 // #[derive(Debug)]
 pub struct AuthHandler {
-    #[allow(dead_code)]
-    client_secret: String,
-    client_id: String,
     host: String,
+    /// The providers users can log in with, the first one by default.
+    providers: Vec<Provider>,
+    /// The emails of the users who are always admins, whatever their assigned role.
+    admins: Vec<String>,
+    /// How many seconds the clocks of the providers may be off.
+    leeway: u64,
+    /// Whether users can log in with local accounts too.
+    local_accounts: bool,
 }
 
 impl AuthHandler {
-    pub fn new(host: String, client_secret: String, client_id: String) -> Self {
+    pub fn new(host: String) -> Self {
         AuthHandler {
-            client_secret,
-            client_id,
             host,
+            providers: vec![],
+            admins: vec![],
+            leeway: DEFAULT_LEEWAY,
+            local_accounts: false,
+        }
+    }
+
+    pub fn with_local_accounts(self, local_accounts: bool) -> Self {
+        AuthHandler {
+            local_accounts,
+            ..self
+        }
+    }
+
+    pub fn local_accounts(&self) -> bool {
+        self.local_accounts
+    }
+
+    pub fn with_leeway(self, leeway: u64) -> Self {
+        AuthHandler { leeway, ..self }
+    }
+
+    pub fn with_providers(self, providers: Vec<Provider>) -> Self {
+        AuthHandler { providers, ..self }
+    }
+
+    pub fn provider_names(&self) -> Vec<String> {
+        self.providers
+            .iter()
+            .map(|p| p.name().to_string())
+            .collect()
+    }
+
+    /// The provider with this name, or the default one.
+    pub fn provider(&self, name: Option<&str>) -> Result<&Provider, Error> {
+        match name {
+            Some(name) => self
+                .providers
+                .iter()
+                .find(|p| p.name() == name)
+                .ok_or_else(|| {
+                    Error::validation(format!("Unknown login provider {}", name))
+                        .with_details(json!({ "providers": self.provider_names() }))
+                }),
+            None => self
+                .providers
+                .first()
+                .ok_or_else(|| Error::internal("No login provider is configured")),
         }
     }
 
-    pub async fn login(&self, db: &Database, referrer: &str) -> Result<url::Url, Error> {
+    pub fn with_admins(self, admins: Vec<String>) -> Self {
+        AuthHandler { admins, ..self }
+    }
+
+    pub fn is_admin(&self, email: &str) -> bool {
+        self.admins.iter().any(|a| a.eq_ignore_ascii_case(email))
+    }
+
+    /// Where providers send users back to with a code.
+    fn redirect_uri(&self) -> String {
+        format!("{}/api/v1/auth/authorize", self.host)
+    }
+
+    /// The URL to log in at the provider, or the default one.
+    pub async fn login(
+        &self,
+        db: &Database,
+        provider: Option<&str>,
+        referrer: &str,
+    ) -> Result<url::Url, Error> {
+        let provider = self.provider(provider)?;
+
         // Create random state
         // To be saved in the browser
         let session: String = rand::thread_rng()
@@ -48,233 +186,619 @@ impl AuthHandler {
             .map(char::from)
             .collect();
 
+        // The code can only be exchanged with the verifier, which never leaves the server
+        let verifier = pkce_verifier();
+
         // Save session in the database
         db.add_session(
             session.clone(),
             SessionRecord {
                 token: None,
                 nonce: nonce.clone(),
+                provider: Some(provider.name().to_string()),
+                verifier: Some(verifier.clone()),
+                access_token: None,
+                refresh_token: None,
+                created_at: Some(Utc::now()),
+                groups: vec![],
+                referrer: Some(referrer.to_string()),
             },
         )
         .await?;
         // Create URL to get auth code
         let u = Url::parse_with_params(
-            "https://login.microsoftonline.com/common/oauth2/v2.0/authorize",
+            &provider.discovery.authorization_endpoint,
             &[
-                ("client_id", self.client_id.as_str()),
-                ("response_type", "id_token"),
-                (
-                    "redirect_uri",
-                    &format!("{}/api/v1/auth/authorize", self.host),
-                ),
-                ("response_mode", "form_post"),
-                ("scope", "openid profile email"),
-                ("state", &format!("State={}&Referrer={}", session, referrer)),
+                ("client_id", provider.config.client_id.as_str()),
+                ("response_type", "code"),
+                ("redirect_uri", &self.redirect_uri()),
+                ("scope", provider.config.scopes.as_str()),
+                ("state", &session),
                 ("nonce", &nonce),
+                ("code_challenge", &pkce_challenge(&verifier)),
+                ("code_challenge_method", "S256"),
             ],
         )
-        .map_err(Error::default)?;
+        .map_err(Error::internal)?;
 
         // Return url and state
         Ok(u)
     }
 
-    pub async fn validate_token(&self, db: &Database, jwt: &str, state: &str) -> Result<(), Error> {
+    /// Finish the login of the session: exchange the code for tokens and validate the ID token.
+    /// Returns where the login started from, to send the user back to.
+    pub async fn authorize(
+        &self,
+        db: &Database,
+        code: &str,
+        state: &str,
+    ) -> Result<Option<String>, Error> {
         // 1. Retreive the pkce verifier, using the state
-        let s = db.get_session(state.to_string()).await?;
-
-        // 2 decode jwt
-        // 2.a get the kId from the header
-        let jwt_header = decode_header(jwt).map_err(Error::default)?;
-        let kid = jwt_header.kid.expect("jwt header should have a kid");
-
-        // 2.b retrieve the key from the ms endpoint using the kId
-        let key = get("https://login.microsoftonline.com/common/discovery/v2.0/keys")
-            .set("kid", &kid)
-            .call()
-            .map_err(Error::default)?
-            .into_json::<jsonwebtoken::jwk::JwkSet>()
-            .map_err(Error::default)?
-            .keys
-            .iter()
-            .find(|k| k.common.key_id == Some(kid.clone()))
-            .ok_or_else(|| Error::default("JWKS is empty"))?
-            .clone();
+        // Both it and the nonce are taken from the session, so a login cannot be replayed
+        let s = db
+            .take_login(state.to_string())
+            .await
+            .map_err(|e| match e.kind() {
+                ErrorKind::NotFound => Error::unauthorized("Unknown login session"),
+                _ => e,
+            })?;
+        let verifier = match s.verifier.as_deref() {
+            Some(verifier) if !s.nonce.is_empty() => verifier,
+            _ => return Err(TokenError::Replayed.into()),
+        };
+
+        // Sessions from before providers were named are from the default one
+        let provider = self.provider(s.provider.as_deref())?;
+
+        // 2. Exchange the code, then validate the ID token with the nonce
+        let tokens = provider
+            .exchange(code, verifier, &self.redirect_uri())
+            .await?;
+        let jwt = tokens
+            .id_token
+            .as_deref()
+            .ok_or_else(|| Error::upstream("The login provider sent no ID token"))?;
+        let id = self
+            .validate_token(provider, jwt, Some(s.nonce.as_str()))
+            .await?;
+
+        // 3. Save the tokens in the session, the nonce, the verifier and the referrer are used up
+        let referrer = s.referrer.clone();
+        let (email, provider) = (id.token.preferred_username.clone(), s.provider.clone());
+        db.update_api_token_groups(&email, provider, id.groups.clone())
+            .await?;
+        db.update_session(
+            state.to_string(),
+            SessionRecord {
+                token: Some(id.token),
+                groups: id.groups,
+                nonce: String::new(),
+                verifier: None,
+                access_token: tokens.access_token,
+                refresh_token: tokens.refresh_token,
+                referrer: None,
+                ..s
+            },
+        )
+        .await?;
+        Ok(referrer)
+    }
+
+    /// Validate an ID token of the provider, and its nonce if there is one to check.
+    pub async fn validate_token(
+        &self,
+        provider: &Provider,
+        jwt: &str,
+        nonce: Option<&str>,
+    ) -> Result<IdToken, Error> {
+        // 1. decode jwt
+        // 1.a get the kId from the header
+        let jwt_header = decode_header(jwt).map_err(|_| TokenError::Malformed)?;
+        let kid = jwt_header.kid.ok_or(TokenError::MissingKid)?;
+
+        // 1.b retrieve the key from the provider using the kId, cached until the provider rotates it
+        let key = provider.key(&kid).await?;
         let decode_key: DecodingKey = match key.algorithm {
             jsonwebtoken::jwk::AlgorithmParameters::RSA(p) => {
-                Ok(DecodingKey::from_rsa_components(&p.n, &p.e).map_err(Error::default)?)
+                DecodingKey::from_rsa_components(&p.n, &p.e).map_err(Error::upstream)?
             }
-            _ => Err(Error::default("Unsupported alogirthm")),
-        }?;
+            _ => return Err(TokenError::UnsupportedAlgorithm.into()),
+        };
 
-        // 2.c actually decode the jwt...
-        let validator = Validation::new(jwt_header.alg);
-        let jwt_decoded =
-            decode::<SessionRecord>(jwt, &decode_key, &validator).map_err(Error::default)?;
+        // 1.c actually decode the jwt...
+        // Only RSA signatures, so the public key can never be used as an HMAC secret
+        if !matches!(
+            jwt_header.alg,
+            Algorithm::RS256
+                | Algorithm::RS384
+                | Algorithm::RS512
+                | Algorithm::PS256
+                | Algorithm::PS384
+                | Algorithm::PS512
+        ) {
+            return Err(TokenError::UnsupportedAlgorithm.into());
+        }
+        // The token must be issued by the provider the login started at, to us, and be current
+        let mut validator = Validation::new(jwt_header.alg);
+        validator.set_audience(&[&provider.config.client_id]);
+        validator.set_issuer(&[&provider.discovery.issuer]);
+        validator.set_required_spec_claims(&["exp", "iat", "iss", "aud"]);
+        validator.validate_nbf = true;
+        validator.leeway = self.leeway;
+        // Decoded as any JSON first: jsonwebtoken deserializes the claims before it validates
+        // them, so a token lacking a required claim would fail as invalid, not as missing it
+        let jwt_decoded = decode::<Value>(jwt, &decode_key, &validator)
+            .map_err(|e| Error::from(TokenError::from(e)))?;
+        // jsonwebtoken only checks the presence of the claims it validates itself
+        if !jwt_decoded.claims["iat"].is_number() {
+            return Err(TokenError::MissingClaim.into());
+        }
+        let claims: IdClaims =
+            serde_json::from_value(jwt_decoded.claims).map_err(|_| TokenError::Invalid)?;
+        if claims.iat > Utc::now().timestamp() + self.leeway as i64 {
+            return Err(TokenError::NotYetValid.into());
+        }
 
-        // 2.d Validate the jwt using the nonce
-        if jwt_decoded.claims.nonce != s.nonce {
-            return Err(Error::default("Failed to validate jwt nonce"));
+        // 1.d Validate the jwt using the nonce
+        if nonce.is_some() && claims.nonce.as_deref() != nonce {
+            return Err(TokenError::Nonce.into());
         };
 
-        let mut session_record = jwt_decoded.claims;
-        session_record
-            .token
-            .as_mut()
-            .expect("Failed to parse token")
-            .token = Some(jwt.to_string());
+        // 1.e The groups, one or a list of them
+        let groups = match claims.other.get(&provider.config.groups_claim) {
+            Some(Value::Array(groups)) => groups
+                .iter()
+                .filter_map(|g| g.as_str().map(String::from))
+                .collect(),
+            Some(Value::String(group)) => vec![group.clone()],
+            _ => vec![],
+        };
 
-        // 3. Return the Access token. To be saved in the session
-        // 3a. Save the token information in the database?
+        Ok(IdToken {
+            token: Token {
+                token: Some(jwt.to_string()),
+                ..claims.token
+            },
+            groups,
+        })
+    }
+
+    /// Log the session out, deleting it. When asked, and the provider supports it, returns
+    /// where to log out at the provider too, which then redirects to the referrer.
+    pub async fn logout(
+        &self,
+        db: &Database,
+        session: String,
+        end_session: bool,
+        referrer: &str,
+    ) -> Result<Option<Url>, Error> {
+        let s = match db.get_session(session.clone()).await {
+            Ok(s) => s,
+            // Already logged out, or revoked
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        db.delete_session(session).await?;
+        if !end_session || s.provider.as_deref() == Some(local::PROVIDER) {
+            return Ok(None);
+        }
+        let provider = self.provider(s.provider.as_deref())?;
+        let endpoint = match &provider.discovery.end_session_endpoint {
+            Some(endpoint) => endpoint,
+            None => return Ok(None),
+        };
+        let mut params = vec![
+            ("client_id", provider.config.client_id.clone()),
+            (
+                "post_logout_redirect_uri",
+                format!("{}{}", self.host, referrer),
+            ),
+        ];
+        if let Some(jwt) = s.token.and_then(|t| t.token) {
+            params.push(("id_token_hint", jwt));
+        }
+        Url::parse_with_params(endpoint, &params)
+            .map(Some)
+            .map_err(Error::upstream)
+    }
+
+    /// Whether the session is logged in, renewing it first if it is about to expire.
+    pub async fn is_logged_in(&self, db: &Database, session: String) -> Result<bool, Error> {
+        if is_api_token(&session) {
+            return match self.api_token(db, &session).await {
+                Ok(_) => Ok(true),
+                Err(e) if e.kind() == ErrorKind::Unauthorized => Ok(false),
+                Err(e) => Err(e),
+            };
+        }
+        let s = match db.get_session(session.clone()).await {
+            Ok(s) => s,
+            // Logged out, revoked or swept
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        let exp = match &s.token {
+            Some(t) => t.exp,
+            None => return Ok(false),
+        };
+        if s.refresh_token.is_none() || exp - Utc::now() > Duration::seconds(RENEW_BEFORE) {
+            return Ok(exp > Utc::now());
+        }
+        match self.renew(db, session, s).await {
+            Ok(logged_in) => Ok(logged_in),
+            Err(e) => {
+                log::debug!("Unable to renew the session: {:?}", e);
+                Ok(exp > Utc::now())
+            }
+        }
+    }
+
+    /// Get new tokens for the session with its refresh token.
+    ///
+    /// The refresh token is taken from the session first, so of concurrent requests only one
+    /// renews it: providers rotating refresh tokens refuse one used twice. The others fail
+    /// with a conflict, and go on with the session as it is.
+    /// Returns whether the session is still logged in: it is not if the ID token is of another user.
+    async fn renew(&self, db: &Database, session: String, s: SessionRecord) -> Result<bool, Error> {
+        let provider = self.provider(s.provider.as_deref())?;
+        let refresh_token = s.refresh_token.clone().unwrap_or_default();
+        if !db
+            .take_refresh_token(session.clone(), refresh_token.clone())
+            .await?
+        {
+            return Err(Error::conflict("The session is being renewed"));
+        }
+        let renewed = match self.refresh(provider, &refresh_token, &s).await {
+            Ok(renewed) => renewed,
+            Err(e) => {
+                // Put back unless refused, to try again with the next request
+                if e.kind() != ErrorKind::Unauthorized {
+                    db.update_session(session, s).await?;
+                }
+                return Err(e);
+            }
+        };
+        let user = s.token.as_ref().map(|t| t.preferred_username.as_str());
+        if user != Some(renewed.token.preferred_username.as_str()) {
+            log::error!(
+                "Renewing the session of {:?} gave an ID token of {}",
+                user,
+                renewed.token.preferred_username
+            );
+            db.delete_session(session).await?;
+            return Ok(false);
+        }
+        db.update_api_token_groups(
+            &renewed.token.preferred_username,
+            s.provider.clone(),
+            renewed.groups.clone(),
+        )
+        .await?;
         db.update_session(
-            state.to_string(),
-            session_record.token.expect("Failed to parse token"),
+            session,
+            SessionRecord {
+                token: Some(renewed.token),
+                groups: renewed.groups,
+                access_token: renewed.access_token.or(s.access_token),
+                // Providers rotating refresh tokens send a new one
+                refresh_token: renewed.refresh_token.or(s.refresh_token),
+                ..s
+            },
         )
         .await
-        .map_err(Error::default)
-        .map(|_| ())
+        .map(|_| true)
     }
 
-    pub async fn is_logged_in(&self, db: &Database, session: String) -> Result<bool, Error> {
-        db.get_session(session).await?.token.map_or(Ok(false), |t| {
-            log::debug!("{:?}", t.exp);
-            Ok(t.exp.cmp(&Utc::now()).is_gt())
+    /// Exchange the refresh token of the session for new tokens.
+    async fn refresh(
+        &self,
+        provider: &Provider,
+        refresh_token: &str,
+        s: &SessionRecord,
+    ) -> Result<Renewed, Error> {
+        let tokens = provider.refresh(refresh_token).await?;
+        let id = match (&tokens.id_token, s.token.clone(), tokens.expires_in) {
+            (Some(jwt), _, _) => self.validate_token(provider, jwt, None).await?,
+            // Without a new ID token, the session lasts as long as the new access token
+            (None, Some(token), Some(secs)) => IdToken {
+                token: Token {
+                    exp: Utc::now() + Duration::seconds(secs),
+                    ..token
+                },
+                groups: s.groups.clone(),
+            },
+            _ => return Err(Error::upstream("The login provider renewed no token")),
+        };
+        Ok(Renewed {
+            token: id.token,
+            groups: id.groups,
+            access_token: tokens.access_token,
+            refresh_token: tokens.refresh_token,
         })
     }
 
+    /// Lets through logged in users only.
     pub async fn auth_middleware(
         req: ServiceRequest,
         next: Next<impl MessageBody + 'static>,
     ) -> Result<ServiceResponse<impl MessageBody>, AError> {
-        // get session cookie
-        let session_cookie = req.get_identity();
-        if session_cookie.is_none() {
-            let resp = HttpResponse::Unauthorized().finish().map_into_boxed_body();
-            let (request, _) = req.into_parts();
-            return Ok(ServiceResponse::new(request, resp));
-        };
-
-        let session_str = session_cookie.unwrap();
-
-        // get db
-        let db = req
-            .app_data::<actix_web::web::Data<Database>>()
-            .ok_or_else(|| Error::default("Unable to get Database"))?;
+        Self::require(Role::Viewer, req, next).await
+    }
 
-        // Get AuthHandler
-        let auth_handler = req
-            .app_data::<actix_web::web::Data<AuthHandler>>()
-            .ok_or_else(|| Error::default("Unable to get AuthHandler"))?;
+    /// Lets through logged in editors and admins only.
+    pub async fn editor_middleware(
+        req: ServiceRequest,
+        next: Next<impl MessageBody + 'static>,
+    ) -> Result<ServiceResponse<impl MessageBody>, AError> {
+        Self::require(Role::Editor, req, next).await
+    }
 
-        // authenticate user
-        if auth_handler.is_logged_in(db, session_str).await? {
-            next.call(req)
-                .await
-                .map(ServiceResponse::map_into_boxed_body)
-        } else {
-            let resp = HttpResponse::Unauthorized().finish().map_into_boxed_body();
-            let (request, _) = req.into_parts();
-            Ok(ServiceResponse::new(request, resp))
-        }
+    /// Lets through logged in admins only.
+    pub async fn admin_middleware(
+        req: ServiceRequest,
+        next: Next<impl MessageBody + 'static>,
+    ) -> Result<ServiceResponse<impl MessageBody>, AError> {
+        Self::require(Role::Admin, req, next).await
     }
 
-    #[allow(dead_code, unused_variables)]
-    pub async fn authorize_middleware(
+    /// Lets anyone read, and curators change by the method: contributors add with `POST`,
+    /// editors change with `PUT` or `PATCH`, and admins `DELETE`.
+    pub async fn curation_middleware(
         req: ServiceRequest,
         next: Next<impl MessageBody + 'static>,
     ) -> Result<ServiceResponse<impl MessageBody>, AError> {
-        // get session cookie
-        let session_cookie = req.cookie("ir_session");
-        if session_cookie.is_none() {
-            let resp = HttpResponse::Found()
-                .append_header((
-                    "location",
-                    format!("/api/v1/auth/login?referrer={}", req.path()),
-                ))
-                .finish()
-                .map_into_boxed_body();
-            let (request, _) = req.into_parts();
-            return Ok(ServiceResponse::new(request, resp));
+        let role = match *req.method() {
+            Method::GET | Method::HEAD | Method::OPTIONS => {
+                return next
+                    .call(req)
+                    .await
+                    .map(ServiceResponse::map_into_boxed_body)
+            }
+            Method::POST => Role::Contributor,
+            Method::DELETE => Role::Admin,
+            _ => Role::Editor,
         };
+        Self::require(role, req, next).await
+    }
 
-        let session_str = session_cookie.unwrap().value().to_string();
-
-        // get db
+    /// Lets through the logged in users with at least the role.
+    /// Others get a 401, and users with a lower role a 403.
+    async fn require(
+        role: Role,
+        req: ServiceRequest,
+        next: Next<impl MessageBody + 'static>,
+    ) -> Result<ServiceResponse<BoxBody>, AError> {
+        let session = match req.get_identity() {
+            Some(session) => session,
+            None => {
+                let resp = HttpResponse::Unauthorized().finish().map_into_boxed_body();
+                let (request, _) = req.into_parts();
+                return Ok(ServiceResponse::new(request, resp));
+            }
+        };
         let db = req
             .app_data::<actix_web::web::Data<Database>>()
-            .ok_or_else(|| Error::default("Unable to get Database"))?;
-
-        // Get AuthHandler
+            .ok_or_else(|| Error::internal("Unable to get Database"))?;
         let auth_handler = req
             .app_data::<actix_web::web::Data<AuthHandler>>()
-            .ok_or_else(|| Error::default("Unable to get AuthHandler"))?;
-        let session = db.get_session(session_str).await?;
-        next.call(req)
-            .await
-            .map(ServiceResponse::map_into_boxed_body)
+            .ok_or_else(|| Error::internal("Unable to get AuthHandler"))?;
+
+        let resp = match auth_handler.get_user(db, session).await {
+            Ok(user) if user.role >= role => {
+                return next
+                    .call(req)
+                    .await
+                    .map(ServiceResponse::map_into_boxed_body)
+            }
+            Ok(_) => {
+                Error::forbidden(format!("Requires the {} role", role.name())).error_response()
+            }
+            Err(e) if e.kind() == ErrorKind::Unauthorized => HttpResponse::Unauthorized().finish(),
+            Err(e) => return Err(e.into()),
+        };
+        let (request, _) = req.into_parts();
+        Ok(ServiceResponse::new(request, resp.map_into_boxed_body()))
     }
 
     pub async fn get_user(&self, db: &Database, session: String) -> Result<User, Error> {
+        if is_api_token(&session) {
+            let t = self.api_token(db, &session).await?;
+            // The groups of the latest login, so the token follows the role of its owner
+            let role = self
+                .role(db, &t.email, t.provider.as_deref(), &t.groups)
+                .await?;
+            return Ok(User {
+                name: t.user_name,
+                email: t.email,
+                role: role.min(t.scope),
+            });
+        }
         if !self.is_logged_in(db, session.clone()).await? {
-            return Err(Error::new(
-                "User is not logged in",
-                actix_web::http::StatusCode::UNAUTHORIZED,
-            ));
+            return Err(Error::unauthorized("User is not logged in"));
         };
-        let t = db
-            .get_session(session)
-            .await?
-            .token
-            .expect("Token is not present");
+        let s = db.get_session(session).await?;
+        let t = s.token.expect("Token is not present");
+        let role = self
+            .role(db, &t.preferred_username, s.provider.as_deref(), &s.groups)
+            .await?;
         Ok(User {
             name: t.name,
             email: t.preferred_username,
+            role,
         })
     }
+
+    /// The role of a user. Admins by the admin list come first, then the role assigned
+    /// locally, which may be lower than the one from the groups at their provider.
+    pub async fn role(
+        &self,
+        db: &Database,
+        email: &str,
+        provider: Option<&str>,
+        groups: &[String],
+    ) -> Result<Role, Error> {
+        if self.is_admin(email) {
+            return Ok(Role::Admin);
+        }
+        if let Some(assigned) = db.get_role(email).await? {
+            return Ok(assigned.role);
+        }
+        Ok(self
+            .provider(provider)
+            .ok()
+            .and_then(|p| p.config.role_of(groups))
+            .unwrap_or_default())
+    }
+
+    /// The API token, if it is known and current.
+    async fn api_token(&self, db: &Database, token: &str) -> Result<ApiToken, Error> {
+        match db.get_api_token(&hash_token(token)).await {
+            Ok(t) if t.expires_at > Utc::now() => Ok(t),
+            Ok(_) => Err(Error::unauthorized("Expired API token")),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                Err(Error::unauthorized("Unknown API token"))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Mint an API token for the user of the session. The token is only shown in the result.
+    pub async fn mint_token(
+        &self,
+        db: &Database,
+        session: String,
+        new: NewApiToken,
+    ) -> Result<MintedToken, Error> {
+        new.validate()?;
+        // So a leaked token cannot outlive itself
+        if is_api_token(&session) {
+            return Err(Error::forbidden("API tokens cannot mint tokens"));
+        }
+        let user = self.get_user(db, session.clone()).await?;
+        let s = db.get_session(session).await?;
+        if new.scope > user.role {
+            return Err(Error::forbidden(format!(
+                "The scope cannot be above your {} role",
+                user.role.name()
+            )));
+        }
+        let token: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(40)
+            .map(char::from)
+            .collect();
+        let token = format!("{}{}", TOKEN_PREFIX, token);
+        let now = Utc::now();
+        let minted = db
+            .add_api_token(ApiToken {
+                key: None,
+                name: new.name,
+                hash: hash_token(&token),
+                scope: new.scope,
+                email: user.email,
+                user_name: user.name,
+                provider: s.provider,
+                groups: s.groups,
+                created_at: now,
+                expires_at: now + Duration::days(new.expires_in_days as i64),
+            })
+            .await?;
+        Ok(MintedToken::new(token, minted))
+    }
+}
+
+/// Delete the expired sessions and API tokens, and the abandoned logins.
+pub async fn sweep_sessions(db: &Database) -> Result<u64, Error> {
+    let now = Utc::now();
+    let sessions = db
+        .sweep_sessions(
+            now,
+            now - Duration::hours(RENEWABLE_FOR),
+            now - Duration::minutes(LOGIN_TIMEOUT),
+        )
+        .await?;
+    Ok(sessions + db.sweep_api_tokens(now).await?)
+}
+
+/// Sweeps the sessions at every interval, until the server stops.
+pub async fn sweeper(db: Data<Database>, every: std::time::Duration) {
+    let mut interval = actix_web::rt::time::interval(every);
+    loop {
+        interval.tick().await;
+        match sweep_sessions(&db).await {
+            Ok(0) => {}
+            Ok(n) => log::info!("Swept {} sessions and API tokens", n),
+            Err(e) => log::error!("Unable to sweep sessions: {:?}", e),
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::core::mock_idp::{self, MockIdp, KID};
+    use crate::core::oidc::{Discovery, ProviderConfig};
+    use jsonwebtoken::{encode, EncodingKey};
     use std::borrow::Cow;
+    use std::sync::{Arc, Mutex};
+
+    fn provider(name: &str) -> Provider {
+        Provider::new(
+            ProviderConfig {
+                name: name.to_string(),
+                issuer: format!("https://{}.example.org", name),
+                client_id: "id".to_string(),
+                client_secret: Some("secret".to_string()),
+                scopes: ProviderConfig::default_scopes(),
+                groups_claim: ProviderConfig::default_groups_claim(),
+                roles: HashMap::from([
+                    ("curators".to_string(), Role::Contributor),
+                    ("editors".to_string(), Role::Editor),
+                ]),
+            },
+            Discovery {
+                issuer: format!("https://{}.example.org", name),
+                authorization_endpoint: format!("https://{}.example.org/authorize", name),
+                jwks_uri: format!("https://{}.example.org/jwks", name),
+                token_endpoint: None,
+                end_session_endpoint: None,
+            },
+        )
+    }
+
+    fn auth() -> AuthHandler {
+        AuthHandler::new("http://localhost".to_string())
+            .with_providers(vec![provider("azure"), provider("keycloak")])
+    }
 
     #[test]
     fn test_new() {
-        let _auth = AuthHandler::new(
-            "http://localhost".to_string(),
-            "secret".to_string(),
-            "id".to_string(),
-        );
+        let _auth = AuthHandler::new("http://localhost".to_string());
     }
 
     #[test]
     #[ignore]
     #[should_panic]
     fn test_new_bad_host() {
-        let _auth = AuthHandler::new(
-            "localhost".to_string(),
-            "secret".to_string(),
-            "id".to_string(),
-        );
+        let _auth = AuthHandler::new("localhost".to_string());
     }
 
     #[actix_web::test]
     async fn get_login_url() {
         let mut db = Database::default();
-        db.expect_add_session().returning(|_, _| Ok(()));
-        let auth = AuthHandler::new(
-            "http://localhost".to_string(),
-            "secret".to_string(),
-            "id".to_string(),
-        );
-        let url_result = auth.login(&db, "base").await;
+        db.expect_add_session()
+            .withf(|_, s| s.referrer.as_deref() == Some("base"))
+            .returning(|_, _| Ok(()));
+        let auth = auth();
+        let url_result = auth.login(&db, None, "base").await;
         assert!(url_result.is_ok(), "Created auth url successfully");
         let url = url_result.unwrap();
         assert_eq!(
-            url.path(),
-            "/common/oauth2/v2.0/authorize",
-            "Auth path is correct"
+            url.host_str(),
+            Some("azure.example.org"),
+            "Defaults to the first provider"
         );
+        assert_eq!(url.path(), "/authorize", "Auth path is correct");
         assert!(url.query().is_some(), "Auth url has queries");
         assert!(
             url.query_pairs()
@@ -291,6 +815,748 @@ mod test {
                     ))
                 .is_some(),
             "Auth url specifies the correct redirect uri"
+        );
+        let query: std::collections::HashMap<_, _> = url.query_pairs().into_owned().collect();
+        assert_eq!(query["response_type"], "code");
+        assert_eq!(query["code_challenge_method"], "S256");
+        assert!(
+            !query["state"].contains("base"),
+            "The referrer stays on the server"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_login_pkce_challenge() {
+        let idp = MockIdp::start();
+        let provider = Provider::discover(idp.config("mock")).await.unwrap();
+        let auth = AuthHandler::new("http://localhost".to_string()).with_providers(vec![provider]);
+        let verifier = Arc::new(Mutex::new(None));
+        let saved = verifier.clone();
+        let mut db = Database::default();
+        db.expect_add_session().returning(move |_, s| {
+            *saved.lock().unwrap() = s.verifier;
+            Ok(())
+        });
+        let url = auth.login(&db, None, "base").await.unwrap();
+
+        let challenge = url
+            .query_pairs()
+            .find(|(k, _)| k == "code_challenge")
+            .map(|(_, v)| v.into_owned());
+        let verifier = verifier.lock().unwrap().take().unwrap();
+        assert_eq!(challenge, Some(pkce_challenge(&verifier)));
+        assert!(
+            !url.as_str().contains(&verifier),
+            "The verifier stays on the server"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_login_named_provider() {
+        let mut db = Database::default();
+        db.expect_add_session()
+            .withf(|_, s| s.provider.as_deref() == Some("keycloak"))
+            .returning(|_, _| Ok(()));
+        let url = auth().login(&db, Some("keycloak"), "base").await.unwrap();
+        assert_eq!(url.host_str(), Some("keycloak.example.org"));
+
+        let err = auth().login(&db, Some("github"), "base").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Validation);
+        assert_eq!(
+            err.details().unwrap()["providers"],
+            json!(["azure", "keycloak"])
+        );
+    }
+
+    /// Log in at the mock provider, returning the handler and the session it started.
+    async fn mock_login(idp: &MockIdp) -> (AuthHandler, SessionRecord) {
+        let provider = Provider::discover(idp.config("mock")).await.unwrap();
+        let auth = AuthHandler::new("http://localhost".to_string()).with_providers(vec![provider]);
+        let started = Arc::new(Mutex::new(None));
+        let mut db = Database::default();
+        let saved = started.clone();
+        db.expect_add_session().returning(move |_, s| {
+            *saved.lock().unwrap() = Some(s);
+            Ok(())
+        });
+        auth.login(&db, Some("mock"), "base").await.unwrap();
+        let session = started.lock().unwrap().take().unwrap();
+        (auth, session)
+    }
+
+    fn session_db(session: SessionRecord) -> Database {
+        let mut db = Database::default();
+        db.expect_take_login()
+            .returning(move |_| Ok(session.clone()));
+        db.expect_update_session().returning(|_, s| Ok(s));
+        db.expect_update_api_token_groups()
+            .returning(|_, _, _| Ok(()));
+        db
+    }
+
+    #[actix_web::test]
+    async fn test_authorize() {
+        let idp = MockIdp::start();
+        let (auth, session) = mock_login(&idp).await;
+        idp.issue_code("code", session.verifier.as_deref().unwrap(), &session.nonce);
+        let mut db = Database::default();
+        db.expect_take_login()
+            .returning(move |_| Ok(session.clone()));
+        db.expect_update_session()
+            .withf(|state, s| {
+                state == "state"
+                    && s.verifier.is_none()
+                    && s.refresh_token.is_some()
+                    && s.groups == ["editors"]
+                    && s.referrer.is_none()
+                    && s.token.as_ref().unwrap().preferred_username == "user@example.org"
+            })
+            .times(1)
+            .returning(|_, s| Ok(s));
+        // The API tokens of the user follow their groups
+        db.expect_update_api_token_groups()
+            .withf(|email, provider, groups| {
+                email == "user@example.org"
+                    && provider.as_deref() == Some("mock")
+                    && groups == &["editors"]
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+
+        let referrer = auth.authorize(&db, "code", "state").await.unwrap();
+        assert_eq!(referrer.as_deref(), Some("base"));
+    }
+
+    #[actix_web::test]
+    async fn test_authorize_wrong_verifier() {
+        let idp = MockIdp::start();
+        let (auth, session) = mock_login(&idp).await;
+        idp.issue_code("code", "another verifier", &session.nonce);
+        let db = session_db(session);
+
+        let err = auth.authorize(&db, "code", "state").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unauthorized);
+    }
+
+    #[actix_web::test]
+    async fn test_authorize_used_session() {
+        let idp = MockIdp::start();
+        let (auth, session) = mock_login(&idp).await;
+        idp.issue_code("code", session.verifier.as_deref().unwrap(), &session.nonce);
+        let db = session_db(SessionRecord {
+            verifier: None,
+            ..session
+        });
+
+        let err = auth.authorize(&db, "code", "state").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unauthorized);
+        assert_eq!(err.details().unwrap()["reason"], "replayed");
+    }
+
+    #[actix_web::test]
+    async fn test_authorize_replayed_nonce() {
+        let idp = MockIdp::start();
+        let (auth, session) = mock_login(&idp).await;
+        idp.issue_code("code", session.verifier.as_deref().unwrap(), &session.nonce);
+        // Taken by a concurrent login, which failed before clearing the verifier
+        let db = session_db(SessionRecord {
+            nonce: String::new(),
+            ..session
+        });
+
+        let err = auth.authorize(&db, "code", "state").await.unwrap_err();
+        assert_eq!(err.details().unwrap()["reason"], "replayed");
+    }
+
+    /// Why the provider of the handler rejects the token.
+    async fn rejection(auth: &AuthHandler, jwt: &str, nonce: &str) -> serde_json::Value {
+        let provider = auth.provider(None).unwrap();
+        let err = auth
+            .validate_token(provider, jwt, Some(nonce))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unauthorized);
+        err.details().unwrap()["reason"].clone()
+    }
+
+    #[actix_web::test]
+    async fn test_validate_token_claims() {
+        let idp = MockIdp::start();
+        let (auth, session) = mock_login(&idp).await;
+        let now = Utc::now().timestamp();
+        let cases = [
+            ("aud", json!("another-client"), "audience"),
+            ("iss", json!("https://evil.example.org"), "issuer"),
+            ("exp", json!(now - 3600), "expired"),
+            ("nbf", json!(now + 3600), "not_yet_valid"),
+            ("iat", json!(now + 3600), "not_yet_valid"),
+            ("nonce", json!("another nonce"), "nonce"),
+        ];
+        for (claim, value, reason) in cases {
+            let mut claims = idp.claims(&session.nonce);
+            claims[claim] = value;
+            let jwt = idp.sign(&claims);
+            assert_eq!(
+                rejection(&auth, &jwt, &session.nonce).await,
+                reason,
+                "{}",
+                claim
+            );
+        }
+        for claim in ["exp", "iat", "aud", "iss"] {
+            let mut claims = idp.claims(&session.nonce);
+            claims.as_object_mut().unwrap().remove(claim);
+            let jwt = idp.sign(&claims);
+            assert_eq!(
+                rejection(&auth, &jwt, &session.nonce).await,
+                "missing_claim",
+                "{}",
+                claim
+            );
+        }
+        // Valid, but not the claims of a user
+        let mut claims = idp.claims(&session.nonce);
+        claims.as_object_mut().unwrap().remove("preferred_username");
+        let jwt = idp.sign(&claims);
+        assert_eq!(rejection(&auth, &jwt, &session.nonce).await, "invalid");
+    }
+
+    #[actix_web::test]
+    async fn test_validate_token_leeway() {
+        let idp = MockIdp::start();
+        let (auth, session) = mock_login(&idp).await;
+        let provider = auth.provider(None).unwrap();
+        let strict = AuthHandler::new("http://localhost".to_string())
+            .with_providers(vec![provider.clone()])
+            .with_leeway(0);
+        // Within the default minute of leeway
+        let now = Utc::now().timestamp();
+        let cases = [
+            ("exp", now - 30, "expired"),
+            ("nbf", now + 30, "not_yet_valid"),
+            ("iat", now + 30, "not_yet_valid"),
+        ];
+        for (claim, value, reason) in cases {
+            let mut claims = idp.claims(&session.nonce);
+            claims[claim] = json!(value);
+            let jwt = idp.sign(&claims);
+
+            auth.validate_token(provider, &jwt, Some(session.nonce.as_str()))
+                .await
+                .unwrap();
+            assert_eq!(
+                rejection(&strict, &jwt, &session.nonce).await,
+                reason,
+                "{}",
+                claim
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_validate_token_signatures() {
+        let idp = MockIdp::start();
+        let (auth, session) = mock_login(&idp).await;
+        let claims = idp.claims(&session.nonce);
+        let header = |alg| jsonwebtoken::Header {
+            kid: Some(KID.to_string()),
+            ..jsonwebtoken::Header::new(alg)
+        };
+
+        // Another RSA key under the id of the provider's
+        let forged = encode(
+            &header(Algorithm::RS256),
+            &claims,
+            &mock_idp::OTHER_KEY.encoding,
         )
+        .unwrap();
+        assert_eq!(rejection(&auth, &forged, &session.nonce).await, "signature");
+
+        // An HMAC, as if the public key were a shared secret
+        let secret = EncodingKey::from_secret(mock_idp::KEY.modulus.as_bytes());
+        let forged = encode(&header(Algorithm::HS256), &claims, &secret).unwrap();
+        assert_eq!(
+            rejection(&auth, &forged, &session.nonce).await,
+            "unsupported_algorithm"
+        );
+
+        let unnamed = idp.sign_with(jsonwebtoken::Header::new(Algorithm::RS256), &claims);
+        assert_eq!(
+            rejection(&auth, &unnamed, &session.nonce).await,
+            "missing_kid"
+        );
+
+        let mut unknown = header(Algorithm::RS256);
+        unknown.kid = Some("unknown".to_string());
+        let unknown = idp.sign_with(unknown, &claims);
+        assert_eq!(
+            rejection(&auth, &unknown, &session.nonce).await,
+            "unknown_kid"
+        );
+
+        assert_eq!(
+            rejection(&auth, "not a token", &session.nonce).await,
+            "malformed"
+        );
+    }
+
+    /// A logged in session of the mock provider, expiring in the given seconds.
+    fn logged_in(session: SessionRecord, expires_in: i64, refresh_token: &str) -> SessionRecord {
+        SessionRecord {
+            token: Some(Token {
+                token: None,
+                name: "Mock User".to_string(),
+                preferred_username: "user@example.org".to_string(),
+                exp: Utc::now() + Duration::seconds(expires_in),
+            }),
+            verifier: None,
+            refresh_token: Some(refresh_token.to_string()),
+            ..session
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_is_logged_in_renews() {
+        let idp = MockIdp::start();
+        let (auth, session) = mock_login(&idp).await;
+        idp.issue_refresh_token("refresh");
+        let mut db = Database::default();
+        let session = logged_in(session, 60, "refresh");
+        db.expect_get_session()
+            .returning(move |_| Ok(session.clone()));
+        db.expect_take_refresh_token()
+            .withf(|_, token| token == "refresh")
+            .times(1)
+            .returning(|_, _| Ok(true));
+        db.expect_update_session()
+            .withf(|_, s| {
+                s.token.as_ref().unwrap().exp > Utc::now() + Duration::minutes(30)
+                    && s.refresh_token.as_deref() != Some("refresh")
+            })
+            .times(1)
+            .returning(|_, s| Ok(s));
+        db.expect_update_api_token_groups()
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+
+        assert!(auth.is_logged_in(&db, "state".to_string()).await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn test_is_logged_in_not_renewed_early() {
+        let idp = MockIdp::start();
+        let (auth, session) = mock_login(&idp).await;
+        let mut db = Database::default();
+        let session = logged_in(session, 3600, "refresh");
+        db.expect_get_session()
+            .returning(move |_| Ok(session.clone()));
+        db.expect_update_session().never();
+
+        assert!(auth.is_logged_in(&db, "state".to_string()).await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn test_is_logged_in_renewal_refused() {
+        let idp = MockIdp::start();
+        let (auth, session) = mock_login(&idp).await;
+        let mut db = Database::default();
+        let session = logged_in(session, -60, "revoked");
+        db.expect_get_session()
+            .returning(move |_| Ok(session.clone()));
+        db.expect_take_refresh_token().returning(|_, _| Ok(true));
+        db.expect_update_session().never();
+
+        assert!(!auth.is_logged_in(&db, "state".to_string()).await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn test_is_logged_in_renewed_concurrently() {
+        let idp = MockIdp::start();
+        let (auth, session) = mock_login(&idp).await;
+        idp.issue_refresh_token("refresh");
+        let mut db = Database::default();
+        let session = logged_in(session, 60, "refresh");
+        db.expect_get_session()
+            .returning(move |_| Ok(session.clone()));
+        // Another request took it first
+        db.expect_take_refresh_token().returning(|_, _| Ok(false));
+        db.expect_update_session().never();
+
+        assert!(auth.is_logged_in(&db, "state".to_string()).await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn test_is_logged_in_renewed_as_another_user() {
+        let idp = MockIdp::start();
+        let (auth, session) = mock_login(&idp).await;
+        idp.issue_refresh_token("refresh");
+        let mut db = Database::default();
+        let mut session = logged_in(session, 60, "refresh");
+        session.token.as_mut().unwrap().preferred_username = "other@example.org".to_string();
+        db.expect_get_session()
+            .returning(move |_| Ok(session.clone()));
+        db.expect_take_refresh_token().returning(|_, _| Ok(true));
+        db.expect_update_session().never();
+        db.expect_delete_session().times(1).returning(|_| Ok(()));
+
+        assert!(!auth.is_logged_in(&db, "state".to_string()).await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn test_is_logged_in_revoked() {
+        let mut db = Database::default();
+        db.expect_get_session()
+            .returning(|key| Err(Error::not_found(format!("Session {} not found", key))));
+
+        assert!(!auth().is_logged_in(&db, "state".to_string()).await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn test_logout_ends_session() {
+        let idp = MockIdp::start();
+        let (auth, session) = mock_login(&idp).await;
+        let mut session = logged_in(session, 3600, "refresh");
+        session.token.as_mut().unwrap().token = Some("id-token".to_string());
+        let mut db = Database::default();
+        db.expect_get_session()
+            .returning(move |_| Ok(session.clone()));
+        db.expect_delete_session()
+            .withf(|s| s == "state")
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let url = auth
+            .logout(&db, "state".to_string(), true, "/api/v1/")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(url.path(), "/logout");
+        let query: std::collections::HashMap<_, _> = url.query_pairs().into_owned().collect();
+        assert_eq!(query["id_token_hint"], "id-token");
+        assert_eq!(query["client_id"], mock_idp::CLIENT_ID);
+        assert_eq!(
+            query["post_logout_redirect_uri"],
+            "http://localhost/api/v1/"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_logout_local_only() {
+        let mut db = Database::default();
+        db.expect_get_session().returning(|_| {
+            Ok(SessionRecord {
+                nonce: String::new(),
+                token: None,
+                provider: Some("azure".to_string()),
+                verifier: None,
+                access_token: None,
+                refresh_token: None,
+                created_at: None,
+                groups: vec![],
+                referrer: None,
+            })
+        });
+        db.expect_delete_session().times(1).returning(|_| Ok(()));
+
+        let url = auth()
+            .logout(&db, "state".to_string(), false, "/api/v1/")
+            .await
+            .unwrap();
+        assert_eq!(url, None);
+    }
+
+    #[actix_web::test]
+    async fn test_logout_local_account() {
+        let mut db = Database::default();
+        db.expect_get_session().returning(|_| {
+            Ok(SessionRecord {
+                provider: Some(local::PROVIDER.to_string()),
+                ..member_of(&[])
+            })
+        });
+        db.expect_delete_session().times(1).returning(|_| Ok(()));
+
+        let url = auth()
+            .logout(&db, "state".to_string(), true, "/api/v1/")
+            .await
+            .unwrap();
+        assert_eq!(url, None, "There is no provider to log out at");
+    }
+
+    #[actix_web::test]
+    async fn test_logout_unknown_session() {
+        let mut db = Database::default();
+        db.expect_get_session()
+            .returning(|key| Err(Error::not_found(format!("Session {} not found", key))));
+        db.expect_delete_session().never();
+
+        let url = auth()
+            .logout(&db, "state".to_string(), true, "/api/v1/")
+            .await
+            .unwrap();
+        assert_eq!(url, None);
+    }
+
+    #[actix_web::test]
+    async fn test_sweep_sessions() {
+        let mut db = Database::default();
+        db.expect_sweep_sessions()
+            .withf(|expired, renewable, started| {
+                *renewable < *started && *started < *expired && *expired <= Utc::now()
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(2));
+        db.expect_sweep_api_tokens()
+            .withf(|expired| *expired <= Utc::now())
+            .times(1)
+            .returning(|_| Ok(1));
+
+        assert_eq!(sweep_sessions(&db).await.unwrap(), 3);
+    }
+
+    /// A session logged in at the azure provider, in the groups.
+    fn member_of(groups: &[&str]) -> SessionRecord {
+        SessionRecord {
+            nonce: String::new(),
+            token: Some(Token {
+                token: None,
+                name: "User".to_string(),
+                preferred_username: "User@example.org".to_string(),
+                exp: Utc::now() + Duration::hours(1),
+            }),
+            provider: Some("azure".to_string()),
+            verifier: None,
+            access_token: None,
+            refresh_token: None,
+            created_at: None,
+            groups: groups.iter().map(|g| g.to_string()).collect(),
+            referrer: None,
+        }
+    }
+
+    fn assigned(role: Role) -> Option<RoleAssignment> {
+        Some(RoleAssignment {
+            email: "user@example.org".to_string(),
+            role,
+            assigned_by: None,
+            assigned_at: None,
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_role() {
+        let cases = [
+            (vec![], None, Role::Viewer),
+            (vec!["staff"], None, Role::Viewer),
+            (vec!["curators", "editors"], None, Role::Editor),
+            // Assigned roles win, even over higher group roles
+            (
+                vec!["editors"],
+                assigned(Role::Contributor),
+                Role::Contributor,
+            ),
+            (vec![], assigned(Role::Admin), Role::Admin),
+        ];
+        for (groups, assignment, role) in cases {
+            let mut db = Database::default();
+            db.expect_get_role()
+                .withf(|email| email == "User@example.org")
+                .returning(move |_| Ok(assignment.clone()));
+            let groups: Vec<String> = groups.iter().map(|g| g.to_string()).collect();
+            assert_eq!(
+                auth()
+                    .role(&db, "User@example.org", Some("azure"), &groups)
+                    .await
+                    .unwrap(),
+                role,
+                "{:?}",
+                groups
+            );
+        }
+
+        // Admins by the list are admins whatever their assigned role
+        let mut db = Database::default();
+        db.expect_get_role().never();
+        let auth = auth().with_admins(vec!["user@example.org".to_string()]);
+        assert_eq!(
+            auth.role(&db, "User@example.org", Some("azure"), &[])
+                .await
+                .unwrap(),
+            Role::Admin
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_curation_middleware() {
+        use actix_identity::{CookieIdentityPolicy, Identity, IdentityService};
+        use actix_service::Service;
+        use actix_web::{
+            http::{header::SET_COOKIE, StatusCode},
+            test::{init_service, TestRequest},
+            web, App,
+        };
+        use actix_web_lab::middleware::from_fn;
+
+        #[actix_web::get("/login")]
+        async fn login(id: Identity) -> HttpResponse {
+            id.remember("session".to_string());
+            HttpResponse::Ok().finish()
+        }
+
+        let cases = [
+            (None, Method::GET, StatusCode::OK),
+            (None, Method::POST, StatusCode::UNAUTHORIZED),
+            (Some(Role::Viewer), Method::POST, StatusCode::FORBIDDEN),
+            (Some(Role::Contributor), Method::POST, StatusCode::OK),
+            (Some(Role::Contributor), Method::PUT, StatusCode::FORBIDDEN),
+            (Some(Role::Editor), Method::PUT, StatusCode::OK),
+            (Some(Role::Editor), Method::DELETE, StatusCode::FORBIDDEN),
+            (Some(Role::Admin), Method::DELETE, StatusCode::OK),
+        ];
+        for (role, method, status) in cases {
+            let mut db = Database::default();
+            db.expect_get_session().returning(|_| Ok(member_of(&[])));
+            db.expect_get_role()
+                .returning(move |_| Ok(role.and_then(assigned)));
+            let app = init_service(
+                App::new()
+                    .wrap(IdentityService::new(
+                        CookieIdentityPolicy::new(&[0; 32]).secure(false),
+                    ))
+                    .app_data(Data::new(db))
+                    .app_data(Data::new(auth()))
+                    .service(login)
+                    .service(
+                        web::scope("/topics")
+                            .route(
+                                "/",
+                                web::route().to(|| async { HttpResponse::Ok().finish() }),
+                            )
+                            .wrap(from_fn(AuthHandler::curation_middleware)),
+                    ),
+            )
+            .await;
+
+            let mut req = TestRequest::default()
+                .method(method.clone())
+                .uri("/topics/");
+            if role.is_some() {
+                let resp = app
+                    .call(TestRequest::with_uri("/login").to_request())
+                    .await
+                    .unwrap();
+                let cookie = resp.headers().get(SET_COOKIE).unwrap().to_str().unwrap();
+                req = req.cookie(actix_web::cookie::Cookie::parse(cookie.to_string()).unwrap());
+            }
+            let resp = app.call(req.to_request()).await.unwrap();
+            assert_eq!(resp.status(), status, "{:?} {}", role, method);
+        }
+    }
+
+    fn api_token(scope: Role, expires_in: i64) -> ApiToken {
+        ApiToken {
+            key: Some("1".to_string()),
+            name: "import script".to_string(),
+            hash: hash_token("irt_secret"),
+            scope,
+            email: "user@example.org".to_string(),
+            user_name: "User".to_string(),
+            provider: Some("azure".to_string()),
+            groups: vec!["editors".to_string()],
+            created_at: Utc::now(),
+            expires_at: Utc::now() + Duration::seconds(expires_in),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_mint_token() {
+        let mut db = Database::default();
+        db.expect_get_session()
+            .returning(|_| Ok(member_of(&["editors"])));
+        db.expect_get_role().returning(|_| Ok(None));
+        let stored = Arc::new(Mutex::new(None));
+        let saved = stored.clone();
+        db.expect_add_api_token().times(1).returning(move |t| {
+            *saved.lock().unwrap() = Some(t.clone());
+            Ok(ApiToken {
+                key: Some("1".to_string()),
+                ..t
+            })
+        });
+        let new = |scope| NewApiToken {
+            name: "import script".to_string(),
+            scope,
+            expires_in_days: 7,
+        };
+
+        let minted = auth()
+            .mint_token(&db, "state".to_string(), new(Role::Editor))
+            .await
+            .unwrap();
+        assert!(is_api_token(&minted.token));
+        assert_eq!(minted.key, "1");
+        let stored = stored.lock().unwrap().take().unwrap();
+        assert_eq!(stored.hash, hash_token(&minted.token));
+        assert!(!stored.hash.contains(&minted.token));
+        assert_eq!(stored.provider.as_deref(), Some("azure"));
+        assert_eq!(stored.groups, ["editors"]);
+
+        // Not above the role of the user, and not with another token
+        let err = auth()
+            .mint_token(&db, "state".to_string(), new(Role::Admin))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Forbidden);
+        let err = auth()
+            .mint_token(&db, "irt_secret".to_string(), new(Role::Viewer))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Forbidden);
+    }
+
+    #[actix_web::test]
+    async fn test_api_token_user() {
+        let mut db = Database::default();
+        let token = Arc::new(Mutex::new(api_token(Role::Contributor, 60)));
+        let current = token.clone();
+        db.expect_get_api_token()
+            .withf(|hash| hash == hash_token("irt_secret"))
+            .returning(move |_| Ok(current.lock().unwrap().clone()));
+        db.expect_get_role().returning(|_| Ok(None));
+        db.expect_get_session().never();
+        // Not even after the owner logged out everywhere
+        db.expect_get_user_sessions().never();
+
+        let auth = auth();
+        assert!(auth
+            .is_logged_in(&db, "irt_secret".to_string())
+            .await
+            .unwrap());
+        let user = auth.get_user(&db, "irt_secret".to_string()).await.unwrap();
+        assert_eq!(user.email, "user@example.org");
+        assert_eq!(user.role, Role::Contributor, "The scope caps the role");
+
+        // The role follows the groups of the owner, as of their latest login
+        token.lock().unwrap().groups.clear();
+        let user = auth.get_user(&db, "irt_secret".to_string()).await.unwrap();
+        assert_eq!(user.role, Role::Viewer);
+    }
+
+    #[actix_web::test]
+    async fn test_api_token_expired() {
+        let mut db = Database::default();
+        db.expect_get_api_token()
+            .returning(|_| Ok(api_token(Role::Viewer, -60)));
+
+        let auth = auth();
+        assert!(!auth
+            .is_logged_in(&db, "irt_secret".to_string())
+            .await
+            .unwrap());
+        let err = auth
+            .get_user(&db, "irt_secret".to_string())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unauthorized);
     }
 }
@@ -0,0 +1,111 @@
+use crate::models::generic::Error;
+use crate::models::refs::{HRef, QRef, RefEnum};
+
+/// Prefixes scholars put before a Quran citation, lowercase.
+const QURAN_PREFIXES: [&str; 4] = ["quran", "qur'an", "q.", "q"];
+
+/// Words naming the grade of a Hadith collection rather than the collection.
+const HADITH_GRADES: [&str; 2] = ["sahih", "sunan"];
+
+/// Parses a citation as written in a spreadsheet into a reference.
+///
+/// Quran citations are `chapter:verse` or `chapter:first-last`, optionally
+/// prefixed, e.g. `2:255`, `Quran 2:255-257` or `Q. 112:1`.
+/// Hadith citations are a collection then a number, e.g. `Bukhari 1`,
+/// `Sahih Muslim 8a` or `bukhari:1`. Collections are lowercased.
+pub fn parse(citation: &str) -> Result<RefEnum, Error> {
+    let citation = citation.trim();
+    if citation.is_empty() {
+        return Err(Error::validation("Empty citation"));
+    }
+    let invalid = || Error::validation(format!("Cannot parse citation '{}'", citation));
+
+    let lower = citation.to_lowercase();
+    let verses = QURAN_PREFIXES
+        .iter()
+        .find_map(|p| lower.strip_prefix(p).map(str::trim_start))
+        .unwrap_or(&lower);
+    if verses.starts_with(|c: char| c.is_ascii_digit()) {
+        let q = parse_verses(verses).ok_or_else(invalid)?;
+        q.validate()?;
+        return Ok(RefEnum::Q(q));
+    }
+
+    // The number is the last word, after a space or a colon
+    let (collection, number) = lower.rsplit_once([' ', ':']).ok_or_else(invalid)?;
+    let number = number.trim();
+    let collection: Vec<&str> = collection
+        .split_whitespace()
+        .filter(|w| !HADITH_GRADES.contains(w))
+        .collect();
+    if collection.is_empty()
+        || number.is_empty()
+        || !number.starts_with(|c: char| c.is_ascii_digit())
+        || !number.chars().all(|c| c.is_ascii_alphanumeric())
+    {
+        return Err(invalid());
+    }
+    Ok(RefEnum::H(HRef {
+        collection: collection.join("_"),
+        number: number.to_string(),
+    }))
+}
+
+fn parse_verses(s: &str) -> Option<QRef> {
+    let (chapter, verses) = s.split_once(':')?;
+    let (init, fin) = verses.split_once('-').unwrap_or((verses, verses));
+    Some(QRef {
+        chapter: chapter.trim().parse().ok()?,
+        init_verse: init.trim().parse().ok()?,
+        final_verse: fin.trim().parse().ok()?,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::generic::ErrorKind;
+
+    fn q(chapter: usize, init_verse: usize, final_verse: usize) -> RefEnum {
+        RefEnum::Q(QRef {
+            chapter,
+            init_verse,
+            final_verse,
+        })
+    }
+
+    fn h(collection: &str, number: &str) -> RefEnum {
+        RefEnum::H(HRef {
+            collection: collection.to_string(),
+            number: number.to_string(),
+        })
+    }
+
+    #[test]
+    fn test_parse_quran() {
+        assert_eq!(parse("2:255").unwrap(), q(2, 255, 255));
+        assert_eq!(parse(" 2:255-257 ").unwrap(), q(2, 255, 257));
+        assert_eq!(parse("Quran 2:255").unwrap(), q(2, 255, 255));
+        assert_eq!(parse("Qur'an 1:1-7").unwrap(), q(1, 1, 7));
+        assert_eq!(parse("Q. 112:1").unwrap(), q(112, 1, 1));
+    }
+
+    #[test]
+    fn test_parse_hadith() {
+        assert_eq!(parse("Bukhari 1").unwrap(), h("bukhari", "1"));
+        assert_eq!(parse("bukhari:1").unwrap(), h("bukhari", "1"));
+        assert_eq!(parse("Sahih Muslim 8a").unwrap(), h("muslim", "8a"));
+        assert_eq!(
+            parse("Sunan Abi Dawud 4031").unwrap(),
+            h("abi_dawud", "4031")
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        for citation in ["", "Bukhari", "2:", "115:1", "2:10-5", "Bukhari one"] {
+            let err = parse(citation).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Validation, "{}", citation);
+        }
+    }
+}
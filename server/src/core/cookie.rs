@@ -0,0 +1,370 @@
+//! The session cookie: the keys it is signed with, and its attributes.
+
+use super::auth::is_api_token;
+use actix_identity::{CookieIdentityPolicy, IdentityPolicy};
+// The futures actix-identity 0.4 returns, deprecated in favour of those of core since
+#[allow(deprecated)]
+use actix_utils::future::{ready, Ready};
+use actix_web::{
+    cookie,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::AUTHORIZATION,
+    Error as AError, HttpMessage,
+};
+use rand::RngCore;
+use std::fs;
+use std::io::{self, ErrorKind, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum SameSite {
+    Strict,
+    /// Sent when following a link to the server, as from the login provider.
+    #[default]
+    Lax,
+    /// Sent on every request, which requires a secure cookie.
+    None,
+}
+
+impl From<SameSite> for cookie::SameSite {
+    fn from(s: SameSite) -> Self {
+        match s {
+            SameSite::Strict => cookie::SameSite::Strict,
+            SameSite::Lax => cookie::SameSite::Lax,
+            SameSite::None => cookie::SameSite::None,
+        }
+    }
+}
+
+/// The attributes of the session cookie.
+#[derive(Debug, Clone)]
+pub struct CookieConfig {
+    pub name: String,
+    pub domain: Option<String>,
+    pub same_site: SameSite,
+    /// In seconds. Without it the cookie lasts as long as the browser session.
+    pub max_age: Option<i64>,
+    /// Only sent over HTTPS. Turn it off for plain HTTP local development.
+    pub secure: bool,
+}
+
+impl Default for CookieConfig {
+    fn default() -> Self {
+        CookieConfig {
+            name: "ir_session".to_string(),
+            domain: None,
+            same_site: SameSite::default(),
+            max_age: None,
+            secure: true,
+        }
+    }
+}
+
+impl CookieConfig {
+    /// Browsers drop cookies sent on every request but over plain HTTP too,
+    /// so logins would silently fail.
+    pub fn validate(&self) -> io::Result<()> {
+        if self.same_site == SameSite::None && !self.secure {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "COOKIE_SAME_SITE=none requires COOKIE_SECURE",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The keys session cookies are signed with.
+#[derive(Debug, Clone)]
+pub struct CookieKeys {
+    /// Signs new cookies.
+    pub current: Vec<u8>,
+    /// Still accepted, so rotating the key does not log everyone out.
+    pub old: Vec<Vec<u8>>,
+}
+
+impl CookieKeys {
+    pub const MIN_LEN: usize = 32;
+
+    /// A key from its base64 encoding.
+    pub fn decode(key: &str) -> io::Result<Vec<u8>> {
+        let key = base64::decode(key.trim())
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("Invalid key: {}", e)))?;
+        if key.len() < Self::MIN_LEN {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Cookie keys must be at least {} bytes", Self::MIN_LEN),
+            ));
+        }
+        Ok(key)
+    }
+
+    /// Read the key from the file, or generate it on the first run.
+    pub fn load_or_generate(path: &str) -> io::Result<Vec<u8>> {
+        match fs::read_to_string(path) {
+            Ok(key) => Self::decode(&key),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let mut key = vec![0; 64];
+                rand::thread_rng().fill_bytes(&mut key);
+                let mut options = fs::OpenOptions::new();
+                options.write(true).create_new(true);
+                #[cfg(unix)]
+                std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+                options
+                    .open(path)?
+                    .write_all(base64::encode(&key).as_bytes())?;
+                log::info!("Generated the cookie key in {}", path);
+                Ok(key)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Marks a request whose cookie was signed with an old key, so it is signed again.
+struct OldKey;
+
+/// Identifies requests by a cookie signed with the current key or an old one.
+/// Cookies signed with an old key are signed with the current one in the response.
+/// An API token in an `Authorization: Bearer` header identifies the request instead,
+/// and is never put in a cookie.
+pub struct KeyRingPolicy {
+    current: CookieIdentityPolicy,
+    old: Vec<CookieIdentityPolicy>,
+}
+
+impl KeyRingPolicy {
+    pub fn new(keys: &CookieKeys, config: &CookieConfig) -> Self {
+        let policy = |key: &[u8]| {
+            let policy = CookieIdentityPolicy::new(key)
+                .name(&config.name)
+                .path("/")
+                .http_only(true)
+                .same_site(config.same_site.into())
+                .secure(config.secure);
+            let policy = match &config.domain {
+                Some(domain) => policy.domain(domain),
+                None => policy,
+            };
+            match config.max_age {
+                Some(secs) => policy.max_age_secs(secs),
+                None => policy,
+            }
+        };
+        KeyRingPolicy {
+            current: policy(&keys.current),
+            old: keys.old.iter().map(|k| policy(k)).collect(),
+        }
+    }
+}
+
+#[allow(deprecated)]
+impl IdentityPolicy for KeyRingPolicy {
+    type Future = Ready<Result<Option<String>, AError>>;
+    type ResponseFuture = Ready<Result<(), AError>>;
+
+    fn from_request(&self, req: &mut ServiceRequest) -> Self::Future {
+        let bearer = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Bearer"))
+            .map(|(_, token)| token.trim())
+            .filter(|t| is_api_token(t));
+        if let Some(token) = bearer {
+            return ready(Ok(Some(token.to_string())));
+        }
+        match self.current.from_request(req).into_inner() {
+            Ok(None) => {}
+            found => return ready(found),
+        }
+        for policy in &self.old {
+            if let Ok(Some(id)) = policy.from_request(req).into_inner() {
+                req.extensions_mut().insert(OldKey);
+                return ready(Ok(Some(id)));
+            }
+        }
+        ready(Ok(None))
+    }
+
+    fn to_response<B>(
+        &self,
+        identity: Option<String>,
+        changed: bool,
+        res: &mut ServiceResponse<B>,
+    ) -> Self::ResponseFuture {
+        let resign = res.request().extensions().get::<OldKey>().is_some();
+        if !changed && identity.as_deref().is_some_and(is_api_token) {
+            return ready(Ok(()));
+        }
+        self.current.to_response(identity, changed || resign, res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_identity::{Identity, IdentityService};
+    use actix_service::Service;
+    use actix_web::{
+        get,
+        http::{header::SET_COOKIE, StatusCode},
+        test::{init_service, read_body, TestRequest},
+        App, HttpResponse,
+    };
+
+    #[get("/login")]
+    async fn login(id: Identity) -> HttpResponse {
+        id.remember("session".to_string());
+        HttpResponse::Ok().finish()
+    }
+
+    #[get("/")]
+    async fn whoami(id: Identity) -> HttpResponse {
+        HttpResponse::Ok().body(id.identity().unwrap_or_default())
+    }
+
+    fn keys(current: u8, old: &[u8]) -> CookieKeys {
+        CookieKeys {
+            current: vec![current; 32],
+            old: old.iter().map(|k| vec![*k; 32]).collect(),
+        }
+    }
+
+    /// The session cookie a server signing with the keys sets.
+    async fn session_cookie(keys: &CookieKeys, config: &CookieConfig) -> cookie::Cookie<'static> {
+        let policy = KeyRingPolicy::new(keys, config);
+        let app = init_service(App::new().wrap(IdentityService::new(policy)).service(login)).await;
+        let resp = app
+            .call(TestRequest::with_uri("/login").to_request())
+            .await
+            .unwrap();
+        let header = resp.headers().get(SET_COOKIE).unwrap().to_str().unwrap();
+        cookie::Cookie::parse(header.to_string()).unwrap()
+    }
+
+    #[test]
+    fn test_same_site_none_requires_secure() {
+        let config = CookieConfig {
+            same_site: SameSite::None,
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+        let insecure = CookieConfig {
+            secure: false,
+            ..config
+        };
+        assert!(insecure.validate().is_err());
+        let lax = CookieConfig {
+            same_site: SameSite::Lax,
+            ..insecure
+        };
+        assert!(lax.validate().is_ok());
+    }
+
+    #[test]
+    fn test_decode_short_key() {
+        assert!(CookieKeys::decode(&base64::encode([1; 31])).is_err());
+        assert_eq!(
+            CookieKeys::decode(&base64::encode([1; 32])).unwrap(),
+            vec![1; 32]
+        );
+    }
+
+    #[test]
+    fn test_load_or_generate() {
+        let path = std::env::temp_dir().join(format!("cookie-{}.key", rand::random::<u64>()));
+        let path = path.to_str().unwrap();
+        let key = CookieKeys::load_or_generate(path).unwrap();
+        assert!(key.len() >= CookieKeys::MIN_LEN);
+        assert_eq!(CookieKeys::load_or_generate(path).unwrap(), key);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_cookie_attributes() {
+        let config = CookieConfig {
+            name: "session".to_string(),
+            domain: Some("example.org".to_string()),
+            same_site: SameSite::Strict,
+            max_age: Some(3600),
+            secure: false,
+        };
+        let cookie = session_cookie(&keys(1, &[]), &config).await;
+
+        assert_eq!(cookie.name(), "session");
+        assert_eq!(cookie.domain(), Some("example.org"));
+        assert_eq!(cookie.same_site(), Some(cookie::SameSite::Strict));
+        assert_eq!(
+            cookie.max_age(),
+            Some(cookie::time::Duration::seconds(3600))
+        );
+        assert_eq!(cookie.secure(), None);
+        assert_eq!(cookie.http_only(), Some(true));
+    }
+
+    #[actix_web::test]
+    async fn test_rotated_key() {
+        let config = CookieConfig::default();
+        let cookie = session_cookie(&keys(1, &[]), &config).await;
+
+        // Rotated: the old key is still accepted, and the cookie signed again
+        let rotated = KeyRingPolicy::new(&keys(2, &[1]), &config);
+        let app = init_service(
+            App::new()
+                .wrap(IdentityService::new(rotated))
+                .service(whoami),
+        )
+        .await;
+        let req = TestRequest::with_uri("/")
+            .cookie(cookie.clone())
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resigned = resp.headers().get(SET_COOKIE).cloned();
+        assert_eq!(read_body(resp).await, "session");
+        let resigned =
+            cookie::Cookie::parse(resigned.unwrap().to_str().unwrap().to_string()).unwrap();
+        assert_ne!(resigned.value(), cookie.value());
+
+        // Dropped: the old key is not accepted anymore
+        let dropped = KeyRingPolicy::new(&keys(2, &[]), &config);
+        let app = init_service(
+            App::new()
+                .wrap(IdentityService::new(dropped))
+                .service(whoami),
+        )
+        .await;
+        let req = TestRequest::with_uri("/").cookie(cookie).to_request();
+        assert_eq!(read_body(app.call(req).await.unwrap()).await, "");
+    }
+
+    #[actix_web::test]
+    async fn test_bearer_token() {
+        let policy = KeyRingPolicy::new(&keys(1, &[]), &CookieConfig::default());
+        let app = init_service(
+            App::new()
+                .wrap(IdentityService::new(policy))
+                .service(whoami),
+        )
+        .await;
+        let req = TestRequest::with_uri("/")
+            .insert_header((AUTHORIZATION, "Bearer irt_secret"))
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert!(resp.headers().get(SET_COOKIE).is_none());
+        assert_eq!(read_body(resp).await, "irt_secret");
+
+        // The scheme is case insensitive
+        let req = TestRequest::with_uri("/")
+            .insert_header((AUTHORIZATION, "bearer irt_secret"))
+            .to_request();
+        assert_eq!(read_body(app.call(req).await.unwrap()).await, "irt_secret");
+
+        // Other bearer tokens are not ours
+        let req = TestRequest::with_uri("/")
+            .insert_header((AUTHORIZATION, "Bearer eyJhbGciOi"))
+            .to_request();
+        assert_eq!(read_body(app.call(req).await.unwrap()).await, "");
+    }
+}
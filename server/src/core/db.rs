@@ -1,31 +1,377 @@
-use aragog::query::{Comparison, Filter, Query, QueryResult};
+use aragog::query::Query;
 use aragog::transaction::Transaction;
-use aragog::{DatabaseConnection, DatabaseRecord, Record};
+use aragog::{DatabaseAccess, DatabaseConnection, DatabaseRecord, Record};
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
 
+use super::events::EventBus;
 use crate::models::auth::*;
-use crate::models::generic::Error;
+use crate::models::batch::{BatchResult, OpResult, OpStatus, Operation};
+use crate::models::events::{Event, EventKind};
+use crate::models::export::{Entry, EntryKind, ExportEdge, ExportRef};
+use crate::models::generic::{Cursor, Error, Page};
+use crate::models::graph::Graph;
+use crate::models::import::{ImportReport, ImportRow, RowError};
 use crate::models::refs::*;
-use crate::models::topics::Topic;
+use crate::models::topics::{SortOrder, Topic, TopicFilter, TopicMatch};
+use crate::models::webhooks::{DeliveryStatus, Webhook, WebhookDelivery};
 
 use mockall::automock;
 
+use std::collections::{HashMap, HashSet};
 use std::process::Command;
 type Result<T> = std::result::Result<T, Error>;
 
+/// Runs a raw AQL query, for what the query builder cannot express.
+/// Values are always passed as bind variables, never formatted into the query.
+/// A unique constraint violation is a conflict, like when creating records.
+async fn aql<T: DeserializeOwned, D: DatabaseAccess + ?Sized>(
+    db: &D,
+    query: &str,
+    vars: HashMap<&str, Value>,
+) -> Result<Vec<T>> {
+    db.database()
+        .aql_bind_vars(query, vars)
+        .await
+        .map_err(|e| match aragog::Error::from(e) {
+            e @ aragog::Error::Conflict(_) => e.into(),
+            e => Error::storage(e),
+        })
+}
+
+#[derive(Deserialize)]
+struct Row<T> {
+    item: T,
+    value: Value,
+    key: String,
+}
+
+#[derive(Deserialize)]
+struct Rows<T> {
+    total: u64,
+    rows: Vec<Row<T>>,
+}
+
+/// Creates a reference and links a topic to it.
+async fn link_ref<R: Record + Send, D: DatabaseAccess + ?Sized>(
+    db: &D,
+    topic: &str,
+    reference: R,
+    edge: RefEdge,
+) -> Result<()> {
+    let r = DatabaseRecord::create(reference, db).await?;
+    let t = Topic::find(topic, db).await?;
+    DatabaseRecord::link(&t, &r, db, edge).await?;
+    Ok(())
+}
+
+/// Removes the references of a topic matching `reference`, and their edges.
+async fn unlink_ref<D: DatabaseAccess + ?Sized>(
+    db: &D,
+    topic: &str,
+    collection: &str,
+    reference: Value,
+) -> Result<()> {
+    let query = r#"
+        FOR r, e IN 1..1 OUTBOUND @topic @@edges
+            FILTER IS_SAME_COLLECTION(@refs, r) AND MATCHES(r, @ref)
+            REMOVE e IN @@edges
+            REMOVE r IN @@refs
+            RETURN 1
+    "#;
+    let vars = HashMap::from([
+        (
+            "topic",
+            json!(format!("{}/{}", Topic::COLLECTION_NAME, topic)),
+        ),
+        ("@edges", json!(RefEdge::COLLECTION_NAME)),
+        ("refs", json!(collection)),
+        ("@refs", json!(collection)),
+        ("ref", reference),
+    ]);
+    let removed = aql::<Value, D>(db, query, vars).await?;
+    if removed.is_empty() {
+        return Err(
+            Error::not_found(format!("Topic {} has no such reference", topic))
+                .with_details(json!({ "key": topic })),
+        );
+    }
+    Ok(())
+}
+
+/// Applies one operation of a batch.
+async fn apply<D: DatabaseAccess + ?Sized>(
+    db: &D,
+    op: Operation,
+    user: Option<&str>,
+) -> Result<()> {
+    match op {
+        Operation::CreateTopic { name } => {
+            let now = Utc::now();
+            let t = Topic {
+                created_by: user.map(String::from),
+                created_at: Some(now),
+                updated_at: Some(now),
+                ..Topic::new(&name)
+            };
+            match DatabaseRecord::create(t, db).await {
+                Ok(_) => Ok(()),
+                Err(aragog::Error::Conflict(_)) => Err(topic_conflict(db, &name).await),
+                Err(e) => Err(e.into()),
+            }
+        }
+        Operation::RenameTopic { topic, name } => {
+            let mut t = DatabaseRecord::<Topic>::find(&topic, db).await?;
+            t.name = name.clone();
+            t.updated_at = Some(Utc::now());
+            match t.save(db).await {
+                Ok(_) => Ok(()),
+                Err(aragog::Error::Conflict(_)) => Err(topic_conflict(db, &name).await),
+                Err(e) => Err(e.into()),
+            }
+        }
+        Operation::LinkQRef { topic, qref } => link_ref(db, &topic, qref, RefEdge::default()).await,
+        Operation::LinkHRef { topic, href } => link_ref(db, &topic, href, RefEdge::default()).await,
+        Operation::UnlinkQRef { topic, qref } => {
+            unlink_ref(db, &topic, QRef::COLLECTION_NAME, json!(qref)).await
+        }
+        Operation::UnlinkHRef { topic, href } => {
+            unlink_ref(db, &topic, HRef::COLLECTION_NAME, json!(href)).await
+        }
+    }
+}
+
+/// The event an operation of a batch publishes once committed.
+fn event_of(op: &Operation) -> Event {
+    match op {
+        Operation::CreateTopic { name } => Event::topic(EventKind::TopicCreated, name),
+        Operation::RenameTopic { topic, .. } => Event::topic(EventKind::TopicUpdated, topic),
+        Operation::LinkQRef { topic, qref } => {
+            Event::reference(EventKind::RefLinked, topic, RefEnum::Q(qref.clone()))
+        }
+        Operation::LinkHRef { topic, href } => {
+            Event::reference(EventKind::RefLinked, topic, RefEnum::H(href.clone()))
+        }
+        Operation::UnlinkQRef { topic, qref } => {
+            Event::reference(EventKind::RefUnlinked, topic, RefEnum::Q(qref.clone()))
+        }
+        Operation::UnlinkHRef { topic, href } => {
+            Event::reference(EventKind::RefUnlinked, topic, RefEnum::H(href.clone()))
+        }
+    }
+}
+
+/// The collection holding this kind of reference.
+fn ref_collection(reference: &RefEnum) -> Result<&'static str> {
+    match reference {
+        RefEnum::Q(_) => Ok(QRef::COLLECTION_NAME),
+        RefEnum::H(_) => Ok(HRef::COLLECTION_NAME),
+        RefEnum::B(_) => Err(Error::validation("Book references are not stored")),
+    }
+}
+
+/// A record with the key it is stored under.
+#[derive(Serialize)]
+struct MergeKey<T> {
+    #[serde(rename = "_key")]
+    key: String,
+    #[serde(flatten)]
+    value: T,
+}
+
+/// The key an exported reference is restored under.
+/// Its id must name the collection its kind is stored in.
+fn restored_key(r: &ExportRef) -> Result<String> {
+    let collection = ref_collection(&r.reference)?;
+    match r.id.split_once('/') {
+        Some((c, key)) if c == collection && !key.is_empty() => Ok(key.to_string()),
+        _ => Err(Error::validation(format!(
+            "Reference id {} is not in {}",
+            r.id, collection
+        ))),
+    }
+}
+
+/// Writes the records of `Database::restore_entries`, replacing existing ones.
+async fn restore<D: DatabaseAccess + ?Sized>(
+    db: &D,
+    topics: Vec<Topic>,
+    qrefs: Vec<Value>,
+    hrefs: Vec<Value>,
+    edges: Vec<ExportEdge>,
+) -> Result<()> {
+    let upsert = r#"
+        FOR doc IN @docs
+            UPSERT { _key: doc._key }
+                INSERT doc
+                REPLACE doc
+                IN @@collection
+    "#;
+    for (collection, docs) in [
+        (Topic::COLLECTION_NAME, json!(topics)),
+        (QRef::COLLECTION_NAME, json!(qrefs)),
+        (HRef::COLLECTION_NAME, json!(hrefs)),
+    ] {
+        let vars = HashMap::from([("@collection", json!(collection)), ("docs", docs)]);
+        aql::<Value, D>(db, upsert, vars).await?;
+    }
+
+    let query = r#"
+        FOR e IN @edges
+            LET doc = {
+                _key: e.id,
+                _from: CONCAT(@topics, "/", e.topic),
+                _to: e.ref,
+                note: e.note
+            }
+            UPSERT { _key: e.id }
+                INSERT doc
+                REPLACE doc
+                IN @@edges
+    "#;
+    let vars = HashMap::from([
+        ("@edges", json!(RefEdge::COLLECTION_NAME)),
+        ("topics", json!(Topic::COLLECTION_NAME)),
+        ("edges", json!(edges)),
+    ]);
+    aql::<Value, D>(db, query, vars).await.map(|_| ())
+}
+
+/// Finds a topic by its name.
+async fn find_topic_by_name<D: DatabaseAccess + ?Sized>(
+    db: &D,
+    name: &str,
+) -> Result<Option<Topic>> {
+    let query = "FOR t IN @@topics FILTER t.name == @name LIMIT 1 RETURN t";
+    let vars = HashMap::from([
+        ("@topics", json!(Topic::COLLECTION_NAME)),
+        ("name", json!(name)),
+    ]);
+    aql(db, query, vars).await.map(|mut t: Vec<Topic>| t.pop())
+}
+
+/// The conflict error for a topic name that is already taken.
+async fn topic_conflict<D: DatabaseAccess + ?Sized>(db: &D, name: &str) -> Error {
+    let key = match find_topic_by_name(db, name).await {
+        Ok(Some(t)) => t.key,
+        _ => None,
+    };
+    Error::conflict(format!("Topic {} already exists", name)).with_details(json!({ "key": key }))
+}
+
+/// Runs a keyset paginated AQL query.
+///
+/// `source` is the body of a query that binds `item`, `sort_value` and `key`
+/// for each row. Rows are ordered by `sort_value` then `key`, and the cursor
+/// points at the last row seen, so deep pages cost the same as the first.
+/// Split the term into `n` pieces, as even as can be. A label within `n - 1` edits of the
+/// term, or whose start is, holds at least one of them, as each edit breaks at most one piece.
+/// Terms shorter than `n` are their own only piece.
+fn pieces(q: &str, n: usize) -> Vec<String> {
+    let chars: Vec<char> = q.chars().collect();
+    if chars.len() < n {
+        return vec![q.to_string()];
+    }
+    (0..n)
+        .map(|i| {
+            chars[i * chars.len() / n..(i + 1) * chars.len() / n]
+                .iter()
+                .collect()
+        })
+        .collect()
+}
+
+async fn paginate<T: DeserializeOwned>(
+    db: &DatabaseConnection,
+    source: &str,
+    mut vars: HashMap<&str, Value>,
+    order: SortOrder,
+    size: u32,
+    cursor: Option<Cursor>,
+) -> Result<Page<T>> {
+    let backward = cursor.as_ref().is_some_and(|c| c.backward);
+    // Paging backwards walks the listing in reverse, then flips the rows
+    let (cmp, dir) = match (order, backward) {
+        (SortOrder::Asc, false) | (SortOrder::Desc, true) => (">", "ASC"),
+        (SortOrder::Desc, false) | (SortOrder::Asc, true) => ("<", "DESC"),
+    };
+    let query = format!(
+        r#"
+        LET total = LENGTH({source} RETURN 1)
+        LET rows = (
+            {source}
+            FILTER @cursor == null
+                OR sort_value {cmp} @cursor.v
+                OR (sort_value == @cursor.v AND key {cmp} @cursor.k)
+            SORT sort_value {dir}, key {dir}
+            LIMIT @size
+            RETURN {{ item, value: sort_value, key }}
+        )
+        RETURN {{ total, rows }}
+    "#
+    );
+    vars.insert("cursor", json!(cursor));
+    // One extra row tells whether there is anything past this page
+    vars.insert("size", json!(size + 1));
+
+    let Rows { total, mut rows } = aql::<Rows<T>, _>(db, &query, vars)
+        .await?
+        .pop()
+        .ok_or_else(|| Error::internal("Paginated query returned nothing"))?;
+    let more = rows.len() > size as usize;
+    rows.truncate(size as usize);
+    if backward {
+        rows.reverse();
+    }
+
+    // Coming from a cursor there is always a page in the direction we came from
+    let (has_next, has_prev) = if backward {
+        (true, more)
+    } else {
+        (more, cursor.is_some())
+    };
+    let cursor_at = |row: &Row<T>, backward: bool| {
+        Cursor {
+            value: row.value.clone(),
+            key: row.key.clone(),
+            backward,
+        }
+        .encode()
+    };
+    let next_cursor = rows
+        .last()
+        .filter(|_| has_next)
+        .map(|r| cursor_at(r, false));
+    let prev_cursor = rows
+        .first()
+        .filter(|_| has_prev)
+        .map(|r| cursor_at(r, true));
+
+    Ok(Page {
+        items: rows.into_iter().map(|r| r.item).collect(),
+        total,
+        next_cursor,
+        prev_cursor,
+    })
+}
+
 pub struct Database {
     db: DatabaseConnection,
+    events: EventBus,
 }
 
 #[automock]
 impl Database {
-    pub async fn new(cfg: Config) -> Self {
+    /// events: Where changes are published once they succeed.
+    pub async fn new(cfg: Config, events: EventBus) -> Self {
         let db = DatabaseConnection::builder()
             .with_credentials(&cfg.address, &cfg.db_name, &cfg.username, &cfg.pass)
             .with_schema_path(&cfg.schema_path)
             .build()
             .await
             .expect("Failed to create a database connection...");
-        Database { db }
+        Database { db, events }
     }
 
     pub async fn health(&self) -> Result<()> {
@@ -33,7 +379,7 @@ impl Database {
             .args(["-u", "root", "describe"])
             .output()
             .map(|_| ())
-            .map_err(Error::default)
+            .map_err(Error::storage)
     }
 
     pub fn migrate() -> Result<()> {
@@ -41,62 +387,593 @@ impl Database {
             .args(["-u", "root", "migrate"])
             .output()
             .map(|_| ())
-            .map_err(Error::default)
+            .map_err(Error::storage)
     }
 
-    pub async fn get_topics(&self, page: u32, size: u32) -> Result<Vec<String>> {
-        let skip = (page - 1) * size;
-        Topic::query()
-            .limit(size, Some(skip))
-            .call(&self.db)
-            .await
-            .map(|r: QueryResult<Topic>| r.0.into_iter().map(|r| r.record.name).collect())
-            .map_err(Error::default)
+    /// Get a page of topic names.
+    /// Topics are always ordered, falling back to the key to break ties,
+    /// so the same page holds the same topics across requests.
+    pub async fn get_topics(
+        &self,
+        size: u32,
+        cursor: Option<Cursor>,
+        filter: TopicFilter,
+    ) -> Result<Page<String>> {
+        let source = r#"
+            FOR t IN @@topics
+                LET ref_count = LENGTH(FOR r IN 1..1 OUTBOUND t @@edges RETURN 1)
+                FILTER @has_refs == null OR (ref_count > 0) == @has_refs
+                FILTER @parent == null OR t.parent == @parent
+                FILTER @created_by == null OR t.created_by == @created_by
+                FILTER @updated_since == null OR t.updated_at >= @updated_since
+                LET item = t.name
+                LET sort_value = @sort == "ref_count" ? ref_count : t[@sort]
+                LET key = t._key
+        "#;
+        let vars = HashMap::from([
+            ("@topics", json!(Topic::COLLECTION_NAME)),
+            ("@edges", json!(RefEdge::COLLECTION_NAME)),
+            ("has_refs", json!(filter.has_refs)),
+            ("parent", json!(filter.parent)),
+            ("created_by", json!(filter.created_by)),
+            ("updated_since", json!(filter.updated_since)),
+            ("sort", json!(filter.sort.as_str())),
+        ]);
+        paginate(&self.db, source, vars, filter.order, size, cursor).await
+    }
+
+    /// Search topics by name, alias and translated names.
+    /// Exact matches rank first, then prefix matches, then substring matches
+    /// and finally fuzzy matches within a small edit distance.
+    /// Ties are broken by the number of references, most referenced first.
+    ///
+    /// q: The search term, matched case insensitively.
+    /// limit: The maximum number of matches.
+    pub async fn search_topics(&self, q: &str, limit: u32) -> Result<Vec<TopicMatch>> {
+        let q = q.trim().to_lowercase();
+        // Allow one typo in short terms and two in longer ones
+        let max_distance = if q.chars().count() <= 4 { 1 } else { 2 };
+        // Only labels holding a piece of the term can be close enough, so the costly
+        // edit distances are only computed for those
+        let query = r#"
+            FOR t IN @@topics
+                LET labels = UNION_DISTINCT([t.name], t.aliases || [], VALUES(t.names || {}))
+                LET hits = (
+                    FOR l IN labels
+                        LET label = LOWER(l)
+                        FILTER LENGTH(@pieces[* FILTER CONTAINS(label, CURRENT)]) > 0
+                        LET distance = MIN([
+                            LEVENSHTEIN_DISTANCE(label, @q),
+                            LEVENSHTEIN_DISTANCE(LEFT(label, LENGTH(@q)), @q)
+                        ])
+                        LET tier = label == @q ? 0
+                            : STARTS_WITH(label, @q) ? 1
+                            : CONTAINS(label, @q) ? 2
+                            : distance <= @max_distance ? 3
+                            : 4
+                        FILTER tier < 4
+                        SORT tier, distance
+                        LIMIT 1
+                        RETURN { label: l, tier, distance }
+                )
+                FILTER LENGTH(hits) > 0
+                LET ref_count = LENGTH(FOR r IN 1..1 OUTBOUND t @@edges RETURN 1)
+                SORT hits[0].tier, hits[0].distance, ref_count DESC, t.name
+                LIMIT @limit
+                RETURN { key: t._key, name: t.name, matched: hits[0].label, ref_count }
+        "#;
+        let vars = HashMap::from([
+            ("@topics", json!(Topic::COLLECTION_NAME)),
+            ("@edges", json!(RefEdge::COLLECTION_NAME)),
+            ("pieces", json!(pieces(&q, max_distance + 1))),
+            ("q", json!(q)),
+            ("max_distance", json!(max_distance)),
+            ("limit", json!(limit)),
+        ]);
+        aql(&self.db, query, vars).await
+    }
+
+    /// Find a topic by its name.
+    pub async fn find_topic_by_name(&self, name: &str) -> Result<Option<Topic>> {
+        find_topic_by_name(&self.db, name).await
+    }
+
+    /// Create a topic, keyed by its name.
+    /// Fails with a conflict holding the key of the existing topic if the name is taken.
+    pub async fn add_topic(&self, topic: Topic) -> Result<()> {
+        let now = Utc::now();
+        let name = topic.name.clone();
+        let t = Topic {
+            key: Some(topic.name.clone()),
+            created_at: Some(now),
+            updated_at: Some(now),
+            ..topic
+        };
+        match DatabaseRecord::create(t, &self.db).await {
+            Ok(_) => {
+                self.events
+                    .publish(Event::topic(EventKind::TopicCreated, &name));
+                Ok(())
+            }
+            Err(aragog::Error::Conflict(_)) => Err(topic_conflict(&self.db, &name).await),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Create or replace the topic with this key.
+    /// Returns whether the topic was created.
+    ///
+    /// create_only: Fail with a failed precondition if the topic exists, for `If-None-Match: *`.
+    pub async fn upsert_topic(&self, key: &str, topic: Topic, create_only: bool) -> Result<bool> {
+        // The name is unique, but may only be reused by the same topic
+        if let Some(existing) = self.find_topic_by_name(&topic.name).await? {
+            if existing.key.as_deref() != Some(key) {
+                return Err(topic_conflict(&self.db, &topic.name).await);
+            }
+        }
+
+        let now = Utc::now();
+        if create_only {
+            let t = Topic {
+                key: Some(key.to_string()),
+                created_at: Some(now),
+                updated_at: Some(now),
+                ..topic
+            };
+            return match DatabaseRecord::create(t, &self.db).await {
+                Ok(_) => {
+                    self.events
+                        .publish(Event::topic(EventKind::TopicCreated, key));
+                    Ok(true)
+                }
+                Err(aragog::Error::Conflict(_)) => Err(Error::precondition_failed(format!(
+                    "Topic {} already exists",
+                    key
+                ))
+                .with_details(json!({ "key": key }))),
+                Err(e) => Err(e.into()),
+            };
+        }
+
+        // Keep who created the topic and when, whatever the request says
+        let query = r#"
+            UPSERT { _key: @key }
+                INSERT MERGE(UNSET(@topic, "_key"), { _key: @key, created_at: @now, updated_at: @now })
+                UPDATE MERGE(UNSET(@topic, "_key", "created_by", "created_at"), { updated_at: @now })
+                IN @@topics
+                RETURN OLD == null
+        "#;
+        let vars = HashMap::from([
+            ("@topics", json!(Topic::COLLECTION_NAME)),
+            ("key", json!(key)),
+            ("topic", json!(topic)),
+            ("now", json!(now)),
+        ]);
+        let created = aql::<bool, _>(&self.db, query, vars)
+            .await?
+            .pop()
+            .ok_or_else(|| Error::internal("Upsert returned nothing"))?;
+        let kind = match created {
+            true => EventKind::TopicCreated,
+            false => EventKind::TopicUpdated,
+        };
+        self.events.publish(Event::topic(kind, key));
+        Ok(created)
     }
 
-    pub async fn add_topic(&self, name: &str) -> Result<()> {
-        let t = Topic::new(name);
-        DatabaseRecord::create(t, &self.db)
+    pub async fn get_topic(&self, key: &str) -> Result<Topic> {
+        DatabaseRecord::<Topic>::find(key, &self.db)
             .await
-            .map_err(Error::default)
-            .map(|_| ())
+            .map(|r| r.record)
+            .map_err(Error::from)
+    }
+
+    /// Get the keys of the topics whose parent is this topic.
+    pub async fn get_topic_children(&self, key: &str) -> Result<Vec<String>> {
+        let query = "FOR t IN @@topics FILTER t.parent == @key SORT t._key RETURN t._key";
+        let vars = HashMap::from([
+            ("@topics", json!(Topic::COLLECTION_NAME)),
+            ("key", json!(key)),
+        ]);
+        aql(&self.db, query, vars).await
     }
 
     pub async fn delete_topic(&self, topic: &str) -> Result<()> {
         DatabaseRecord::<Topic>::find(topic, &self.db)
             .await
-            .map_err(Error::default)?
+            .map_err(Error::from)?
             .delete(&self.db)
             .await
-            .map(Into::into)
-            .map_err(Error::default)
+            .map_err(Error::from)?;
+        self.events
+            .publish(Event::topic(EventKind::TopicDeleted, topic));
+        Ok(())
     }
 
     pub async fn add_qref_to_topic(&self, topic: &str, q_ref: QRef) -> Result<()> {
-        let t = Transaction::new(&self.db).await.map_err(Error::default)?;
+        let event = Event::reference(EventKind::RefLinked, topic, RefEnum::Q(q_ref.clone()));
+        let t = Transaction::new(&self.db).await.map_err(Error::from)?;
         t.safe_execute(|con| async move {
             let r = DatabaseRecord::create(q_ref, &con).await?;
             let to = Topic::find(topic, &con).await?;
-            DatabaseRecord::link(&to, &r, &con, RefEdge {}).await?;
+            DatabaseRecord::link(&to, &r, &con, RefEdge::default()).await?;
             log::debug!("linked topic");
             Ok(())
         })
         .await
         .and_then(Into::into)
-        .map_err(Error::default)
+        .map_err(Error::from)?;
+        self.events.publish(event);
+        Ok(())
     }
 
     pub async fn add_href_to_topic(&self, topic: &str, h_ref: HRef) -> Result<()> {
-        let t = Transaction::new(&self.db).await.map_err(Error::default)?;
+        let event = Event::reference(EventKind::RefLinked, topic, RefEnum::H(h_ref.clone()));
+        let t = Transaction::new(&self.db).await.map_err(Error::from)?;
         t.safe_execute(|con| async move {
             let r = DatabaseRecord::create(h_ref, &con).await?;
             let t = Topic::find(topic, &con).await?;
-            DatabaseRecord::link(&t, &r, &con, RefEdge {}).await?;
+            DatabaseRecord::link(&t, &r, &con, RefEdge::default()).await?;
             Ok(())
         })
         .await
         .and_then(Into::into)
-        .map_err(Error::default)
+        .map_err(Error::from)?;
+        self.events.publish(event);
+        Ok(())
+    }
+
+    /// Run a batch of operations in one transaction.
+    /// Stops at the first failing operation, and rolls back the ones before it.
+    ///
+    /// user: The email of the user, recorded on created topics.
+    pub async fn run_batch(
+        &self,
+        ops: Vec<Operation>,
+        user: Option<String>,
+    ) -> Result<BatchResult> {
+        let t = Transaction::new(&self.db).await.map_err(Error::from)?;
+        let con = t.database_connection();
+        let mut results = Vec::with_capacity(ops.len());
+        let mut events = Vec::with_capacity(ops.len());
+        let mut failed = false;
+        for (index, op) in ops.into_iter().enumerate() {
+            if failed {
+                results.push(OpResult::new(index, OpStatus::Skipped));
+                continue;
+            }
+            let event = event_of(&op);
+            match apply(con, op, user.as_deref()).await {
+                Ok(()) => {
+                    results.push(OpResult::new(index, OpStatus::Ok));
+                    events.push(event);
+                }
+                Err(e) => {
+                    log::debug!("Batch failed at operation {}: {:?}", index, e);
+                    failed = true;
+                    results.push(OpResult::failed(index, e));
+                }
+            }
+        }
+
+        if failed {
+            t.abort().await.map_err(Error::from)?;
+            for r in results.iter_mut().filter(|r| r.status == OpStatus::Ok) {
+                r.status = OpStatus::RolledBack;
+            }
+        } else {
+            t.commit().await.map_err(Error::from)?;
+            events.into_iter().for_each(|e| self.events.publish(e));
+        }
+        Ok(BatchResult {
+            committed: !failed,
+            results,
+        })
+    }
+
+    /// Import rows of topic–reference mappings.
+    /// Missing topics are created, and references the topic already has are left alone,
+    /// so importing the same rows again changes nothing.
+    /// A row that fails is reported with its line, and does not stop the others.
+    ///
+    /// user: The email of the user, recorded on created topics.
+    /// dry_run: Only count what would change.
+    pub async fn import_rows(
+        &self,
+        rows: Vec<ImportRow>,
+        user: Option<String>,
+        dry_run: bool,
+    ) -> Result<ImportReport> {
+        let mut report = ImportReport {
+            dry_run,
+            ..Default::default()
+        };
+        // What a dry run would have created by now, so repeats are not counted twice
+        let mut planned = HashSet::new();
+        for row in rows {
+            let line = row.line;
+            match self.import_row(row, &user, dry_run, &mut planned).await {
+                Ok((topic_created, ref_linked)) => {
+                    report.topics_created += topic_created as usize;
+                    report.refs_linked += ref_linked as usize;
+                    report.unchanged += (!topic_created && !ref_linked) as usize;
+                }
+                Err(e) => report.errors.push(RowError {
+                    line,
+                    message: e.message().to_string(),
+                }),
+            }
+        }
+        Ok(report)
+    }
+
+    /// Import one row, returning whether it created the topic and whether it linked the reference.
+    ///
+    /// planned: The topics and references a dry run would already have created.
+    async fn import_row(
+        &self,
+        row: ImportRow,
+        user: &Option<String>,
+        dry_run: bool,
+        planned: &mut HashSet<String>,
+    ) -> Result<(bool, bool)> {
+        let reference = format!("{}|{}", row.topic, json!(row.reference));
+        let key = match self.find_topic_by_name(&row.topic).await? {
+            Some(t) => t.key,
+            None if dry_run => {
+                let created = planned.insert(row.topic.clone());
+                return Ok((created, planned.insert(reference)));
+            }
+            None => {
+                let topic = Topic {
+                    created_by: user.clone(),
+                    ..Topic::new(&row.topic)
+                };
+                self.add_topic(topic).await?;
+                let name = row.topic.clone();
+                return self.link_row(&name, row).await.map(|_| (true, true));
+            }
+        };
+        let key = key.unwrap_or_else(|| row.topic.clone());
+        if self.has_ref(&key, &row.reference).await? {
+            Ok((false, false))
+        } else if dry_run {
+            Ok((false, planned.insert(reference)))
+        } else {
+            self.link_row(&key, row).await.map(|_| (false, true))
+        }
+    }
+
+    /// Whether the topic already points at this reference.
+    async fn has_ref(&self, topic: &str, reference: &RefEnum) -> Result<bool> {
+        let query = r#"
+            FOR r IN 1..1 OUTBOUND @topic @@edges
+                FILTER IS_SAME_COLLECTION(@refs, r) AND MATCHES(r, @ref)
+                LIMIT 1
+                RETURN 1
+        "#;
+        let vars = HashMap::from([
+            (
+                "topic",
+                json!(format!("{}/{}", Topic::COLLECTION_NAME, topic)),
+            ),
+            ("@edges", json!(RefEdge::COLLECTION_NAME)),
+            ("refs", json!(ref_collection(reference)?)),
+            ("ref", json!(reference)),
+        ]);
+        aql::<Value, _>(&self.db, query, vars)
+            .await
+            .map(|r| !r.is_empty())
+    }
+
+    /// Link the reference of an imported row, with its note, in one transaction.
+    async fn link_row(&self, topic: &str, row: ImportRow) -> Result<()> {
+        let event = Event::reference(EventKind::RefLinked, topic, row.reference.clone());
+        let edge = RefEdge { note: row.note };
+        let t = Transaction::new(&self.db).await.map_err(Error::from)?;
+        let con = t.database_connection();
+        let linked = match row.reference {
+            RefEnum::Q(q) => link_ref(con, topic, q, edge).await,
+            RefEnum::H(h) => link_ref(con, topic, h, edge).await,
+            RefEnum::B(_) => Err(Error::validation("Book references cannot be imported")),
+        };
+        match linked {
+            Ok(()) => {
+                t.commit().await.map_err(Error::from)?;
+                self.events.publish(event);
+                Ok(())
+            }
+            Err(e) => {
+                t.abort().await.map_err(Error::from)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Get the next entries of a kind for an export, ordered by id.
+    ///
+    /// after: The id of the last entry of the previous batch.
+    pub async fn export_entries(
+        &self,
+        kind: EntryKind,
+        after: Option<String>,
+        size: u32,
+    ) -> Result<Vec<Entry>> {
+        let (query, mut vars) = match kind {
+            EntryKind::Topics => (
+                r#"
+                FOR t IN @@topics
+                    FILTER @after == null OR t._key > @after
+                    SORT t._key
+                    LIMIT @size
+                    RETURN MERGE(UNSET(t, "_id", "_rev"), { type: "topic" })
+                "#,
+                HashMap::from([("@topics", json!(Topic::COLLECTION_NAME))]),
+            ),
+            EntryKind::Refs => (
+                r#"
+                FOR r IN UNION((FOR r IN @@qrefs RETURN r), (FOR r IN @@hrefs RETURN r))
+                    FILTER @after == null OR r._id > @after
+                    SORT r._id
+                    LIMIT @size
+                    RETURN MERGE(UNSET(r, "_key", "_id", "_rev"), { type: "ref", id: r._id })
+                "#,
+                HashMap::from([
+                    ("@qrefs", json!(QRef::COLLECTION_NAME)),
+                    ("@hrefs", json!(HRef::COLLECTION_NAME)),
+                ]),
+            ),
+            EntryKind::Edges => (
+                r#"
+                FOR e IN @@edges
+                    FILTER @after == null OR e._key > @after
+                    SORT e._key
+                    LIMIT @size
+                    RETURN {
+                        type: "edge",
+                        id: e._key,
+                        topic: PARSE_IDENTIFIER(e._from).key,
+                        ref: e._to,
+                        note: e.note
+                    }
+                "#,
+                HashMap::from([("@edges", json!(RefEdge::COLLECTION_NAME))]),
+            ),
+        };
+        vars.insert("after", json!(after));
+        vars.insert("size", json!(size));
+        aql(&self.db, query, vars).await
+    }
+
+    /// Write exported entries, keeping their ids.
+    /// Entries that already exist are replaced, so restoring twice is harmless.
+    /// No events are published, listeners should reload after a restore.
+    pub async fn restore_entries(&self, entries: Vec<Entry>) -> Result<()> {
+        let mut topics = vec![];
+        let mut qrefs = vec![];
+        let mut hrefs = vec![];
+        let mut edges = vec![];
+        for entry in entries {
+            match entry {
+                Entry::Header(_) => {}
+                Entry::Topic(t) if t.key.is_none() => {
+                    return Err(Error::validation(format!("Topic {} has no key", t.name)))
+                }
+                Entry::Topic(t) => topics.push(t),
+                Entry::Ref(r) => {
+                    let key = restored_key(&r)?;
+                    match r.reference {
+                        RefEnum::Q(q) => qrefs.push(json!(MergeKey { key, value: q })),
+                        RefEnum::H(h) => hrefs.push(json!(MergeKey { key, value: h })),
+                        RefEnum::B(_) => {
+                            return Err(Error::validation("Book references are not stored"))
+                        }
+                    }
+                }
+                Entry::Edge(e) => {
+                    let to = e.reference.split_once('/').map(|(c, _)| c);
+                    if to != Some(QRef::COLLECTION_NAME) && to != Some(HRef::COLLECTION_NAME) {
+                        return Err(Error::validation(format!(
+                            "Edge {} does not point at a reference",
+                            e.id
+                        )));
+                    }
+                    edges.push(e)
+                }
+            }
+        }
+
+        let t = Transaction::new(&self.db).await.map_err(Error::from)?;
+        let restored = restore(t.database_connection(), topics, qrefs, hrefs, edges).await;
+        match restored {
+            Ok(()) => t.commit().await.map_err(Error::from),
+            Err(e) => {
+                t.abort().await.map_err(Error::from)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Get the topic graph, or the part of it within `depth` edges of the root topic.
+    /// Edges are followed both ways, so a topic reaches the other topics sharing its references.
+    pub async fn get_graph(&self, root: Option<String>, depth: u32) -> Result<Graph> {
+        let vertices = match root {
+            Some(_) => {
+                r#"
+                LET vertices = (
+                    FOR v IN 0..@depth ANY CONCAT(@topics, "/", @root) @@edges
+                        OPTIONS { order: "bfs", uniqueVertices: "global" }
+                        RETURN v
+                )
+                "#
+            }
+            None => {
+                r#"
+                LET vertices = UNION(
+                    (FOR t IN @@topics RETURN t),
+                    (FOR r IN @@qrefs RETURN r),
+                    (FOR r IN @@hrefs RETURN r)
+                )
+                "#
+            }
+        };
+        let query = format!(
+            r#"
+            {vertices}
+            LET ids = vertices[*]._id
+            LET topics = (
+                FOR v IN vertices
+                    FILTER IS_SAME_COLLECTION(@topics, v)
+                    RETURN {{
+                        id: v._id,
+                        name: v.name,
+                        aliases: v.aliases || [],
+                        names: v.names || {{}},
+                        parent: v.parent
+                    }}
+            )
+            LET refs = (
+                FOR v IN vertices
+                    FILTER !IS_SAME_COLLECTION(@topics, v)
+                    RETURN MERGE(UNSET(v, "_key", "_id", "_rev"), {{ id: v._id }})
+            )
+            // Sets of ids, as objects, so each edge is looked up in constant time
+            LET sources = ZIP(topics[*].id, topics[*].id)
+            LET targets = ZIP(ids, ids)
+            LET edges = (
+                FOR e IN @@edges
+                    FILTER HAS(sources, e._from) AND HAS(targets, e._to)
+                    RETURN {{ source: e._from, target: e._to }}
+            )
+            RETURN {{ topics, refs, edges }}
+        "#
+        );
+        let mut vars = HashMap::from([
+            ("topics", json!(Topic::COLLECTION_NAME)),
+            ("@edges", json!(RefEdge::COLLECTION_NAME)),
+        ]);
+        match &root {
+            Some(key) => {
+                vars.insert("root", json!(key));
+                vars.insert("depth", json!(depth));
+            }
+            None => {
+                vars.insert("@topics", json!(Topic::COLLECTION_NAME));
+                vars.insert("@qrefs", json!(QRef::COLLECTION_NAME));
+                vars.insert("@hrefs", json!(HRef::COLLECTION_NAME));
+            }
+        }
+        let graph = aql::<Graph, _>(&self.db, &query, vars)
+            .await?
+            .pop()
+            .unwrap_or_default();
+        match root {
+            Some(key) if graph.topics.is_empty() => {
+                Err(Error::not_found(format!("Topic {} not found", key))
+                    .with_details(json!({ "key": key })))
+            }
+            _ => Ok(graph),
+        }
     }
 
     pub async fn get_refs(&self, topic: &str) -> Result<Vec<RefEnum>> {
@@ -109,7 +986,7 @@ impl Database {
         )
         .call(&self.db)
         .await
-        .map_err(Error::default)?;
+        .map_err(Error::from)?;
 
         // Get all QRefs
         let mut q: Vec<RefEnum> = r
@@ -129,22 +1006,29 @@ impl Database {
         Ok(q)
     }
 
-    pub async fn get_qrefs(&self, topic: &str, page: u32, size: u32) -> Result<Vec<QRef>> {
-        let skip = (page - 1) * size;
-        let r = Query::outbound(
-            1,
-            1,
-            RefEdge::COLLECTION_NAME,
-            &format!("{}/{}", Topic::COLLECTION_NAME, topic),
-        )
-        .limit(size, Some(skip))
-        .call(&self.db)
-        .await
-        .map_err(Error::default)?;
-        Ok(r.get_records::<QRef>()
-            .iter()
-            .map(|r| r.record.clone())
-            .collect())
+    /// Get a page of the Quran references of a topic, in mushaf order.
+    pub async fn get_qrefs(
+        &self,
+        topic: &str,
+        size: u32,
+        cursor: Option<Cursor>,
+    ) -> Result<Page<QRef>> {
+        let source = r#"
+            FOR r IN 1..1 OUTBOUND @topic @@edges
+                FILTER IS_SAME_COLLECTION(@qrefs, r)
+                LET item = r
+                LET sort_value = [r.chapter, r.init_verse, r.final_verse]
+                LET key = r._key
+        "#;
+        let vars = HashMap::from([
+            (
+                "topic",
+                json!(format!("{}/{}", Topic::COLLECTION_NAME, topic)),
+            ),
+            ("@edges", json!(RefEdge::COLLECTION_NAME)),
+            ("qrefs", json!(QRef::COLLECTION_NAME)),
+        ]);
+        paginate(&self.db, source, vars, SortOrder::Asc, size, cursor).await
     }
 
     /// Get the list of topics pointing at this verse.
@@ -157,101 +1041,196 @@ impl Database {
     /// Then gets all the topic names that are pointing to these references.
     ///
     /// qref: The verse reference, can be multiple verses but must be contiguous.
-    /// page: the page to get.
     /// size: The size of each page.
+    /// cursor: Where the page starts.
     pub async fn get_topics_from_qref(
         &self,
         qref: QRef,
-        page: u32,
         size: u32,
-    ) -> Result<Vec<String>> {
-        let skip = (page - 1) * size;
-        // Create query
-        let qrefs_query = QRef::query().filter(
-            Filter::new(Comparison::field("chapter").equals(qref.chapter))
-                .and(Comparison::field("init_verse").lesser_or_equal(qref.init_verse))
-                .and(Comparison::field("final_verse").greater_or_equal(qref.final_verse)),
-        );
-
-        let qrefs: Vec<Query> = QRef::get(&qrefs_query, &self.db)
-            .await
-            .map_err(Error::default)?
-            .0
-            .iter()
-            .map(|r| {
-                r.inbound_query(1, 1, RefEdge::COLLECTION_NAME)
-                    .limit(size, Some(skip))
-            })
-            .collect();
-        let mut topics: Vec<String> = vec![];
-
-        for ref_query in qrefs {
-            let newtopics: Vec<String> = ref_query
-                .call(&self.db)
-                .await
-                .map_err(Error::default)?
-                .0
-                .iter()
-                .map(|r: &DatabaseRecord<Topic>| r.record.name.clone())
-                .collect();
-            topics.extend(newtopics);
-        }
-        // remove duplicate topic names
-        topics.dedup();
-        Ok(topics)
+        cursor: Option<Cursor>,
+    ) -> Result<Page<String>> {
+        let source = r#"
+            FOR t IN UNIQUE(
+                FOR r IN @@qrefs
+                    FILTER r.chapter == @chapter
+                        AND r.init_verse <= @init_verse
+                        AND r.final_verse >= @final_verse
+                    FOR t IN 1..1 INBOUND r @@edges
+                        RETURN t
+            )
+                LET item = t.name
+                LET sort_value = t.name
+                LET key = t._key
+        "#;
+        let vars = HashMap::from([
+            ("@qrefs", json!(QRef::COLLECTION_NAME)),
+            ("@edges", json!(RefEdge::COLLECTION_NAME)),
+            ("chapter", json!(qref.chapter)),
+            ("init_verse", json!(qref.init_verse)),
+            ("final_verse", json!(qref.final_verse)),
+        ]);
+        paginate(&self.db, source, vars, SortOrder::Asc, size, cursor).await
     }
 
     /// Get the list of topics pointing at this Hadith.
     /// Then gets all the topic names that are pointing to these references.
     ///
     /// href: The Hadith reference.
-    /// page: the page to get.
     /// size: The size of each page.
+    /// cursor: Where the page starts.
     pub async fn get_topics_from_href(
         &self,
         href: HRef,
-        page: u32,
         size: u32,
-    ) -> Result<Vec<String>> {
-        let skip = (page - 1) * size;
+        cursor: Option<Cursor>,
+    ) -> Result<Page<String>> {
+        let source = r#"
+            FOR t IN UNIQUE(
+                FOR r IN @@hrefs
+                    FILTER r.collection == @collection AND r.number == @number
+                    FOR t IN 1..1 INBOUND r @@edges
+                        RETURN t
+            )
+                LET item = t.name
+                LET sort_value = t.name
+                LET key = t._key
+        "#;
+        let vars = HashMap::from([
+            ("@hrefs", json!(HRef::COLLECTION_NAME)),
+            ("@edges", json!(RefEdge::COLLECTION_NAME)),
+            ("collection", json!(href.collection)),
+            ("number", json!(href.number)),
+        ]);
+        paginate(&self.db, source, vars, SortOrder::Asc, size, cursor).await
+    }
 
-        let hrefs_query = QRef::query().filter(
-            Filter::new(Comparison::field("collection").equals(href.collection))
-                .and(Comparison::field("number").equals(href.number)),
-        );
+    /// Get all the topics pointing at each of the references.
+    /// Verses match references containing them, as in `get_topics_from_qref`.
+    /// Book references are not stored, so never have topics.
+    pub async fn lookup_topics(&self, refs: Vec<RefEnum>) -> Result<Vec<RefTopics>> {
+        let query = r#"
+            FOR ref IN @refs
+                LET matches = ref.chapter != null ? (
+                    FOR r IN @@qrefs
+                        FILTER r.chapter == ref.chapter
+                            AND r.init_verse <= ref.init_verse
+                            AND r.final_verse >= ref.final_verse
+                        RETURN r
+                ) : ref.collection != null ? (
+                    FOR r IN @@hrefs
+                        FILTER r.collection == ref.collection AND r.number == ref.number
+                        RETURN r
+                ) : []
+                LET topics = SORTED_UNIQUE(
+                    FOR r IN matches
+                        FOR t IN 1..1 INBOUND r @@edges
+                            RETURN t.name
+                )
+                RETURN { ref, topics }
+        "#;
+        let vars = HashMap::from([
+            ("@qrefs", json!(QRef::COLLECTION_NAME)),
+            ("@hrefs", json!(HRef::COLLECTION_NAME)),
+            ("@edges", json!(RefEdge::COLLECTION_NAME)),
+            ("refs", json!(refs)),
+        ]);
+        aql(&self.db, query, vars).await
+    }
 
-        let hrefs: Vec<Query> = HRef::get(&hrefs_query, &self.db)
+    /// Register a webhook, returning it with its key.
+    pub async fn add_webhook(&self, hook: Webhook) -> Result<Webhook> {
+        let r = DatabaseRecord::create(hook, &self.db).await?;
+        Ok(Webhook {
+            key: Some(r.key().clone()),
+            ..r.record
+        })
+    }
+
+    /// Get every webhook, oldest first.
+    pub async fn get_webhooks(&self) -> Result<Vec<Webhook>> {
+        let query = "FOR w IN @@webhooks SORT w.created_at RETURN w";
+        let vars = HashMap::from([("@webhooks", json!(Webhook::COLLECTION_NAME))]);
+        aql(&self.db, query, vars).await
+    }
+
+    pub async fn get_webhook(&self, key: &str) -> Result<Webhook> {
+        Webhook::find(key, &self.db)
             .await
-            .map_err(Error::default)?
-            .0
-            .iter()
-            .map(|r| {
-                r.inbound_query(1, 1, RefEdge::COLLECTION_NAME)
-                    .limit(size, Some(skip))
-            })
-            .collect();
-        let mut topics: Vec<String> = vec![];
-
-        for ref_query in hrefs {
-            let newtopics: Vec<String> = ref_query
-                .call(&self.db)
-                .await
-                .map_err(Error::default)?
-                .0
-                .iter()
-                .map(|r: &DatabaseRecord<Topic>| r.record.name.clone())
-                .collect();
-            topics.extend(newtopics);
-        }
-        // remove duplicate topic names
-        topics.dedup();
-        Ok(topics)
+            .map(|r| r.record)
+            .map_err(Error::from)
+    }
+
+    /// Delete a webhook and its delivery log.
+    pub async fn delete_webhook(&self, key: &str) -> Result<()> {
+        Webhook::find(key, &self.db).await?.delete(&self.db).await?;
+        let query = r#"
+            FOR d IN @@deliveries
+                FILTER d.webhook == @key
+                REMOVE d IN @@deliveries
+        "#;
+        let vars = HashMap::from([
+            ("@deliveries", json!(WebhookDelivery::COLLECTION_NAME)),
+            ("key", json!(key)),
+        ]);
+        aql::<Value, _>(&self.db, query, vars).await.map(|_| ())
+    }
+
+    /// Log a delivery, returning it with its key.
+    pub async fn add_delivery(&self, delivery: WebhookDelivery) -> Result<WebhookDelivery> {
+        let r = DatabaseRecord::create(delivery, &self.db).await?;
+        Ok(WebhookDelivery {
+            key: Some(r.key().clone()),
+            ..r.record
+        })
+    }
+
+    /// Replace a logged delivery, after an attempt.
+    pub async fn update_delivery(&self, delivery: WebhookDelivery) -> Result<()> {
+        let key = delivery
+            .key
+            .clone()
+            .ok_or_else(|| Error::internal("The delivery has no key"))?;
+        let mut doc = WebhookDelivery::find(&key, &self.db).await?;
+        doc.record = delivery;
+        doc.save(&self.db).await.map_err(Error::from)
+    }
+
+    /// Get the last deliveries to a webhook, newest first.
+    pub async fn get_deliveries(&self, webhook: &str, limit: u32) -> Result<Vec<WebhookDelivery>> {
+        let query = r#"
+            FOR d IN @@deliveries
+                FILTER d.webhook == @webhook
+                SORT d.event.at DESC
+                LIMIT @limit
+                RETURN d
+        "#;
+        let vars = HashMap::from([
+            ("@deliveries", json!(WebhookDelivery::COLLECTION_NAME)),
+            ("webhook", json!(webhook)),
+            ("limit", json!(limit)),
+        ]);
+        aql(&self.db, query, vars).await
+    }
+
+    /// Get the deliveries waiting to be tried again, oldest first.
+    pub async fn get_pending_deliveries(&self) -> Result<Vec<WebhookDelivery>> {
+        let query = r#"
+            FOR d IN @@deliveries
+                FILTER d.status == @status
+                SORT d.event.at
+                RETURN d
+        "#;
+        let vars = HashMap::from([
+            ("@deliveries", json!(WebhookDelivery::COLLECTION_NAME)),
+            ("status", json!(DeliveryStatus::Pending)),
+        ]);
+        aql(&self.db, query, vars).await
     }
 
     pub async fn add_session(&self, key: String, session: SessionRecord) -> Result<()> {
         DatabaseRecord::create_with_key(session, key, &self.db)
             .await
-            .map_err(Error::default)
+            .map_err(Error::from)
             .map(|_| ())
     }
 
@@ -259,15 +1238,361 @@ impl Database {
         SessionRecord::find(&state, &self.db)
             .await
             .map(|r| r.record)
-            .map_err(Error::default)
+            .map_err(Error::from)
+    }
+
+    /// Delete a session, logging it out.
+    pub async fn delete_session(&self, state: String) -> Result<()> {
+        SessionRecord::find(&state, &self.db)
+            .await?
+            .delete(&self.db)
+            .await
+            .map_err(Error::from)
+    }
+
+    /// The sessions the user with this email is logged in with, whatever its case.
+    pub async fn get_user_sessions(&self, email: &str) -> Result<Vec<SessionRecord>> {
+        let query = r#"
+            FOR s IN @@sessions
+                FILTER LOWER(s.preferred_username) == LOWER(@email)
+                SORT s.exp DESC
+                RETURN s
+        "#;
+        let vars = HashMap::from([
+            ("@sessions", json!(SessionRecord::COLLECTION_NAME)),
+            ("email", json!(email)),
+        ]);
+        aql(&self.db, query, vars).await
     }
 
-    pub async fn update_session(&self, state: String, token: Token) -> Result<SessionRecord> {
+    /// Delete the sessions of the user with this email, returning how many there were.
+    pub async fn delete_user_sessions(&self, email: &str) -> Result<u64> {
+        let query = r#"
+            FOR s IN @@sessions
+                FILTER LOWER(s.preferred_username) == LOWER(@email)
+                REMOVE s IN @@sessions
+                COLLECT WITH COUNT INTO n
+                RETURN n
+        "#;
+        let vars = HashMap::from([
+            ("@sessions", json!(SessionRecord::COLLECTION_NAME)),
+            ("email", json!(email)),
+        ]);
+        Ok(aql(&self.db, query, vars).await?.pop().unwrap_or(0))
+    }
+
+    /// Delete the sessions that expired before `expired`, or before `renewable` if they
+    /// have a refresh token, and the logins started before `started` and never finished.
+    /// Returns how many were deleted.
+    pub async fn sweep_sessions(
+        &self,
+        expired: DateTime<Utc>,
+        renewable: DateTime<Utc>,
+        started: DateTime<Utc>,
+    ) -> Result<u64> {
+        let query = r#"
+            FOR s IN @@sessions
+                FILTER HAS(s, "exp")
+                    ? s.exp < (s.refresh_token == null ? @expired : @renewable)
+                    : (s.created_at == null OR s.created_at < @started)
+                REMOVE s IN @@sessions
+                COLLECT WITH COUNT INTO n
+                RETURN n
+        "#;
+        let vars = HashMap::from([
+            ("@sessions", json!(SessionRecord::COLLECTION_NAME)),
+            ("expired", json!(expired.timestamp())),
+            ("renewable", json!(renewable.timestamp())),
+            ("started", json!(started.timestamp())),
+        ]);
+        Ok(aql(&self.db, query, vars).await?.pop().unwrap_or(0))
+    }
+
+    /// The role assigned to the user with this email, if any.
+    pub async fn get_role(&self, email: &str) -> Result<Option<RoleAssignment>> {
+        let query = r#"
+            FOR r IN @@roles
+                FILTER r.email == LOWER(@email)
+                LIMIT 1
+                RETURN r
+        "#;
+        let vars = HashMap::from([
+            ("@roles", json!(RoleAssignment::COLLECTION_NAME)),
+            ("email", json!(email)),
+        ]);
+        Ok(aql(&self.db, query, vars).await?.pop())
+    }
+
+    /// The roles assigned to users, by email.
+    pub async fn get_roles(&self) -> Result<Vec<RoleAssignment>> {
+        let query = "FOR r IN @@roles SORT r.email RETURN r";
+        let vars = HashMap::from([("@roles", json!(RoleAssignment::COLLECTION_NAME))]);
+        aql(&self.db, query, vars).await
+    }
+
+    /// Assign a role to a user, replacing the one they had.
+    pub async fn set_role(&self, assignment: RoleAssignment) -> Result<RoleAssignment> {
+        let assignment = RoleAssignment {
+            email: assignment.email.to_lowercase(),
+            ..assignment
+        };
+        let query = r#"
+            UPSERT { email: @assignment.email }
+                INSERT @assignment
+                REPLACE @assignment
+                IN @@roles
+            RETURN NEW
+        "#;
+        let vars = HashMap::from([
+            ("@roles", json!(RoleAssignment::COLLECTION_NAME)),
+            ("assignment", json!(assignment)),
+        ]);
+        aql(&self.db, query, vars)
+            .await?
+            .pop()
+            .ok_or_else(|| Error::internal("Unable to assign the role"))
+    }
+
+    /// Remove the role assigned to a user, so they get the one from their provider groups.
+    pub async fn delete_role(&self, email: &str) -> Result<()> {
+        let query = r#"
+            FOR r IN @@roles
+                FILTER r.email == LOWER(@email)
+                REMOVE r IN @@roles
+                RETURN OLD
+        "#;
+        let vars = HashMap::from([
+            ("@roles", json!(RoleAssignment::COLLECTION_NAME)),
+            ("email", json!(email)),
+        ]);
+        match aql::<Value, _>(&self.db, query, vars).await?.pop() {
+            Some(_) => Ok(()),
+            None => Err(Error::not_found(format!("{} has no assigned role", email))),
+        }
+    }
+
+    /// Save a minted API token, its email lowercased as for roles, returning it with its key.
+    pub async fn add_api_token(&self, token: ApiToken) -> Result<ApiToken> {
+        let token = ApiToken {
+            email: token.email.to_lowercase(),
+            ..token
+        };
+        let r = DatabaseRecord::create(token, &self.db).await?;
+        Ok(ApiToken {
+            key: Some(r.key().clone()),
+            ..r.record
+        })
+    }
+
+    /// The API token with this hash.
+    pub async fn get_api_token(&self, hash: &str) -> Result<ApiToken> {
+        let query = r#"
+            FOR t IN @@tokens
+                FILTER t.hash == @hash
+                LIMIT 1
+                RETURN t
+        "#;
+        let vars = HashMap::from([
+            ("@tokens", json!(ApiToken::COLLECTION_NAME)),
+            ("hash", json!(hash)),
+        ]);
+        aql(&self.db, query, vars)
+            .await?
+            .pop()
+            .ok_or_else(|| Error::not_found("Unknown API token"))
+    }
+
+    /// Give the API tokens of the user with this email the provider and groups of their login.
+    pub async fn update_api_token_groups(
+        &self,
+        email: &str,
+        provider: Option<String>,
+        groups: Vec<String>,
+    ) -> Result<()> {
+        let query = r#"
+            FOR t IN @@tokens
+                FILTER t.email == LOWER(@email)
+                UPDATE t WITH { provider: @provider, groups: @groups } IN @@tokens
+        "#;
+        let vars = HashMap::from([
+            ("@tokens", json!(ApiToken::COLLECTION_NAME)),
+            ("email", json!(email)),
+            ("provider", json!(provider)),
+            ("groups", json!(groups)),
+        ]);
+        aql::<Value, _>(&self.db, query, vars).await.map(|_| ())
+    }
+
+    /// The API tokens of the user with this email, the latest first.
+    pub async fn get_api_tokens(&self, email: &str) -> Result<Vec<ApiToken>> {
+        let query = r#"
+            FOR t IN @@tokens
+                FILTER t.email == LOWER(@email)
+                SORT t.created_at DESC
+                RETURN t
+        "#;
+        let vars = HashMap::from([
+            ("@tokens", json!(ApiToken::COLLECTION_NAME)),
+            ("email", json!(email)),
+        ]);
+        aql(&self.db, query, vars).await
+    }
+
+    /// Revoke an API token of the user with this email.
+    pub async fn delete_api_token(&self, key: &str, email: &str) -> Result<()> {
+        let query = r#"
+            FOR t IN @@tokens
+                FILTER t._key == @key AND t.email == LOWER(@email)
+                REMOVE t IN @@tokens
+                RETURN OLD
+        "#;
+        let vars = HashMap::from([
+            ("@tokens", json!(ApiToken::COLLECTION_NAME)),
+            ("key", json!(key)),
+            ("email", json!(email)),
+        ]);
+        match aql::<Value, _>(&self.db, query, vars).await?.pop() {
+            Some(_) => Ok(()),
+            None => Err(Error::not_found(format!("API token {} not found", key))),
+        }
+    }
+
+    /// Delete the API tokens that expired before `expired`, returning how many there were.
+    pub async fn sweep_api_tokens(&self, expired: DateTime<Utc>) -> Result<u64> {
+        let query = r#"
+            FOR t IN @@tokens
+                FILTER DATE_TIMESTAMP(t.expires_at) < DATE_TIMESTAMP(@expired)
+                REMOVE t IN @@tokens
+                COLLECT WITH COUNT INTO n
+                RETURN n
+        "#;
+        let vars = HashMap::from([
+            ("@tokens", json!(ApiToken::COLLECTION_NAME)),
+            ("expired", json!(expired)),
+        ]);
+        Ok(aql(&self.db, query, vars).await?.pop().unwrap_or(0))
+    }
+
+    /// Revoke the API tokens of the user with this email, returning how many there were.
+    pub async fn delete_user_api_tokens(&self, email: &str) -> Result<u64> {
+        let query = r#"
+            FOR t IN @@tokens
+                FILTER t.email == LOWER(@email)
+                REMOVE t IN @@tokens
+                COLLECT WITH COUNT INTO n
+                RETURN n
+        "#;
+        let vars = HashMap::from([
+            ("@tokens", json!(ApiToken::COLLECTION_NAME)),
+            ("email", json!(email)),
+        ]);
+        Ok(aql(&self.db, query, vars).await?.pop().unwrap_or(0))
+    }
+
+    /// Save a local account. Usernames and emails are unique.
+    pub async fn add_account(&self, account: LocalAccount) -> Result<()> {
+        DatabaseRecord::create(account, &self.db)
+            .await
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    /// The local account with this username.
+    pub async fn get_account(&self, username: &str) -> Result<LocalAccount> {
+        self.find_account("username", username).await
+    }
+
+    /// The local account with this email.
+    pub async fn get_account_by_email(&self, email: &str) -> Result<LocalAccount> {
+        self.find_account("email", email).await
+    }
+
+    async fn find_account(&self, field: &str, value: &str) -> Result<LocalAccount> {
+        let query = r#"
+            FOR a IN @@accounts
+                FILTER a[@field] == LOWER(@value)
+                LIMIT 1
+                RETURN a
+        "#;
+        let vars = HashMap::from([
+            ("@accounts", json!(LocalAccount::COLLECTION_NAME)),
+            ("field", json!(field)),
+            ("value", json!(value)),
+        ]);
+        aql(&self.db, query, vars)
+            .await?
+            .pop()
+            .ok_or_else(|| Error::not_found("Unknown local account"))
+    }
+
+    /// Replace the password hash of a local account.
+    pub async fn set_password(&self, username: &str, hash: &str) -> Result<()> {
+        let query = r#"
+            FOR a IN @@accounts
+                FILTER a.username == @username
+                UPDATE a WITH { hash: @hash, password_changed_at: @at } IN @@accounts
+                RETURN NEW
+        "#;
+        let vars = HashMap::from([
+            ("@accounts", json!(LocalAccount::COLLECTION_NAME)),
+            ("username", json!(username)),
+            ("hash", json!(hash)),
+            ("at", json!(Utc::now())),
+        ]);
+        match aql::<Value, _>(&self.db, query, vars).await?.pop() {
+            Some(_) => Ok(()),
+            None => Err(Error::not_found("Unknown local account")),
+        }
+    }
+
+    /// Take the nonce and PKCE verifier of a login session, so they are used once only.
+    /// Returns the session as it was, with an empty nonce if it was already taken.
+    pub async fn take_login(&self, state: String) -> Result<SessionRecord> {
+        let query = r#"
+            FOR s IN @@sessions
+                FILTER s._key == @key
+                UPDATE s WITH { nonce: "", verifier: null } IN @@sessions OPTIONS { keepNull: false }
+                RETURN OLD
+        "#;
+        let vars = HashMap::from([
+            ("@sessions", json!(SessionRecord::COLLECTION_NAME)),
+            ("key", json!(state)),
+        ]);
+        aql(&self.db, query, vars)
+            .await?
+            .pop()
+            .ok_or_else(|| Error::not_found(format!("Session {} not found", state)))
+    }
+
+    /// Take the refresh token of a session, if it still has this one.
+    /// Returns whether it was taken, which only one of concurrent renewals does.
+    pub async fn take_refresh_token(&self, state: String, refresh_token: String) -> Result<bool> {
+        let query = r#"
+            FOR s IN @@sessions
+                FILTER s._key == @key AND s.refresh_token == @refresh_token
+                UPDATE s WITH { refresh_token: null } IN @@sessions OPTIONS { keepNull: false }
+                RETURN 1
+        "#;
+        let vars = HashMap::from([
+            ("@sessions", json!(SessionRecord::COLLECTION_NAME)),
+            ("key", json!(state)),
+            ("refresh_token", json!(refresh_token)),
+        ]);
+        aql::<Value, _>(&self.db, query, vars)
+            .await
+            .map(|taken| !taken.is_empty())
+    }
+
+    pub async fn update_session(
+        &self,
+        state: String,
+        session: SessionRecord,
+    ) -> Result<SessionRecord> {
         let mut sess_doc: DatabaseRecord<SessionRecord> = SessionRecord::find(&state, &self.db)
             .await
-            .map_err(Error::default)?;
-        sess_doc.token = Some(token);
-        sess_doc.save(&self.db).await.map_err(Error::default)?;
+            .map_err(Error::from)?;
+        sess_doc.record = session;
+        sess_doc.save(&self.db).await.map_err(Error::from)?;
         Ok(sess_doc.record)
     }
 }
@@ -280,3 +1605,19 @@ pub struct Config {
     pub db_name: String,
     pub schema_path: String,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pieces() {
+        assert_eq!(pieces("mercy", 3), ["m", "er", "cy"]);
+        assert_eq!(pieces("fast", 2), ["fa", "st"]);
+        assert_eq!(pieces("a", 2), ["a"]);
+        // Each edit breaks at most one piece, so a typo leaves another whole
+        for typo in ["marcy", "mecry", "mercyy", "merc"] {
+            assert!(pieces("mercy", 2).iter().any(|p| typo.contains(p.as_str())));
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use crate::models::events::{Event, EventKind};
+use actix_web::web::Bytes;
+use chrono::Utc;
+use futures::{stream, Stream, StreamExt};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::{self, error::RecvError, Receiver};
+
+/// How many past events are kept for clients resuming with a last event id.
+const HISTORY: usize = 1000;
+
+/// How many events a slow client may fall behind before it is disconnected.
+const CAPACITY: usize = 256;
+
+/// Publishes changes to the graph to the clients listening for them.
+///
+/// Clones share the same listeners, so the bus can be handed to every worker.
+/// The last events are kept, so a client reconnecting with the id of the last
+/// event it saw gets the ones it missed before the new ones. A client whose
+/// events are no longer kept gets a reset event instead, to reload.
+#[derive(Clone)]
+pub struct EventBus {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    sender: broadcast::Sender<Event>,
+    history: Mutex<History>,
+}
+
+struct History {
+    events: VecDeque<Event>,
+    /// The id of the next event. Ids start at the time the server started, in microseconds,
+    /// so they keep increasing across restarts, and those of an earlier run are told apart.
+    next_id: u64,
+}
+
+impl History {
+    /// Whether every event after `id` is still kept, and `id` is one of this run.
+    fn resumes(&self, id: u64) -> bool {
+        let oldest = self.events.front().map_or(self.next_id, |e| e.id);
+        id < self.next_id && id >= oldest.saturating_sub(1)
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        EventBus {
+            inner: Arc::new(Inner {
+                sender,
+                history: Mutex::new(History {
+                    events: VecDeque::with_capacity(HISTORY),
+                    next_id: Utc::now().timestamp_millis() as u64 * 1000,
+                }),
+            }),
+        }
+    }
+}
+
+impl EventBus {
+    /// Number the event and send it to the listeners.
+    pub fn publish(&self, mut event: Event) {
+        // Numbered and sent under the lock, so listeners see events in order
+        let mut history = self.inner.history.lock().unwrap_or_else(|e| e.into_inner());
+        event.id = history.next_id;
+        history.next_id += 1;
+        if history.events.len() == HISTORY {
+            history.events.pop_front();
+        }
+        history.events.push_back(event.clone());
+        // Nobody listening is fine
+        let _ = self.inner.sender.send(event);
+    }
+
+    /// The id of the last event published, or the one before the first.
+    #[cfg(test)]
+    pub fn last_id(&self) -> u64 {
+        let history = self.inner.history.lock().unwrap_or_else(|e| e.into_inner());
+        history.next_id - 1
+    }
+
+    /// The events after `last_id` that are still kept, or a reset if some were dropped,
+    /// and a receiver for the next ones.
+    fn subscribe(&self, last_id: Option<u64>) -> (Vec<Event>, Receiver<Event>) {
+        let history = self.inner.history.lock().unwrap_or_else(|e| e.into_inner());
+        let missed = match last_id {
+            Some(id) if history.resumes(id) => history
+                .events
+                .iter()
+                .filter(|e| e.id > id)
+                .cloned()
+                .collect(),
+            Some(id) => {
+                log::debug!("Unable to resume after event {}", id);
+                vec![Event::reset(history.next_id - 1)]
+            }
+            None => vec![],
+        };
+        (missed, self.inner.sender.subscribe())
+    }
+
+    /// The events after `last_id`, then the new ones, of one topic or all of them.
+    /// When the events after `last_id` are no longer kept, a reset comes first instead.
+    ///
+    /// The stream ends if the client falls too far behind,
+    /// so that it reconnects and catches up from the history.
+    pub fn listen(&self, last_id: Option<u64>, topic: Option<String>) -> impl Stream<Item = Event> {
+        let (missed, receiver) = self.subscribe(last_id);
+        let live = stream::unfold(receiver, |mut receiver| async move {
+            match receiver.recv().await {
+                Ok(event) => Some((event, receiver)),
+                Err(RecvError::Lagged(n)) => {
+                    log::debug!("Event listener lagged by {} events", n);
+                    None
+                }
+                Err(RecvError::Closed) => None,
+            }
+        });
+        stream::iter(missed).chain(live).filter(move |e| {
+            let keep = e.kind == EventKind::Reset || topic.as_ref().is_none_or(|t| t == &e.topic);
+            async move { keep }
+        })
+    }
+}
+
+/// An event as a message of a `text/event-stream`.
+pub fn message(event: &Event) -> Bytes {
+    let data = serde_json::to_string(event).unwrap_or_default();
+    Bytes::from(format!(
+        "id: {}\nevent: {}\ndata: {}\n\n",
+        event.id,
+        event.kind.name(),
+        data
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::events::EventKind;
+
+    #[actix_web::test]
+    async fn test_resume_and_filter() {
+        let bus = EventBus::default();
+        let start = bus.last_id() + 1;
+        bus.publish(Event::topic(EventKind::TopicCreated, "Mercy"));
+        bus.publish(Event::topic(EventKind::TopicCreated, "Patience"));
+        bus.publish(Event::topic(EventKind::TopicUpdated, "Mercy"));
+
+        let mut events = Box::pin(bus.listen(Some(start), Some("Mercy".to_string())));
+        bus.publish(Event::topic(EventKind::TopicDeleted, "Patience"));
+        bus.publish(Event::topic(EventKind::TopicDeleted, "Mercy"));
+
+        let first = events.next().await.unwrap();
+        assert_eq!((first.id, first.kind), (start + 2, EventKind::TopicUpdated));
+        let second = events.next().await.unwrap();
+        assert_eq!(
+            (second.id, second.kind),
+            (start + 4, EventKind::TopicDeleted)
+        );
+    }
+
+    #[test]
+    fn test_history_bounded() {
+        let bus = EventBus::default();
+        let start = bus.last_id() + 1;
+        for _ in 0..HISTORY + 10 {
+            bus.publish(Event::topic(EventKind::TopicCreated, "Mercy"));
+        }
+        let (missed, _) = bus.subscribe(Some(start + 9));
+        assert_eq!(missed.len(), HISTORY);
+        assert_eq!(missed[0].id, start + 10);
+
+        // The event after it was dropped
+        let (missed, _) = bus.subscribe(Some(start + 8));
+        assert_eq!(missed.len(), 1);
+        assert_eq!(missed[0].kind, EventKind::Reset);
+        assert_eq!(missed[0].id, bus.last_id());
+    }
+
+    #[actix_web::test]
+    async fn test_reset() {
+        let bus = EventBus::default();
+        let start = bus.last_id() + 1;
+        assert!(
+            bus.subscribe(Some(start - 1)).0.is_empty(),
+            "Nothing missed yet"
+        );
+        bus.publish(Event::topic(EventKind::TopicCreated, "Mercy"));
+
+        // From an earlier run, or a later one
+        for id in [1, start - 1000, start + 1] {
+            let (missed, _) = bus.subscribe(Some(id));
+            assert_eq!(missed.len(), 1, "{}", id);
+            assert_eq!((missed[0].id, missed[0].kind), (start, EventKind::Reset));
+        }
+        let mut events = Box::pin(bus.listen(Some(1), Some("Patience".to_string())));
+        assert_eq!(events.next().await.unwrap().kind, EventKind::Reset);
+    }
+
+    #[test]
+    fn test_message() {
+        let event = Event {
+            id: 7,
+            ..Event::topic(EventKind::TopicDeleted, "Mercy")
+        };
+        let msg = String::from_utf8(message(&event).to_vec()).unwrap();
+        assert!(msg.starts_with("id: 7\nevent: topic_deleted\ndata: {\"id\":7,"));
+        assert!(msg.ends_with("}\n\n"));
+    }
+}
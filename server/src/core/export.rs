@@ -0,0 +1,173 @@
+#[mockall_double::double]
+use super::db::Database;
+use crate::models::export::{
+    Document, Entry, EntryKind, ExportFormat, Header, RestoreReport, EXPORT_FORMAT, EXPORT_VERSION,
+};
+use crate::models::generic::Error;
+use actix_web::web::{Bytes, Data};
+use futures::future::ready;
+use futures::stream::{self, Stream, StreamExt};
+use std::io::BufRead;
+
+/// The number of entries fetched, or restored, at a time.
+const BATCH_SIZE: u32 = 1000;
+
+/// Streams the whole graph: a header, then topics, references and edges.
+///
+/// Only one batch of entries is in memory at a time. A database failure ends
+/// the stream early, so a truncated export fails to read rather than restoring partially.
+pub fn stream(
+    db: Data<Database>,
+    format: ExportFormat,
+) -> impl Stream<Item = Result<Bytes, Error>> {
+    let header = Header::now();
+    let (open, close) = match format {
+        ExportFormat::Ndjson => (
+            encode(&[Entry::Header(header)], format, true),
+            Ok(Bytes::new()),
+        ),
+        ExportFormat::Json => {
+            // The document is written around the entries, which are never all in memory
+            let mut open = serde_json::to_string(&header).unwrap_or_default();
+            open.pop();
+            open.push_str(r#","entries":["#);
+            (Ok(Bytes::from(open)), Ok(Bytes::from_static(b"]}\n")))
+        }
+    };
+
+    let batches = stream::unfold(
+        (Some(EntryKind::Topics), None::<String>),
+        move |(kind, mut after)| {
+            let db = db.clone();
+            async move {
+                let mut kind = kind?;
+                loop {
+                    match db.export_entries(kind, after.clone(), BATCH_SIZE).await {
+                        Ok(batch) if batch.is_empty() => {
+                            kind = kind.next()?;
+                            after = None;
+                        }
+                        Ok(batch) => {
+                            let last = batch.last().and_then(Entry::id).map(String::from);
+                            return Some((Ok(batch), (Some(kind), last)));
+                        }
+                        Err(e) => return Some((Err(e), (None, None))),
+                    }
+                }
+            }
+        },
+    )
+    .enumerate()
+    .map(move |(i, batch)| batch.and_then(|b| encode(&b, format, i == 0)));
+
+    stream::once(ready(open))
+        .chain(batches)
+        .chain(stream::once(ready(close)))
+}
+
+/// Encodes a batch of entries. JSON entries are separated by commas across batches.
+fn encode(entries: &[Entry], format: ExportFormat, first: bool) -> Result<Bytes, Error> {
+    let mut out = vec![];
+    for (i, entry) in entries.iter().enumerate() {
+        match format {
+            ExportFormat::Ndjson => {
+                serde_json::to_writer(&mut out, entry).map_err(Error::internal)?;
+                out.push(b'\n');
+            }
+            ExportFormat::Json => {
+                if !(first && i == 0) {
+                    out.push(b',');
+                }
+                serde_json::to_writer(&mut out, entry).map_err(Error::internal)?;
+            }
+        }
+    }
+    Ok(Bytes::from(out))
+}
+
+/// Reads an export, checking it is one this version can restore.
+///
+/// NDJSON is read a line at a time, as the entries are restored, so large exports are
+/// never all in memory. A JSON document is parsed whole.
+pub fn read<'a, R: BufRead + 'a>(
+    reader: R,
+    format: ExportFormat,
+) -> Result<Box<dyn Iterator<Item = Result<Entry, Error>> + 'a>, Error> {
+    match format {
+        ExportFormat::Json => {
+            let doc: Document = serde_json::from_reader(reader)
+                .map_err(|e| Error::validation(format!("Invalid export: {}", e)))?;
+            check(&doc.header)?;
+            Ok(Box::new(doc.entries.into_iter().map(Ok)))
+        }
+        ExportFormat::Ndjson => {
+            let mut lines = reader
+                .lines()
+                .enumerate()
+                .filter(|(_, line)| !matches!(line, Ok(line) if line.trim().is_empty()))
+                .map(|(i, line)| {
+                    let invalid = |e: &dyn std::fmt::Display| {
+                        Error::validation(format!("Line {}: {}", i + 1, e))
+                    };
+                    serde_json::from_str::<Entry>(&line.map_err(|e| invalid(&e))?)
+                        .map_err(|e| invalid(&e))
+                });
+            match lines.next().transpose()? {
+                Some(Entry::Header(h)) => check(&h)?,
+                _ => return Err(Error::validation("The export has no header")),
+            }
+            Ok(Box::new(lines))
+        }
+    }
+}
+
+fn check(header: &Header) -> Result<(), Error> {
+    if header.format != EXPORT_FORMAT {
+        return Err(Error::validation(format!(
+            "Not an export: the format is {}",
+            header.format
+        )));
+    }
+    if header.version > EXPORT_VERSION {
+        return Err(Error::validation(format!(
+            "The export is version {}, this server reads up to {}",
+            header.version, EXPORT_VERSION
+        )));
+    }
+    Ok(())
+}
+
+/// Restores entries read from an export, a batch at a time.
+/// Each batch is a transaction, and restoring the same export again changes nothing,
+/// so an export failing to read halfway can be restored again once fixed.
+pub async fn restore(
+    db: &Database,
+    entries: impl Iterator<Item = Result<Entry, Error>>,
+    dry_run: bool,
+) -> Result<RestoreReport, Error> {
+    let mut report = RestoreReport {
+        dry_run,
+        ..Default::default()
+    };
+    let mut batch = Vec::with_capacity(BATCH_SIZE as usize);
+    for entry in entries {
+        let entry = entry?;
+        match entry {
+            Entry::Header(_) => {}
+            Entry::Topic(_) => report.topics += 1,
+            Entry::Ref(_) => report.refs += 1,
+            Entry::Edge(_) => report.edges += 1,
+        }
+        if dry_run {
+            continue;
+        }
+        batch.push(entry);
+        if batch.len() == BATCH_SIZE as usize {
+            db.restore_entries(std::mem::take(&mut batch)).await?;
+        }
+    }
+    if !batch.is_empty() {
+        db.restore_entries(batch).await?;
+    }
+    Ok(report)
+}
@@ -0,0 +1,227 @@
+use super::linked::LinkedData;
+use crate::models::graph::{Graph, GraphFormat};
+use crate::models::topics::Topic;
+use aragog::Record;
+use std::collections::HashSet;
+use std::fmt::Write;
+
+/// A node or edge of the rendered graph, with the attributes every format shares.
+struct Node {
+    id: String,
+    kind: &'static str,
+    label: String,
+}
+
+struct Edge<'a> {
+    source: &'a str,
+    target: String,
+    kind: &'static str,
+}
+
+/// Renders the graph for visualization tools, or as linked data.
+pub fn render(graph: &Graph, format: GraphFormat, linked: &LinkedData) -> String {
+    match format {
+        GraphFormat::GraphMl => {
+            let (nodes, edges) = typed(graph);
+            graphml(&nodes, &edges)
+        }
+        GraphFormat::Dot => {
+            let (nodes, edges) = typed(graph);
+            dot(&nodes, &edges)
+        }
+        GraphFormat::Turtle => linked.turtle(graph),
+    }
+}
+
+/// Topics and references are nodes with a `label` and a `kind`
+/// (`topic`, `qref`, `href` or `bref`). Edges have a `kind` too:
+/// `ref` from a topic to its references, and `parent` from a topic to its broader topic.
+fn typed(graph: &Graph) -> (Vec<Node>, Vec<Edge<'_>>) {
+    let mut nodes: Vec<Node> = graph
+        .topics
+        .iter()
+        .map(|t| Node {
+            id: t.id.clone(),
+            kind: "topic",
+            label: t.name.clone(),
+        })
+        .collect();
+    nodes.extend(graph.refs.iter().map(|r| Node {
+        id: r.id.clone(),
+        kind: r.reference.kind(),
+        label: r.reference.citation(),
+    }));
+
+    let ids: HashSet<&str> = nodes.iter().map(|n| n.id.as_str()).collect();
+    let mut edges: Vec<Edge> = graph
+        .edges
+        .iter()
+        .map(|e| Edge {
+            source: &e.source,
+            target: e.target.clone(),
+            kind: "ref",
+        })
+        .collect();
+    // Parents outside a subgraph are left out rather than drawn as bare nodes
+    edges.extend(graph.topics.iter().filter_map(|t| {
+        let parent = format!("{}/{}", Topic::COLLECTION_NAME, t.parent.as_ref()?);
+        ids.contains(parent.as_str()).then(|| Edge {
+            source: &t.id,
+            target: parent,
+            kind: "parent",
+        })
+    }));
+
+    (nodes, edges)
+}
+
+fn graphml(nodes: &[Node], edges: &[Edge]) -> String {
+    let mut out = String::from(concat!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+        "\n",
+        r#"<graphml xmlns="http://graphml.graphdrawing.org/xmlns">"#,
+        "\n",
+        r#"  <key id="label" for="node" attr.name="label" attr.type="string"/>"#,
+        "\n",
+        r#"  <key id="kind" for="node" attr.name="kind" attr.type="string"/>"#,
+        "\n",
+        r#"  <key id="edge_kind" for="edge" attr.name="kind" attr.type="string"/>"#,
+        "\n",
+        r#"  <graph id="Topics" edgedefault="directed">"#,
+        "\n",
+    ));
+    for n in nodes {
+        let _ = writeln!(
+            out,
+            r#"    <node id="{}"><data key="label">{}</data><data key="kind">{}</data></node>"#,
+            xml_escape(&n.id),
+            xml_escape(&n.label),
+            n.kind
+        );
+    }
+    for e in edges {
+        let _ = writeln!(
+            out,
+            r#"    <edge source="{}" target="{}"><data key="edge_kind">{}</data></edge>"#,
+            xml_escape(e.source),
+            xml_escape(&e.target),
+            e.kind
+        );
+    }
+    out.push_str("  </graph>\n</graphml>\n");
+    out
+}
+
+fn dot(nodes: &[Node], edges: &[Edge]) -> String {
+    let mut out = String::from("digraph Topics {\n");
+    for n in nodes {
+        let shape = if n.kind == "topic" { "box" } else { "ellipse" };
+        let _ = writeln!(
+            out,
+            r#"  "{}" [label="{}", kind="{}", shape={}];"#,
+            dot_escape(&n.id),
+            dot_escape(&n.label),
+            n.kind,
+            shape
+        );
+    }
+    for e in edges {
+        let style = if e.kind == "parent" {
+            ", style=dashed"
+        } else {
+            ""
+        };
+        let _ = writeln!(
+            out,
+            r#"  "{}" -> "{}" [kind="{}"{}];"#,
+            dot_escape(e.source),
+            dot_escape(&e.target),
+            e.kind,
+            style
+        );
+    }
+    out.push_str("}\n");
+    out
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::export::ExportRef;
+    use crate::models::graph::{GraphEdge, GraphTopic};
+    use crate::models::refs::{QRef, RefEnum};
+    use std::collections::HashMap;
+
+    fn linked() -> LinkedData {
+        LinkedData::new("http://localhost:8000")
+    }
+
+    fn graph() -> Graph {
+        Graph {
+            topics: vec![
+                GraphTopic {
+                    id: "Topic/Mercy".to_string(),
+                    name: "Mercy & \"Rahma\"".to_string(),
+                    aliases: vec![],
+                    names: HashMap::new(),
+                    parent: Some("Attributes".to_string()),
+                },
+                GraphTopic {
+                    id: "Topic/Attributes".to_string(),
+                    name: "Attributes".to_string(),
+                    aliases: vec![],
+                    names: HashMap::new(),
+                    parent: Some("Outside".to_string()),
+                },
+            ],
+            refs: vec![ExportRef {
+                id: "QRef/1".to_string(),
+                reference: RefEnum::Q(QRef {
+                    chapter: 7,
+                    init_verse: 156,
+                    final_verse: 157,
+                }),
+            }],
+            edges: vec![GraphEdge {
+                source: "Topic/Mercy".to_string(),
+                target: "QRef/1".to_string(),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_render_dot() {
+        let dot = render(&graph(), GraphFormat::Dot, &linked());
+        assert!(
+            dot.contains(r#""Topic/Mercy" [label="Mercy & \"Rahma\"", kind="topic", shape=box];"#)
+        );
+        assert!(dot.contains(r#""QRef/1" [label="7:156-157", kind="qref", shape=ellipse];"#));
+        assert!(dot.contains(r#""Topic/Mercy" -> "QRef/1" [kind="ref"];"#));
+        assert!(
+            dot.contains(r#""Topic/Mercy" -> "Topic/Attributes" [kind="parent", style=dashed];"#)
+        );
+        assert!(!dot.contains("Outside"));
+    }
+
+    #[test]
+    fn test_render_graphml() {
+        let xml = render(&graph(), GraphFormat::GraphMl, &linked());
+        assert!(xml.contains(r#"<data key="label">Mercy &amp; &quot;Rahma&quot;</data>"#));
+        assert!(xml.contains(
+            r#"<edge source="Topic/Mercy" target="QRef/1"><data key="edge_kind">ref</data></edge>"#
+        ));
+        assert_eq!(xml.matches("<node ").count(), 3);
+        assert_eq!(xml.matches("<edge ").count(), 2);
+    }
+}
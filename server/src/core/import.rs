@@ -0,0 +1,91 @@
+use super::citation;
+#[mockall_double::double]
+use super::db::Database;
+use crate::models::generic::Error;
+use crate::models::import::{ImportFormat, ImportReport, ImportRow, RowError};
+
+/// Imports a file, see `parse` for its format.
+/// Rows that fail to parse are reported along with the rows that fail to import.
+pub async fn run(
+    db: &Database,
+    data: &[u8],
+    format: ImportFormat,
+    user: Option<String>,
+    dry_run: bool,
+) -> Result<ImportReport, Error> {
+    let (rows, errors) = parse(data, format.delimiter())?;
+    let count = rows.len() + errors.len();
+    let mut report = db.import_rows(rows, user, dry_run).await?;
+    report.rows = count;
+    report.errors.extend(errors);
+    report.errors.sort_by_key(|e| e.line);
+    Ok(report)
+}
+
+/// Parses a CSV or TSV file of `topic`, `citation` and optionally `note` columns.
+///
+/// Columns are found by their header, in any order and case.
+/// Rows that cannot be parsed are returned as errors with their line, so the
+/// rest of the file can still be imported.
+pub fn parse(data: &[u8], delimiter: u8) -> Result<(Vec<ImportRow>, Vec<RowError>), Error> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(data);
+
+    let headers = reader
+        .headers()
+        .map_err(|e| Error::validation(format!("Cannot read the header: {}", e)))?
+        .clone();
+    let column = |name: &str| headers.iter().position(|h| h.eq_ignore_ascii_case(name));
+    let (topic, cite) = match (column("topic"), column("citation")) {
+        (Some(t), Some(c)) => (t, c),
+        _ => {
+            return Err(Error::validation(
+                "The header must have topic and citation columns",
+            ))
+        }
+    };
+    let note = column("note");
+
+    let mut rows = vec![];
+    let mut errors = vec![];
+    for record in reader.records() {
+        let record = match record {
+            Ok(r) => r,
+            Err(e) => {
+                errors.push(RowError {
+                    line: e.position().map_or(0, |p| p.line()),
+                    message: e.to_string(),
+                });
+                continue;
+            }
+        };
+        let line = record.position().map_or(0, |p| p.line());
+        let error = |message: String| RowError { line, message };
+        // Spreadsheets often export trailing empty rows
+        if record.iter().all(str::is_empty) {
+            continue;
+        }
+
+        let name = record.get(topic).unwrap_or_default();
+        if name.is_empty() {
+            errors.push(error("Missing topic".to_string()));
+            continue;
+        }
+        match citation::parse(record.get(cite).unwrap_or_default()) {
+            Ok(reference) => rows.push(ImportRow {
+                line,
+                topic: name.to_string(),
+                reference,
+                note: note
+                    .and_then(|n| record.get(n))
+                    .filter(|n| !n.is_empty())
+                    .map(String::from),
+            }),
+            Err(e) => errors.push(error(e.message().to_string())),
+        }
+    }
+    Ok((rows, errors))
+}
@@ -0,0 +1,396 @@
+use crate::models::generic::{Error, Format};
+use crate::models::graph::Graph;
+use crate::models::refs::{HRef, QRef, RefEnum};
+use crate::models::topics::Topic;
+use actix_web::web::Data;
+use actix_web::{HttpRequest, HttpResponse};
+use aragog::Record;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::fmt::Write;
+
+const SKOS: &str = "http://www.w3.org/2004/02/skos/core#";
+const DCTERMS: &str = "http://purl.org/dc/terms/";
+
+/// Whether the client prefers JSON-LD to the other formats of a response.
+pub fn wants_json_ld(req: &HttpRequest) -> bool {
+    let offered = [Format::Json, Format::JsonLd, Format::Csv, Format::Ndjson];
+    Format::negotiate(req, &offered) == Format::JsonLd
+}
+
+/// Publishes topics as SKOS concepts, and references under stable URIs.
+///
+/// URIs are built from the public URL of the server rather than the request,
+/// so they stay the same whichever host or proxy a client goes through.
+/// Topic URIs can be dereferenced on the API, and the topics pointing at a
+/// verse or Hadith are at its URI followed by `/topics`.
+#[derive(Debug, Clone)]
+pub struct LinkedData {
+    base: String,
+}
+
+impl LinkedData {
+    pub fn new(public_url: &str) -> Self {
+        LinkedData {
+            base: format!("{}/api/v1", public_url.trim_end_matches('/')),
+        }
+    }
+
+    /// The linked data settings of the app.
+    pub fn from_request(req: &HttpRequest) -> Result<&LinkedData, Error> {
+        req.app_data::<Data<LinkedData>>()
+            .map(|d| d.get_ref())
+            .ok_or_else(|| Error::internal("Unable to get LinkedData"))
+    }
+
+    pub fn scheme_uri(&self) -> String {
+        format!("{}/topics", self.base)
+    }
+
+    pub fn topic_uri(&self, key: &str) -> String {
+        format!("{}/topics/{}", self.base, encode(key))
+    }
+
+    pub fn verse_uri(&self, chapter: usize, verse: usize) -> String {
+        format!("{}/verses/{}/{}", self.base, chapter, verse)
+    }
+
+    pub fn hadith_uri(&self, href: &HRef) -> String {
+        format!(
+            "{}/hadith/{}/{}",
+            self.base,
+            encode(&href.collection),
+            encode(&href.number)
+        )
+    }
+
+    /// The URIs of a reference, one per verse for a range of verses.
+    /// Book references have no URI.
+    pub fn ref_uris(&self, r: &RefEnum) -> Vec<String> {
+        match r {
+            RefEnum::Q(QRef {
+                chapter,
+                init_verse,
+                final_verse,
+            }) => (*init_verse..=*final_verse)
+                .map(|v| self.verse_uri(*chapter, v))
+                .collect(),
+            RefEnum::H(h) => vec![self.hadith_uri(h)],
+            RefEnum::B(_) => vec![],
+        }
+    }
+
+    fn context() -> Value {
+        json!({ "skos": SKOS, "dcterms": DCTERMS })
+    }
+
+    /// A topic as a SKOS concept, with its broader and narrower topics and references.
+    pub fn concept(&self, topic: &Topic, children: &[String], refs: &[RefEnum]) -> Value {
+        let key = topic.key.as_deref().unwrap_or(&topic.name);
+        let mut labels = vec![json!(topic.name)];
+        let mut names: Vec<_> = topic
+            .names
+            .iter()
+            .filter(|(lang, _)| is_language_tag(lang))
+            .collect();
+        names.sort();
+        labels.extend(
+            names
+                .into_iter()
+                .map(|(lang, name)| json!({ "@value": name, "@language": lang })),
+        );
+
+        let mut concept = Map::new();
+        concept.insert("@context".into(), Self::context());
+        concept.insert("@id".into(), json!(self.topic_uri(key)));
+        concept.insert("@type".into(), json!("skos:Concept"));
+        concept.insert("skos:inScheme".into(), json!({ "@id": self.scheme_uri() }));
+        concept.insert("skos:prefLabel".into(), json!(labels));
+        if !topic.aliases.is_empty() {
+            concept.insert("skos:altLabel".into(), json!(topic.aliases));
+        }
+        if let Some(parent) = &topic.parent {
+            concept.insert(
+                "skos:broader".into(),
+                json!({ "@id": self.topic_uri(parent) }),
+            );
+        }
+        if !children.is_empty() {
+            concept.insert(
+                "skos:narrower".into(),
+                self.ids(children.iter().map(|c| self.topic_uri(c))),
+            );
+        }
+        concept.insert(
+            "dcterms:references".into(),
+            self.ids(refs.iter().flat_map(|r| self.ref_uris(r))),
+        );
+        Value::Object(concept)
+    }
+
+    /// The references of a topic, without the rest of its concept.
+    pub fn topic_refs(&self, key: &str, refs: &[RefEnum]) -> Value {
+        json!({
+            "@context": Self::context(),
+            "@id": self.topic_uri(key),
+            "dcterms:references": self.ids(refs.iter().flat_map(|r| self.ref_uris(r))),
+        })
+    }
+
+    /// A reference and the topics pointing at it.
+    pub fn referenced_by(&self, r: &RefEnum, topics: &[String]) -> Value {
+        let uris = self.ref_uris(r);
+        let id = match uris.as_slice() {
+            [uri] => json!(uri),
+            // A range of verses has no URI of its own
+            _ => Value::Null,
+        };
+        let mut node = json!({
+            "@context": Self::context(),
+            "dcterms:isReferencedBy": self.ids(topics.iter().map(|t| self.topic_uri(t))),
+        });
+        if !id.is_null() {
+            node["@id"] = id;
+        } else {
+            node["dcterms:hasPart"] = self.ids(uris.into_iter());
+        }
+        node
+    }
+
+    fn ids(&self, uris: impl Iterator<Item = String>) -> Value {
+        uris.map(|u| json!({ "@id": u })).collect()
+    }
+
+    /// A JSON-LD response.
+    pub fn respond(doc: Value) -> HttpResponse {
+        HttpResponse::Ok()
+            .content_type(Format::JsonLd.mime())
+            .json(doc)
+    }
+
+    /// The whole graph in Turtle, as one SKOS concept scheme.
+    pub fn turtle(&self, graph: &Graph) -> String {
+        let topic_prefix = format!("{}/", Topic::COLLECTION_NAME);
+        let key_of = |id: &str| id.strip_prefix(&topic_prefix).unwrap_or(id).to_string();
+        let mut narrower: HashMap<&str, Vec<String>> = HashMap::new();
+        for t in graph.topics.iter() {
+            if let Some(parent) = &t.parent {
+                narrower
+                    .entry(parent.as_str())
+                    .or_default()
+                    .push(key_of(&t.id));
+            }
+        }
+        let refs: HashMap<&str, &RefEnum> = graph
+            .refs
+            .iter()
+            .map(|r| (r.id.as_str(), &r.reference))
+            .collect();
+        let mut references: HashMap<&str, Vec<&RefEnum>> = HashMap::new();
+        for e in graph.edges.iter() {
+            if let Some(r) = refs.get(e.target.as_str()) {
+                references.entry(e.source.as_str()).or_default().push(r);
+            }
+        }
+
+        let mut out = String::new();
+        let _ = writeln!(out, "@prefix skos: <{}> .", SKOS);
+        let _ = writeln!(out, "@prefix dcterms: <{}> .", DCTERMS);
+        let _ = writeln!(out);
+        let _ = writeln!(out, "<{}> a skos:ConceptScheme .", self.scheme_uri());
+        for t in graph.topics.iter() {
+            let key = key_of(&t.id);
+            let _ = writeln!(out);
+            let _ = writeln!(out, "<{}> a skos:Concept ;", self.topic_uri(&key));
+            let _ = writeln!(out, "    skos:inScheme <{}> ;", self.scheme_uri());
+            let _ = write!(out, "    skos:prefLabel {}", literal(&t.name, None));
+            let mut names: Vec<_> = t
+                .names
+                .iter()
+                .filter(|(lang, _)| is_language_tag(lang))
+                .collect();
+            names.sort();
+            for (lang, name) in names {
+                let _ = write!(out, ", {}", literal(name, Some(lang)));
+            }
+            for alias in t.aliases.iter() {
+                let _ = write!(out, " ;\n    skos:altLabel {}", literal(alias, None));
+            }
+            if let Some(parent) = &t.parent {
+                let _ = write!(out, " ;\n    skos:broader <{}>", self.topic_uri(parent));
+            }
+            for child in narrower.get(key.as_str()).into_iter().flatten() {
+                let _ = write!(out, " ;\n    skos:narrower <{}>", self.topic_uri(child));
+            }
+            let uris = references
+                .get(t.id.as_str())
+                .into_iter()
+                .flatten()
+                .flat_map(|r| self.ref_uris(r));
+            for uri in uris {
+                let _ = write!(out, " ;\n    dcterms:references <{}>", uri);
+            }
+            let _ = writeln!(out, " .");
+        }
+        out
+    }
+}
+
+/// Percent-encodes a path segment.
+fn encode(segment: &str) -> String {
+    let mut out = String::new();
+    for b in segment.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(b as char)
+            }
+            _ => {
+                let _ = write!(out, "%{:02X}", b);
+            }
+        }
+    }
+    out
+}
+
+/// Whether a key of `names` is a well-formed BCP 47 language tag: subtags of one to
+/// eight letters or digits, the first of letters only. Others are left out of
+/// Linked Data, as they would make it invalid.
+fn is_language_tag(tag: &str) -> bool {
+    let mut subtags = tag.split('-');
+    let primary = subtags.next().unwrap_or_default();
+    (1..=8).contains(&primary.len())
+        && primary.bytes().all(|b| b.is_ascii_alphabetic())
+        && subtags
+            .all(|s| (1..=8).contains(&s.len()) && s.bytes().all(|b| b.is_ascii_alphanumeric()))
+}
+
+/// A Turtle string literal, with a language tag if any.
+fn literal(s: &str, lang: Option<&str>) -> String {
+    let escaped = s
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r");
+    match lang {
+        Some(lang) => format!("\"{}\"@{}", escaped, lang),
+        None => format!("\"{}\"", escaped),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::export::ExportRef;
+    use crate::models::graph::{GraphEdge, GraphTopic};
+
+    fn linked() -> LinkedData {
+        LinkedData::new("https://example.org/")
+    }
+
+    #[test]
+    fn test_concept() {
+        let topic = Topic {
+            aliases: vec!["Rahma".to_string()],
+            names: HashMap::from([
+                ("ar".to_string(), "رحمة".to_string()),
+                ("not a tag".to_string(), "Rahma".to_string()),
+            ]),
+            parent: Some("Divine Attributes".to_string()),
+            ..Topic::new("Mercy")
+        };
+        let refs = vec![RefEnum::Q(QRef {
+            chapter: 7,
+            init_verse: 156,
+            final_verse: 157,
+        })];
+        let doc = linked().concept(&topic, &["Forgiveness".to_string()], &refs);
+
+        assert_eq!(doc["@id"], "https://example.org/api/v1/topics/Mercy");
+        assert_eq!(doc["@type"], "skos:Concept");
+        assert_eq!(
+            doc["skos:prefLabel"],
+            json!(["Mercy", { "@value": "رحمة", "@language": "ar" }])
+        );
+        assert_eq!(
+            doc["skos:broader"]["@id"],
+            "https://example.org/api/v1/topics/Divine%20Attributes"
+        );
+        assert_eq!(
+            doc["skos:narrower"],
+            json!([{ "@id": "https://example.org/api/v1/topics/Forgiveness" }])
+        );
+        assert_eq!(
+            doc["dcterms:references"],
+            json!([
+                { "@id": "https://example.org/api/v1/verses/7/156" },
+                { "@id": "https://example.org/api/v1/verses/7/157" },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_turtle() {
+        let graph = Graph {
+            topics: vec![
+                GraphTopic {
+                    id: "Topic/Mercy".to_string(),
+                    name: "Mercy \"Rahma\"".to_string(),
+                    aliases: vec![],
+                    names: HashMap::from([
+                        ("ar".to_string(), "رحمة".to_string()),
+                        ("ar\" ; a <x".to_string(), "Rahma".to_string()),
+                    ]),
+                    parent: Some("Attributes".to_string()),
+                },
+                GraphTopic {
+                    id: "Topic/Attributes".to_string(),
+                    name: "Attributes".to_string(),
+                    aliases: vec![],
+                    names: HashMap::new(),
+                    parent: None,
+                },
+            ],
+            refs: vec![ExportRef {
+                id: "HRef/1".to_string(),
+                reference: RefEnum::H(HRef {
+                    collection: "bukhari".to_string(),
+                    number: "1".to_string(),
+                }),
+            }],
+            edges: vec![GraphEdge {
+                source: "Topic/Mercy".to_string(),
+                target: "HRef/1".to_string(),
+            }],
+        };
+        let ttl = linked().turtle(&graph);
+
+        assert!(ttl.contains(
+            "<https://example.org/api/v1/topics/Mercy> a skos:Concept ;\n    \
+             skos:inScheme <https://example.org/api/v1/topics> ;\n    \
+             skos:prefLabel \"Mercy \\\"Rahma\\\"\", \"رحمة\"@ar ;\n    \
+             skos:broader <https://example.org/api/v1/topics/Attributes> ;\n    \
+             dcterms:references <https://example.org/api/v1/hadith/bukhari/1> .\n"
+        ));
+        assert!(ttl.contains("skos:narrower <https://example.org/api/v1/topics/Mercy> .\n"));
+        assert!(!ttl.contains("Rahma\"@"));
+    }
+
+    #[test]
+    fn test_is_language_tag() {
+        for tag in ["ar", "en-GB", "zh-Hant-TW", "ur-Arab", "x-klingon"] {
+            assert!(is_language_tag(tag), "{}", tag);
+        }
+        for tag in [
+            "",
+            "a r",
+            "ar-",
+            "-ar",
+            "ar_SA",
+            "1ar",
+            "toolongtag",
+            "ar\" ; a <x",
+        ] {
+            assert!(!is_language_tag(tag), "{}", tag);
+        }
+    }
+}
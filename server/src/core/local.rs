@@ -0,0 +1,394 @@
+//! Local accounts, for installations that cannot reach a login provider.
+//! Their sessions are shaped like those of providers, so the rest of the auth works unchanged.
+
+#[mockall_double::double]
+use super::db::Database;
+use crate::models::{
+    auth::{LocalAccount, SessionRecord, Token},
+    generic::{Error, ErrorKind},
+};
+use actix_web::web;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use chrono::{DateTime, Duration, Utc};
+use rand::{distributions::Alphanumeric, Rng};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// The provider of local sessions, as listed at `/auth/providers`.
+pub const PROVIDER: &str = "local";
+
+pub const MIN_PASSWORD_LEN: usize = 12;
+
+/// How long local sessions last, as no provider renews them.
+const SESSION_HOURS: i64 = 8;
+
+/// How many logins of a username may fail before it is locked.
+pub const MAX_FAILURES: u32 = 5;
+
+/// How long a username stays locked after its last failed login, in minutes.
+/// Failures further apart than this are forgotten.
+const LOCKED_FOR: i64 = 15;
+
+/// Counts the failed logins of each username, to slow down guessing passwords.
+/// Cloned into every worker, like the event bus, so they share the counts.
+#[derive(Clone, Default)]
+pub struct LoginLimiter {
+    failures: Arc<Mutex<HashMap<String, Failures>>>,
+}
+
+struct Failures {
+    count: u32,
+    last: DateTime<Utc>,
+}
+
+impl LoginLimiter {
+    /// Fails with a 429 while the username is locked, telling in how many seconds to retry.
+    fn check(&self, username: &str) -> Result<(), Error> {
+        let failures = self.failures.lock().unwrap();
+        let until = match failures.get(username) {
+            Some(f) if f.count >= MAX_FAILURES => f.last + Duration::minutes(LOCKED_FOR),
+            _ => return Ok(()),
+        };
+        let retry_after = (until - Utc::now()).num_seconds();
+        if retry_after <= 0 {
+            return Ok(());
+        }
+        Err(
+            Error::too_many_requests("Too many failed logins, try again later")
+                .with_details(json!({ "retry_after": retry_after })),
+        )
+    }
+
+    fn failed(&self, username: &str) {
+        let now = Utc::now();
+        let mut failures = self.failures.lock().unwrap();
+        // So guessing usernames does not grow the counts for ever
+        failures.retain(|_, f| f.last + Duration::minutes(LOCKED_FOR) > now);
+        let f = failures.entry(username.to_string()).or_insert(Failures {
+            count: 0,
+            last: now,
+        });
+        f.count += 1;
+        f.last = now;
+    }
+
+    fn succeeded(&self, username: &str) {
+        self.failures.lock().unwrap().remove(username);
+    }
+}
+
+/// The Argon2 hash of a password, with a random salt, as a PHC string.
+pub fn hash_password(password: &str) -> Result<String, Error> {
+    let salt = SaltString::b64_encode(&rand::random::<[u8; 16]>()).map_err(Error::internal)?;
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|h| h.to_string())
+        .map_err(Error::internal)
+}
+
+/// Whether the password is the one hashed, in constant time.
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|h| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &h)
+            .is_ok()
+    })
+}
+
+/// Hash the password on the blocking pool, as Argon2 is slow enough to stall a worker.
+async fn hash_blocking(password: &str) -> Result<String, Error> {
+    let password = password.to_string();
+    web::block(move || hash_password(&password))
+        .await
+        .map_err(Error::internal)?
+}
+
+/// Verify the password on the blocking pool, as Argon2 is slow enough to stall a worker.
+async fn verify_blocking(password: &str, hash: &str) -> Result<bool, Error> {
+    let (password, hash) = (password.to_string(), hash.to_string());
+    web::block(move || verify_password(&password, &hash))
+        .await
+        .map_err(Error::internal)
+}
+
+fn validate_password(password: &str) -> Result<(), Error> {
+    if password.chars().count() < MIN_PASSWORD_LEN {
+        return Err(Error::validation(format!(
+            "Passwords must be at least {} characters long",
+            MIN_PASSWORD_LEN
+        )));
+    }
+    Ok(())
+}
+
+/// Create a local account.
+pub async fn create_account(
+    db: &Database,
+    username: &str,
+    name: &str,
+    email: &str,
+    password: &str,
+) -> Result<LocalAccount, Error> {
+    let username = username.trim().to_lowercase();
+    if username.is_empty() || username.contains(char::is_whitespace) {
+        return Err(Error::validation(
+            "Usernames cannot be empty or have spaces",
+        ));
+    }
+    if !email.contains('@') {
+        return Err(Error::validation(format!("Invalid email {}", email)));
+    }
+    validate_password(password)?;
+    let account = LocalAccount {
+        username,
+        name: name.to_string(),
+        email: email.trim().to_lowercase(),
+        hash: hash_blocking(password).await?,
+        created_at: Utc::now(),
+        password_changed_at: None,
+    };
+    db.add_account(account.clone()).await?;
+    Ok(account)
+}
+
+/// Log in with a local account, returning the key of the new session.
+/// After `MAX_FAILURES` failed logins in a row, the username is locked for a while.
+pub async fn login(
+    db: &Database,
+    limiter: &LoginLimiter,
+    username: &str,
+    password: &str,
+) -> Result<String, Error> {
+    // The same username for the limiter as for the account, so no spelling of it escapes the lock
+    let username = username.trim().to_lowercase();
+    limiter.check(&username)?;
+    let account = match db.get_account(&username).await {
+        Ok(account) => Some(account),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    let verified = match &account {
+        Some(account) => verify_blocking(password, &account.hash).await?,
+        None => {
+            // Hash anyway, so the time taken does not tell which usernames exist
+            let _ = hash_blocking(password).await;
+            false
+        }
+    };
+    let account = match account {
+        Some(account) if verified => account,
+        _ => {
+            limiter.failed(&username);
+            return Err(Error::unauthorized("Wrong username or password"));
+        }
+    };
+    limiter.succeeded(&username);
+
+    let session: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect();
+    let now = Utc::now();
+    db.add_session(
+        session.clone(),
+        SessionRecord {
+            nonce: String::new(),
+            token: Some(Token {
+                token: None,
+                name: account.name,
+                preferred_username: account.email,
+                exp: now + Duration::hours(SESSION_HOURS),
+            }),
+            provider: Some(PROVIDER.to_string()),
+            verifier: None,
+            access_token: None,
+            refresh_token: None,
+            created_at: Some(now),
+            groups: vec![],
+            referrer: None,
+        },
+    )
+    .await?;
+    Ok(session)
+}
+
+/// Change the password of the local account with this email.
+/// Every session and API token of the user is revoked, so a stolen one does not outlive the old password.
+pub async fn change_password(
+    db: &Database,
+    email: &str,
+    current: &str,
+    new: &str,
+) -> Result<(), Error> {
+    validate_password(new)?;
+    let account = db
+        .get_account_by_email(email)
+        .await
+        .map_err(|e| match e.kind() {
+            ErrorKind::NotFound => Error::validation("The user has no local account"),
+            _ => e,
+        })?;
+    if !verify_blocking(current, &account.hash).await? {
+        return Err(Error::forbidden("Wrong password"));
+    }
+    db.set_password(&account.username, &hash_blocking(new).await?)
+        .await?;
+    db.delete_user_sessions(email).await?;
+    db.delete_user_api_tokens(email).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    const PASSWORD: &str = "correct horse battery";
+
+    fn account() -> LocalAccount {
+        LocalAccount {
+            username: "admin".to_string(),
+            name: "Admin".to_string(),
+            email: "admin@example.org".to_string(),
+            hash: hash_password(PASSWORD).unwrap(),
+            created_at: Utc::now(),
+            password_changed_at: None,
+        }
+    }
+
+    #[test]
+    fn test_hash_password() {
+        let hash = hash_password(PASSWORD).unwrap();
+        assert!(hash.starts_with("$argon2"));
+        assert_ne!(hash, hash_password(PASSWORD).unwrap(), "Salted");
+        assert!(verify_password(PASSWORD, &hash));
+        assert!(!verify_password("wrong horse battery", &hash));
+        assert!(!verify_password(PASSWORD, "not a hash"));
+    }
+
+    #[actix_web::test]
+    async fn test_create_account() {
+        let mut db = Database::default();
+        db.expect_add_account()
+            .withf(|a| a.username == "admin" && a.email == "admin@example.org")
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let account = create_account(&db, " Admin", "Admin", "Admin@example.org", PASSWORD)
+            .await
+            .unwrap();
+        assert!(verify_password(PASSWORD, &account.hash));
+
+        let err = create_account(&db, "admin", "Admin", "admin@example.org", "short")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Validation);
+    }
+
+    #[actix_web::test]
+    async fn test_login() {
+        let mut db = Database::default();
+        let account = account();
+        db.expect_get_account()
+            .returning(move |username| match username {
+                "admin" => Ok(account.clone()),
+                _ => Err(Error::not_found("Unknown local account")),
+            });
+        let started = Arc::new(Mutex::new(None));
+        let saved = started.clone();
+        db.expect_add_session().times(1).returning(move |_, s| {
+            *saved.lock().unwrap() = Some(s);
+            Ok(())
+        });
+
+        let limiter = LoginLimiter::default();
+        // Usernames are trimmed and lowercased, as when the account was added
+        let session = login(&db, &limiter, " Admin ", PASSWORD).await.unwrap();
+        assert_eq!(session.len(), 32);
+        let s = started.lock().unwrap().take().unwrap();
+        assert_eq!(s.provider.as_deref(), Some(PROVIDER));
+        let token = s.token.unwrap();
+        assert_eq!(token.preferred_username, "admin@example.org");
+        assert!(token.exp > Utc::now());
+
+        for (username, password) in [("admin", "wrong horse battery"), ("nobody", PASSWORD)] {
+            let err = login(&db, &limiter, username, password).await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Unauthorized);
+            assert_eq!(err.message(), "Wrong username or password");
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_login_limited() {
+        let mut db = Database::default();
+        let account = account();
+        db.expect_get_account()
+            .returning(move |_| Ok(account.clone()));
+        db.expect_add_session().times(1).returning(|_, _| Ok(()));
+
+        let limiter = LoginLimiter::default();
+        for _ in 0..MAX_FAILURES {
+            let err = login(&db, &limiter, "admin", "wrong horse battery")
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Unauthorized);
+        }
+        // Even with the right password, and whatever the case of the username
+        let err = login(&db, &limiter, "Admin", PASSWORD).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TooManyRequests);
+        assert!(err.details().unwrap()["retry_after"].as_i64().unwrap() > 0);
+
+        // Other usernames are not locked, and the lock ends
+        limiter.check("other").unwrap();
+        limiter
+            .failures
+            .lock()
+            .unwrap()
+            .get_mut("admin")
+            .unwrap()
+            .last = Utc::now() - Duration::minutes(LOCKED_FOR);
+        login(&db, &limiter, "admin", PASSWORD).await.unwrap();
+        assert!(
+            limiter.failures.lock().unwrap().is_empty(),
+            "Cleared on success"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_change_password() {
+        let mut db = Database::default();
+        let account = account();
+        db.expect_get_account_by_email()
+            .returning(move |_| Ok(account.clone()));
+        db.expect_set_password()
+            .withf(|username, hash| {
+                username == "admin" && verify_password("a new passphrase", hash)
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        db.expect_delete_user_sessions()
+            .withf(|email| email == "admin@example.org")
+            .times(1)
+            .returning(|_| Ok(2));
+        db.expect_delete_user_api_tokens()
+            .withf(|email| email == "admin@example.org")
+            .times(1)
+            .returning(|_| Ok(1));
+
+        let err = change_password(
+            &db,
+            "admin@example.org",
+            "wrong horse battery",
+            "a new passphrase",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Forbidden);
+        change_password(&db, "admin@example.org", PASSWORD, "a new passphrase")
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,261 @@
+//! A stand-in OpenID Connect provider for tests. It serves its discovery
+//! document and signing keys on localhost, signs ID tokens with a key generated for the tests,
+//! and exchanges the codes and refresh tokens the tests issue.
+
+use super::oidc::ProviderConfig;
+use chrono::Utc;
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use once_cell::sync::Lazy;
+use rsa::{pkcs1::EncodeRsaPrivateKey, pkcs1::LineEnding, PublicKeyParts, RsaPrivateKey};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+pub const KID: &str = "mock-key";
+pub const CLIENT_ID: &str = "mock-client";
+
+/// The signing key of the provider, generated for each run of the tests.
+pub static KEY: Lazy<TestKey> = Lazy::new(TestKey::generate);
+/// Another key, which the provider does not know.
+pub static OTHER_KEY: Lazy<TestKey> = Lazy::new(TestKey::generate);
+
+/// An RSA key pair, as the tests sign with it and the provider publishes it.
+pub struct TestKey {
+    pub encoding: EncodingKey,
+    /// The base64url modulus of the public key, as in a JWK.
+    pub modulus: String,
+}
+
+impl TestKey {
+    fn generate() -> TestKey {
+        let key = RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+        let pem = key.to_pkcs1_pem(LineEnding::LF).unwrap();
+        TestKey {
+            encoding: EncodingKey::from_rsa_pem(pem.as_bytes()).unwrap(),
+            modulus: base64::encode_config(key.n().to_bytes_be(), base64::URL_SAFE_NO_PAD),
+        }
+    }
+}
+
+const EXPONENT: &str = "AQAB";
+
+/// What the provider shares with the tests.
+#[derive(Default)]
+struct Grants {
+    /// The PKCE verifier and the nonce of each code.
+    codes: HashMap<String, (String, String)>,
+    refresh_tokens: Vec<String>,
+    issued: usize,
+    /// The id of the signing key, after it was rotated.
+    kid: Option<String>,
+    jwks_fetches: usize,
+}
+
+pub struct MockIdp {
+    pub issuer: String,
+    grants: Arc<Mutex<Grants>>,
+}
+
+impl MockIdp {
+    /// Start serving on a free port, until the tests end.
+    pub fn start() -> MockIdp {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let grants = Arc::new(Mutex::new(Grants::default()));
+        let idp = MockIdp {
+            issuer: issuer.clone(),
+            grants: grants.clone(),
+        };
+        thread::spawn(move || {
+            let idp = MockIdp { issuer, grants };
+            for stream in listener.incoming().flatten() {
+                idp.respond(stream);
+            }
+        });
+        idp
+    }
+
+    /// Issue a code, as the provider does when a user logs in.
+    /// It is exchanged once, with the verifier, for an ID token with the nonce.
+    pub fn issue_code(&self, code: &str, verifier: &str, nonce: &str) {
+        let mut grants = self.grants.lock().unwrap();
+        grants
+            .codes
+            .insert(code.to_string(), (verifier.to_string(), nonce.to_string()));
+    }
+
+    /// Serve the signing key under a new id, and sign with it from now on.
+    pub fn rotate_key(&self, kid: &str) {
+        self.grants.lock().unwrap().kid = Some(kid.to_string());
+    }
+
+    /// How many times the keys were fetched.
+    pub fn jwks_fetches(&self) -> usize {
+        self.grants.lock().unwrap().jwks_fetches
+    }
+
+    fn kid(&self) -> String {
+        let grants = self.grants.lock().unwrap();
+        grants.kid.clone().unwrap_or_else(|| KID.to_string())
+    }
+
+    /// Issue a refresh token, exchanged once for new tokens.
+    pub fn issue_refresh_token(&self, token: &str) {
+        let mut grants = self.grants.lock().unwrap();
+        grants.refresh_tokens.push(token.to_string());
+    }
+
+    pub fn config(&self, name: &str) -> ProviderConfig {
+        ProviderConfig {
+            name: name.to_string(),
+            issuer: self.issuer.clone(),
+            client_id: CLIENT_ID.to_string(),
+            client_secret: Some("mock-secret".to_string()),
+            scopes: ProviderConfig::default_scopes(),
+            groups_claim: ProviderConfig::default_groups_claim(),
+            roles: HashMap::new(),
+        }
+    }
+
+    /// The claims of a valid ID token for the nonce.
+    pub fn claims(&self, nonce: &str) -> Value {
+        let now = Utc::now().timestamp();
+        json!({
+            "iss": self.issuer,
+            "aud": CLIENT_ID,
+            "iat": now,
+            "exp": now + 3600,
+            "nonce": nonce,
+            "name": "Mock User",
+            "preferred_username": "user@example.org",
+            "groups": ["editors"],
+        })
+    }
+
+    /// Sign claims as the provider does.
+    pub fn sign(&self, claims: &Value) -> String {
+        let header = Header {
+            kid: Some(self.kid()),
+            ..Header::new(Algorithm::RS256)
+        };
+        self.sign_with(header, claims)
+    }
+
+    /// Sign claims with the key of the provider, under any header.
+    pub fn sign_with(&self, header: Header, claims: &Value) -> String {
+        encode(&header, claims, &KEY.encoding).unwrap()
+    }
+}
+
+impl MockIdp {
+    /// Answer the token endpoint: new tokens for a known grant, rotating the refresh token.
+    fn token(&self, form: &str) -> (&'static str, Value) {
+        let form: HashMap<String, String> = url::form_urlencoded::parse(form.as_bytes())
+            .into_owned()
+            .collect();
+        let get = |k: &str| form.get(k).map(String::as_str).unwrap_or_default();
+        if get("client_id") != CLIENT_ID || get("client_secret") != "mock-secret" {
+            return ("401 Unauthorized", json!({"error": "invalid_client"}));
+        }
+        let mut grants = self.grants.lock().unwrap();
+        let claims = match get("grant_type") {
+            "authorization_code" => match grants.codes.remove(get("code")) {
+                Some((verifier, nonce)) if verifier == get("code_verifier") => self.claims(&nonce),
+                _ => return ("400 Bad Request", json!({"error": "invalid_grant"})),
+            },
+            "refresh_token" => {
+                let len = grants.refresh_tokens.len();
+                let token = get("refresh_token");
+                grants.refresh_tokens.retain(|t| t != token);
+                if grants.refresh_tokens.len() == len {
+                    return ("400 Bad Request", json!({"error": "invalid_grant"}));
+                }
+                let mut claims = self.claims("");
+                claims.as_object_mut().unwrap().remove("nonce");
+                claims
+            }
+            _ => {
+                return (
+                    "400 Bad Request",
+                    json!({"error": "unsupported_grant_type"}),
+                )
+            }
+        };
+        grants.issued += 1;
+        let refresh_token = format!("mock-refresh-{}", grants.issued);
+        grants.refresh_tokens.push(refresh_token.clone());
+        let access_token = format!("mock-access-{}", grants.issued);
+        drop(grants);
+        let tokens = json!({
+            "id_token": self.sign(&claims),
+            "access_token": access_token,
+            "refresh_token": refresh_token,
+            "token_type": "Bearer",
+            "expires_in": 3600,
+        });
+        ("200 OK", tokens)
+    }
+
+    fn respond(&self, mut stream: TcpStream) {
+        let issuer = &self.issuer;
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut request = String::new();
+        if reader.read_line(&mut request).is_err() {
+            return;
+        }
+        let mut len = 0;
+        loop {
+            let mut line = String::new();
+            match reader.read_line(&mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) if line == "\r\n" => break,
+                Ok(_) => {}
+            }
+            if let Some(v) = line.to_lowercase().strip_prefix("content-length:") {
+                len = v.trim().parse().unwrap_or(0);
+            }
+        }
+        let mut form = vec![0; len];
+        if reader.read_exact(&mut form).is_err() {
+            return;
+        }
+        let path = request.split_whitespace().nth(1).unwrap_or("/");
+        // Discovery is served under any path, always naming the real issuer
+        let (status, body) = if path.ends_with("/.well-known/openid-configuration") {
+            let doc = json!({
+                "issuer": issuer,
+                "authorization_endpoint": format!("{}/authorize", issuer),
+                "jwks_uri": format!("{}/jwks", issuer),
+                "token_endpoint": format!("{}/token", issuer),
+                "end_session_endpoint": format!("{}/logout", issuer),
+            });
+            ("200 OK", doc)
+        } else if path == "/jwks" {
+            self.grants.lock().unwrap().jwks_fetches += 1;
+            let keys = json!({ "keys": [{
+                "kty": "RSA",
+                "kid": self.kid(),
+                "use": "sig",
+                "alg": "RS256",
+                "n": KEY.modulus,
+                "e": EXPONENT,
+            }]});
+            ("200 OK", keys)
+        } else if path == "/token" {
+            self.token(&String::from_utf8_lossy(&form))
+        } else {
+            ("404 Not Found", json!({}))
+        };
+        let body = body.to_string();
+        let _ = write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nCache-Control: max-age=300\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    }
+}
@@ -1,2 +1,14 @@
 pub mod auth;
+pub mod citation;
+pub mod cookie;
 pub mod db;
+pub mod events;
+pub mod export;
+pub mod graph;
+pub mod import;
+pub mod linked;
+pub mod local;
+#[cfg(test)]
+pub mod mock_idp;
+pub mod oidc;
+pub mod webhooks;
@@ -0,0 +1,472 @@
+use crate::models::{
+    auth::{Role, TokenError},
+    generic::Error,
+};
+use actix_web::rt;
+use jsonwebtoken::jwk::{Jwk, JwkSet};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// An OpenID Connect provider users can log in with, as configured.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProviderConfig {
+    /// Selects the provider at `/auth/login?provider=`.
+    pub name: String,
+    /// The issuer of the ID tokens, whose `/.well-known/openid-configuration`
+    /// describes the provider. For Azure AD, the issuer of a tenant,
+    /// e.g. `https://login.microsoftonline.com/<tenant id>/v2.0`.
+    pub issuer: String,
+    /// The client registered with the provider, which ID tokens must be issued to.
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    #[serde(default = "ProviderConfig::default_scopes")]
+    pub scopes: String,
+    /// The ID token claim listing the groups of the user.
+    #[serde(default = "ProviderConfig::default_groups_claim")]
+    pub groups_claim: String,
+    /// The role of the members of each group. Users in several get the highest.
+    #[serde(default)]
+    pub roles: HashMap<String, Role>,
+}
+
+impl ProviderConfig {
+    pub fn default_scopes() -> String {
+        "openid profile email".to_string()
+    }
+
+    pub fn default_groups_claim() -> String {
+        "groups".to_string()
+    }
+
+    /// The highest role mapped from the groups, if any is.
+    pub fn role_of(&self, groups: &[String]) -> Option<Role> {
+        groups
+            .iter()
+            .filter_map(|g| self.roles.get(g))
+            .max()
+            .copied()
+    }
+}
+
+/// What a provider publishes at `/.well-known/openid-configuration`, as far as we use it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Discovery {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub jwks_uri: String,
+    pub token_endpoint: Option<String>,
+    pub end_session_endpoint: Option<String>,
+}
+
+/// What a token endpoint answers with, for a code or a refresh token.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct TokenResponse {
+    /// Always there for a code, optional for a refresh.
+    pub id_token: Option<String>,
+    pub access_token: Option<String>,
+    /// A new refresh token, if the provider rotates them.
+    pub refresh_token: Option<String>,
+    /// Seconds until the access token expires.
+    pub expires_in: Option<i64>,
+}
+
+/// A random PKCE code verifier.
+pub fn pkce_verifier() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(64)
+        .map(char::from)
+        .collect()
+}
+
+/// The S256 PKCE challenge of a verifier.
+pub fn pkce_challenge(verifier: &str) -> String {
+    base64::encode_config(Sha256::digest(verifier.as_bytes()), base64::URL_SAFE_NO_PAD)
+}
+
+/// The signing keys of a provider, kept as long as its `Cache-Control` allows.
+/// An unknown `kid` fetches them again, as the provider may have rotated its keys,
+/// but no more often than `min_refetch`, so forged tokens cannot flood the provider.
+#[derive(Debug)]
+pub struct JwksCache {
+    uri: String,
+    /// The shortest wait between two fetches.
+    pub min_refetch: Duration,
+    /// Held while fetching, so concurrent logins wait for the same fetch,
+    /// which times out if the provider hangs.
+    keys: Mutex<CachedKeys>,
+}
+
+#[derive(Debug, Default)]
+struct CachedKeys {
+    keys: Option<JwkSet>,
+    fetched: Option<Instant>,
+    expires: Option<Instant>,
+}
+
+impl JwksCache {
+    /// Used without a `max-age`.
+    pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(3600);
+    /// Keys are fetched again at least once a day, whatever the provider says.
+    pub const MAX_AGE: Duration = Duration::from_secs(24 * 3600);
+
+    pub fn new(uri: String) -> Self {
+        JwksCache {
+            uri,
+            min_refetch: Duration::from_secs(60),
+            keys: Mutex::new(CachedKeys::default()),
+        }
+    }
+
+    /// The key with this id, fetching the keys if they expired or do not have it.
+    pub async fn key(&self, kid: &str) -> Result<Jwk, Error> {
+        let mut cached = self.keys.lock().await;
+        let now = Instant::now();
+        if cached.expires.is_none_or(|e| e <= now) && self.may_fetch(&cached, now) {
+            self.fetch(&mut cached, now).await?;
+        }
+        if let Some(key) = cached.find(kid) {
+            return Ok(key);
+        }
+        if self.may_fetch(&cached, now) {
+            log::info!("Unknown signing key {}, fetching {} again", kid, self.uri);
+            self.fetch(&mut cached, now).await?;
+        }
+        cached
+            .find(kid)
+            .ok_or_else(|| TokenError::UnknownKid.into())
+    }
+
+    fn may_fetch(&self, cached: &CachedKeys, now: Instant) -> bool {
+        cached
+            .fetched
+            .is_none_or(|f| now.duration_since(f) >= self.min_refetch)
+    }
+
+    /// Fetch the keys. If that fails, the old ones are kept for the next try.
+    async fn fetch(&self, cached: &mut CachedKeys, now: Instant) -> Result<(), Error> {
+        cached.fetched = Some(now);
+        let uri = self.uri.clone();
+        let fetched = rt::task::spawn_blocking(move || {
+            let resp = agent().get(&uri).call().map_err(Error::upstream)?;
+            let max_age = resp.header("Cache-Control").and_then(max_age);
+            let keys = resp.into_json::<JwkSet>().map_err(Error::upstream)?;
+            Ok::<_, Error>((keys, max_age))
+        })
+        .await
+        .map_err(Error::internal)?;
+        match fetched {
+            Ok((keys, max_age)) => {
+                let max_age = max_age.unwrap_or(Self::DEFAULT_MAX_AGE).min(Self::MAX_AGE);
+                cached.keys = Some(keys);
+                cached.expires = Some(now + max_age);
+                Ok(())
+            }
+            Err(_) if cached.keys.is_some() => {
+                log::warn!("Unable to fetch {}, using the cached keys", self.uri);
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl CachedKeys {
+    fn find(&self, kid: &str) -> Option<Jwk> {
+        self.keys
+            .as_ref()?
+            .keys
+            .iter()
+            .find(|k| k.common.key_id.as_deref() == Some(kid))
+            .cloned()
+    }
+}
+
+/// How long a response may be cached, from its `Cache-Control` header.
+/// `no-cache` and `no-store` allow no caching, beyond the rate limit of fetches.
+pub fn max_age(cache_control: &str) -> Option<Duration> {
+    let mut max_age = None;
+    for directive in cache_control.split(',').map(str::trim) {
+        let directive = directive.to_ascii_lowercase();
+        if directive == "no-cache" || directive == "no-store" {
+            return Some(Duration::ZERO);
+        }
+        if let Some(secs) = directive.strip_prefix("max-age=") {
+            max_age = secs.trim_matches('"').parse().ok().map(Duration::from_secs);
+        }
+    }
+    max_age
+}
+
+/// A configured provider, with its discovered endpoints.
+#[derive(Debug, Clone)]
+pub struct Provider {
+    pub config: ProviderConfig,
+    pub discovery: Discovery,
+    /// Shared by the clones of the provider.
+    pub keys: Arc<JwksCache>,
+}
+
+impl Provider {
+    pub fn new(config: ProviderConfig, discovery: Discovery) -> Self {
+        let keys = Arc::new(JwksCache::new(discovery.jwks_uri.clone()));
+        Provider {
+            config,
+            discovery,
+            keys,
+        }
+    }
+
+    /// Fetch the discovery document of the provider.
+    /// Fails if it names another issuer, as the tokens would not validate.
+    pub async fn discover(config: ProviderConfig) -> Result<Provider, Error> {
+        let issuer = config.issuer.trim_end_matches('/');
+        let url = format!("{}/.well-known/openid-configuration", issuer);
+        let discovery: Discovery = fetch_json(url).await?;
+        if discovery.issuer.trim_end_matches('/') != issuer {
+            return Err(Error::upstream(format!(
+                "Provider {} is configured with issuer {} but discovered {}",
+                config.name, config.issuer, discovery.issuer
+            )));
+        }
+        Ok(Provider::new(config, discovery))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    /// The key the provider signs ID tokens with, by its id.
+    pub async fn key(&self, kid: &str) -> Result<Jwk, Error> {
+        self.keys.key(kid).await
+    }
+
+    /// Exchange an authorization code for tokens, proving the login started here with the verifier.
+    pub async fn exchange(
+        &self,
+        code: &str,
+        verifier: &str,
+        redirect_uri: &str,
+    ) -> Result<TokenResponse, Error> {
+        self.token(vec![
+            ("grant_type", "authorization_code".to_string()),
+            ("code", code.to_string()),
+            ("code_verifier", verifier.to_string()),
+            ("redirect_uri", redirect_uri.to_string()),
+        ])
+        .await
+    }
+
+    /// Get new tokens with a refresh token.
+    pub async fn refresh(&self, refresh_token: &str) -> Result<TokenResponse, Error> {
+        self.token(vec![
+            ("grant_type", "refresh_token".to_string()),
+            ("refresh_token", refresh_token.to_string()),
+            ("scope", self.config.scopes.clone()),
+        ])
+        .await
+    }
+
+    /// Post a grant to the token endpoint, authenticated as the client.
+    /// A refused grant is unauthorized, rather than a failure of the provider.
+    async fn token(&self, mut form: Vec<(&'static str, String)>) -> Result<TokenResponse, Error> {
+        let url = self.discovery.token_endpoint.clone().ok_or_else(|| {
+            Error::upstream(format!("Provider {} has no token endpoint", self.name()))
+        })?;
+        form.push(("client_id", self.config.client_id.clone()));
+        if let Some(secret) = &self.config.client_secret {
+            form.push(("client_secret", secret.clone()));
+        }
+        rt::task::spawn_blocking(move || {
+            let form: Vec<(&str, &str)> = form.iter().map(|(k, v)| (*k, v.as_str())).collect();
+            match agent().post(&url).send_form(&form) {
+                Ok(resp) => resp.into_json::<TokenResponse>().map_err(Error::upstream),
+                Err(ureq::Error::Status(400 | 401, resp)) => {
+                    log::debug!("Grant refused: {:?}", resp.into_string());
+                    Err(Error::unauthorized("The login provider refused the grant"))
+                }
+                Err(e) => Err(Error::upstream(e)),
+            }
+        })
+        .await
+        .map_err(Error::internal)?
+    }
+}
+
+/// Read provider configurations from a JSON file holding a list of them.
+pub fn read_providers(path: &str) -> std::io::Result<Vec<ProviderConfig>> {
+    let data = std::fs::read(path)?;
+    serde_json::from_slice(&data)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// How long a provider has to accept a connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a provider may stay silent while answering. Logins and token validations
+/// wait for the signing keys, so a provider hanging must not hold them for long.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The client for requests to providers.
+fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout_connect(CONNECT_TIMEOUT)
+        .timeout_read(READ_TIMEOUT)
+        .build()
+}
+
+/// Get a JSON document, with the blocking client off the async workers.
+async fn fetch_json<T: DeserializeOwned + Send + 'static>(url: String) -> Result<T, Error> {
+    rt::task::spawn_blocking(move || {
+        agent()
+            .get(&url)
+            .call()
+            .map_err(Error::upstream)?
+            .into_json::<T>()
+            .map_err(Error::upstream)
+    })
+    .await
+    .map_err(Error::internal)?
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::mock_idp::{MockIdp, KID};
+    use crate::models::generic::ErrorKind;
+
+    #[actix_web::test]
+    async fn test_discover() {
+        let idp = MockIdp::start();
+        let provider = Provider::discover(idp.config("mock")).await.unwrap();
+
+        assert_eq!(provider.name(), "mock");
+        assert_eq!(provider.discovery.issuer, idp.issuer);
+        assert_eq!(provider.discovery.jwks_uri, format!("{}/jwks", idp.issuer));
+        let key = provider.key(KID).await.unwrap();
+        assert_eq!(key.common.key_id.as_deref(), Some(KID));
+    }
+
+    #[test]
+    fn test_max_age() {
+        assert_eq!(
+            max_age("public, max-age=300"),
+            Some(Duration::from_secs(300))
+        );
+        assert_eq!(max_age("max-age=300, no-cache"), Some(Duration::ZERO));
+        assert_eq!(max_age("private"), None);
+        assert_eq!(max_age("max-age=soon"), None);
+    }
+
+    #[test]
+    fn test_group_roles() {
+        let config: ProviderConfig = serde_json::from_value(serde_json::json!({
+            "name": "keycloak",
+            "issuer": "https://keycloak.example.org",
+            "client_id": "id",
+            "client_secret": null,
+            "roles": { "/curators": "contributor", "/editors": "editor" }
+        }))
+        .unwrap();
+        assert_eq!(config.groups_claim, "groups");
+
+        let groups = |gs: &[&str]| gs.iter().map(|g| g.to_string()).collect::<Vec<_>>();
+        assert_eq!(config.role_of(&groups(&["/staff"])), None);
+        assert_eq!(
+            config.role_of(&groups(&["/editors", "/curators"])),
+            Some(Role::Editor)
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_keys_cached() {
+        let idp = MockIdp::start();
+        let provider = Provider::discover(idp.config("mock")).await.unwrap();
+        provider.key(KID).await.unwrap();
+        provider.key(KID).await.unwrap();
+
+        assert_eq!(idp.jwks_fetches(), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_unknown_kid_refetch_limited() {
+        let idp = MockIdp::start();
+        let provider = Provider::discover(idp.config("mock")).await.unwrap();
+        provider.key(KID).await.unwrap();
+
+        for _ in 0..3 {
+            let err = provider.key("forged").await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Unauthorized);
+            assert_eq!(err.details().unwrap()["reason"], "unknown_kid");
+        }
+        assert_eq!(idp.jwks_fetches(), 1, "Fetched again within the minute");
+    }
+
+    #[actix_web::test]
+    async fn test_key_rotation() {
+        let idp = MockIdp::start();
+        let mut provider = Provider::discover(idp.config("mock")).await.unwrap();
+        provider.keys = Arc::new(JwksCache {
+            min_refetch: Duration::ZERO,
+            ..JwksCache::new(provider.discovery.jwks_uri.clone())
+        });
+        provider.key(KID).await.unwrap();
+
+        idp.rotate_key("rotated");
+        let key = provider.key("rotated").await.unwrap();
+        assert_eq!(key.common.key_id.as_deref(), Some("rotated"));
+        assert_eq!(idp.jwks_fetches(), 2);
+    }
+
+    #[test]
+    fn test_pkce_challenge() {
+        // From RFC 7636, appendix B
+        assert_eq!(
+            pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+        assert_eq!(pkce_verifier().len(), 64);
+    }
+
+    #[actix_web::test]
+    async fn test_exchange() {
+        let idp = MockIdp::start();
+        let provider = Provider::discover(idp.config("mock")).await.unwrap();
+        idp.issue_code("code", "verifier", "nonce");
+
+        let err = provider
+            .exchange("code", "another verifier", "http://localhost")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unauthorized);
+
+        // A failed exchange uses up the code too
+        idp.issue_code("code", "verifier", "nonce");
+        let tokens = provider
+            .exchange("code", "verifier", "http://localhost")
+            .await
+            .unwrap();
+        assert!(tokens.id_token.is_some());
+        let refreshed = provider
+            .refresh(&tokens.refresh_token.unwrap())
+            .await
+            .unwrap();
+        assert!(refreshed.access_token.is_some());
+    }
+
+    #[actix_web::test]
+    async fn test_discover_other_issuer() {
+        let idp = MockIdp::start();
+        let mut config = idp.config("mock");
+        // Like configuring the common Azure AD endpoint instead of a tenant
+        config.issuer = format!("{}/common", idp.issuer);
+        let err = Provider::discover(config).await.unwrap_err();
+
+        assert_eq!(err.kind(), ErrorKind::Upstream);
+    }
+}
@@ -0,0 +1,416 @@
+#[mockall_double::double]
+use super::db::Database;
+use super::events::EventBus;
+use crate::models::events::{Event, EventKind};
+use crate::models::generic::Error;
+use crate::models::webhooks::{Attempt, DeliveryStatus, Webhook, WebhookDelivery};
+use actix_web::rt::{self, time::sleep};
+use actix_web::web::Data;
+use chrono::Utc;
+use futures::StreamExt;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// The header holding the signature of a delivery.
+pub const SIGNATURE: &str = "X-Webhook-Signature";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// How deliveries are retried.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// The number of tries before a delivery fails.
+    pub attempts: u32,
+    /// The wait before the first retry, doubled for each one after.
+    pub base_delay: Duration,
+    /// How long a receiver has to answer.
+    pub timeout: Duration,
+}
+
+impl Default for RetryPolicy {
+    /// Six tries over about five minutes.
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 6,
+            base_delay: Duration::from_secs(10),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A single try, for testing a webhook.
+    pub fn once() -> Self {
+        RetryPolicy {
+            attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// The wait after the given failed attempt, counting from 1.
+    fn delay(&self, attempt: u32) -> Duration {
+        self.base_delay * 2u32.saturating_pow(attempt - 1)
+    }
+}
+
+/// The signature of a body, as `sha256=` and the hex HMAC-SHA256 of the body.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(body);
+    let mut out = String::from("sha256=");
+    for b in mac.finalize().into_bytes() {
+        let _ = write!(out, "{:02x}", b);
+    }
+    out
+}
+
+/// Whether the signature is that of the body, in constant time.
+pub fn verify(secret: &str, body: &[u8], signature: &str) -> bool {
+    let hex = match signature.strip_prefix("sha256=") {
+        Some(hex) if hex.len() % 2 == 0 && hex.is_ascii() => hex,
+        _ => return false,
+    };
+    let bytes: Option<Vec<u8>> = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect();
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(body);
+    bytes.is_some_and(|b| mac.verify_slice(&b).is_ok())
+}
+
+/// Sends the events published on the bus to the webhooks wanting them, until the server stops.
+/// The deliveries left pending when it last stopped are resumed first.
+pub async fn dispatch(db: Data<Database>, bus: EventBus, policy: RetryPolicy) {
+    // Listening first, so no event is missed while resuming
+    let mut events = Box::pin(bus.listen(None, None));
+    resume(&db, &policy).await;
+    let mut last_id = None;
+    loop {
+        while let Some(event) = events.next().await {
+            last_id = Some(event.id);
+            if event.kind == EventKind::Reset {
+                log::error!(
+                    "Webhook deliveries fell behind, events up to {} were missed",
+                    event.id
+                );
+                continue;
+            }
+            let hooks = match db.get_webhooks().await {
+                Ok(hooks) => hooks,
+                Err(e) => {
+                    log::error!("Unable to get webhooks for event {}: {:?}", event.id, e);
+                    continue;
+                }
+            };
+            for hook in hooks.into_iter().filter(|h| h.wants(event.kind)) {
+                let (db, event, policy) = (db.clone(), event.clone(), policy.clone());
+                rt::spawn(async move {
+                    if let Err(e) = deliver(&db, &hook, event, &policy).await {
+                        log::error!("Unable to log a delivery to {}: {:?}", hook.url, e);
+                    }
+                });
+            }
+        }
+        // The stream ends if deliveries fall behind, so catch up from where it ended
+        events = Box::pin(bus.listen(last_id, None));
+    }
+}
+
+/// Retries the deliveries still pending, as retries only wait in memory.
+async fn resume(db: &Data<Database>, policy: &RetryPolicy) {
+    let pending = match db.get_pending_deliveries().await {
+        Ok(pending) => pending,
+        Err(e) => {
+            log::error!("Unable to get the pending webhook deliveries: {:?}", e);
+            return;
+        }
+    };
+    for delivery in pending {
+        let hook = match db.get_webhook(&delivery.webhook).await {
+            Ok(hook) => hook,
+            Err(e) => {
+                log::error!("Unable to resume delivery {:?}: {:?}", delivery.key, e);
+                continue;
+            }
+        };
+        let (db, policy) = (db.clone(), policy.clone());
+        rt::spawn(async move {
+            if let Err(e) = retry(&db, &hook, delivery, &policy).await {
+                log::error!("Unable to log a delivery to {}: {:?}", hook.url, e);
+            }
+        });
+    }
+}
+
+/// Post an event to a webhook until it is accepted or the attempts run out,
+/// waiting twice as long before each retry. Every attempt is logged.
+pub async fn deliver(
+    db: &Database,
+    hook: &Webhook,
+    event: Event,
+    policy: &RetryPolicy,
+) -> Result<WebhookDelivery, Error> {
+    let webhook = hook.key.as_deref().unwrap_or_default();
+    let delivery = db
+        .add_delivery(WebhookDelivery::new(webhook, event))
+        .await?;
+    retry(db, hook, delivery, policy).await
+}
+
+/// Make the attempts left at a logged delivery, each once the wait after the last one is over.
+async fn retry(
+    db: &Database,
+    hook: &Webhook,
+    mut delivery: WebhookDelivery,
+    policy: &RetryPolicy,
+) -> Result<WebhookDelivery, Error> {
+    let body = serde_json::to_vec(&delivery.event).map_err(Error::internal)?;
+    let made = delivery.attempts.len() as u32;
+    for attempt in made + 1..=policy.attempts {
+        if let Some(last) = delivery.attempts.last() {
+            let wait =
+                chrono::Duration::from_std(policy.delay(attempt - 1)).map_err(Error::internal)?;
+            if let Ok(wait) = (last.at + wait - Utc::now()).to_std() {
+                sleep(wait).await;
+            }
+        }
+        let sent = send(hook, &delivery, body.clone(), policy.timeout).await;
+        let succeeded = sent.succeeded();
+        if !succeeded {
+            log::debug!(
+                "Delivery of event {} to {} failed: {:?}",
+                delivery.event.id,
+                hook.url,
+                sent
+            );
+        }
+        delivery.attempts.push(sent);
+        delivery.status = match succeeded {
+            true => DeliveryStatus::Delivered,
+            false if attempt == policy.attempts => DeliveryStatus::Failed,
+            false => DeliveryStatus::Pending,
+        };
+        db.update_delivery(delivery.clone()).await?;
+        if succeeded {
+            break;
+        }
+    }
+    // Resumed with fewer attempts than it already had
+    if made >= policy.attempts && delivery.status == DeliveryStatus::Pending {
+        delivery.status = DeliveryStatus::Failed;
+        db.update_delivery(delivery.clone()).await?;
+    }
+    Ok(delivery)
+}
+
+/// Post a delivery once. The blocking client runs off the async workers.
+async fn send(
+    hook: &Webhook,
+    delivery: &WebhookDelivery,
+    body: Vec<u8>,
+    timeout: Duration,
+) -> Attempt {
+    let at = Utc::now();
+    let url = hook.url.clone();
+    let signature = sign(&hook.secret, &body);
+    let event = delivery.event.kind.name();
+    let id = delivery.key.clone().unwrap_or_default();
+    let sent = rt::task::spawn_blocking(move || {
+        let sent = ureq::post(&url)
+            .timeout(timeout)
+            .set("Content-Type", "application/json")
+            .set(SIGNATURE, &signature)
+            .set("X-Webhook-Event", event)
+            .set("X-Webhook-Delivery", &id)
+            .send_bytes(&body);
+        match sent {
+            Ok(resp) => (Some(resp.status()), None),
+            Err(ureq::Error::Status(code, _)) => {
+                (Some(code), Some(format!("The receiver answered {}", code)))
+            }
+            Err(e) => (None, Some(e.to_string())),
+        }
+    })
+    .await;
+    let (status_code, error) = sent.unwrap_or_else(|e| (None, Some(e.to_string())));
+    Attempt {
+        at,
+        status_code,
+        error,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::events::EventKind;
+    use std::io::{BufRead, BufReader, Read, Write as IoWrite};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    const SECRET: &str = "0123456789abcdef";
+
+    /// A stand-in receiver answering each request with the next status,
+    /// and handing over the headers and body it got.
+    fn receiver(statuses: Vec<u16>) -> (String, mpsc::Receiver<(String, Vec<u8>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            for (status, stream) in statuses.into_iter().zip(listener.incoming()) {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut headers = String::new();
+                let mut len = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    if let Some(v) = line.to_lowercase().strip_prefix("content-length:") {
+                        len = v.trim().parse().unwrap();
+                    }
+                    headers.push_str(&line);
+                }
+                let mut body = vec![0; len];
+                reader.read_exact(&mut body).unwrap();
+                tx.send((headers, body)).unwrap();
+                write!(
+                    stream,
+                    "HTTP/1.1 {} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    fn hook(url: String) -> Webhook {
+        Webhook {
+            key: Some("1".to_string()),
+            url,
+            events: vec![],
+            secret: SECRET.to_string(),
+            created_by: None,
+            created_at: None,
+        }
+    }
+
+    fn logging_db() -> Database {
+        let mut db = Database::default();
+        db.expect_add_delivery().returning(|d| {
+            Ok(WebhookDelivery {
+                key: Some("d1".to_string()),
+                ..d
+            })
+        });
+        db.expect_update_delivery().returning(|_| Ok(()));
+        db
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let signature = sign(SECRET, b"{}");
+        assert!(signature.starts_with("sha256="));
+        assert_eq!(signature.len(), 7 + 64);
+        assert!(verify(SECRET, b"{}", &signature));
+        assert!(!verify(SECRET, b"{ }", &signature));
+        assert!(!verify("another secret!!", b"{}", &signature));
+        assert!(!verify(SECRET, b"{}", "sha256=zz"));
+    }
+
+    #[actix_web::test]
+    async fn test_deliver_retries() {
+        let (url, requests) = receiver(vec![500, 200]);
+        let policy = RetryPolicy {
+            base_delay: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let event = Event::topic(EventKind::TopicCreated, "Mercy");
+        let delivery = deliver(&logging_db(), &hook(url), event, &policy)
+            .await
+            .unwrap();
+
+        assert_eq!(delivery.status, DeliveryStatus::Delivered);
+        let codes: Vec<_> = delivery.attempts.iter().map(|a| a.status_code).collect();
+        assert_eq!(codes, vec![Some(500), Some(200)]);
+
+        let (headers, body) = requests.recv().unwrap();
+        let headers = headers.to_lowercase();
+        assert!(headers.contains("x-webhook-event: topic_created"));
+        assert!(headers.contains("x-webhook-delivery: d1"));
+        let signature = headers
+            .lines()
+            .find_map(|l| l.strip_prefix("x-webhook-signature: "))
+            .unwrap();
+        assert!(verify(SECRET, &body, signature.trim()));
+    }
+
+    #[actix_web::test]
+    async fn test_deliver_fails_after_attempts() {
+        let (url, _requests) = receiver(vec![503, 503]);
+        let policy = RetryPolicy {
+            attempts: 2,
+            base_delay: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let event = Event::topic(EventKind::TopicDeleted, "Mercy");
+        let delivery = deliver(&logging_db(), &hook(url), event, &policy)
+            .await
+            .unwrap();
+
+        assert_eq!(delivery.status, DeliveryStatus::Failed);
+        assert_eq!(delivery.attempts.len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn test_retry_resumes() {
+        let (url, requests) = receiver(vec![200]);
+        let policy = RetryPolicy {
+            attempts: 3,
+            base_delay: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let failed = Attempt {
+            at: Utc::now() - chrono::Duration::hours(1),
+            status_code: Some(503),
+            error: None,
+        };
+        let pending = WebhookDelivery {
+            key: Some("d1".to_string()),
+            attempts: vec![failed.clone()],
+            ..WebhookDelivery::new("1", Event::topic(EventKind::TopicCreated, "Mercy"))
+        };
+        let delivery = retry(&logging_db(), &hook(url.clone()), pending.clone(), &policy)
+            .await
+            .unwrap();
+        assert_eq!(delivery.status, DeliveryStatus::Delivered);
+        assert_eq!(delivery.attempts.len(), 2);
+        requests.recv().unwrap();
+
+        // No attempts left
+        let exhausted = WebhookDelivery {
+            attempts: vec![failed; 3],
+            ..pending
+        };
+        let delivery = retry(&logging_db(), &hook(url), exhausted, &policy)
+            .await
+            .unwrap();
+        assert_eq!(delivery.status, DeliveryStatus::Failed);
+        assert_eq!(delivery.attempts.len(), 3);
+    }
+
+    #[test]
+    fn test_delay_doubles() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.delay(1), Duration::from_secs(10));
+        assert_eq!(policy.delay(3), Duration::from_secs(40));
+    }
+}
@@ -1,34 +1,60 @@
 use actix_identity::Identity;
-use actix_web::web::{scope, Data, Form, Json, Query, ServiceConfig};
-use actix_web::{get, post, services, HttpResponse};
+use actix_web::web::{scope, Data, Json, Path, Query, ServiceConfig};
+use actix_web::{delete, get, post, services, HttpResponse};
 use serde::{Deserialize, Serialize};
+use utoipa::IntoParams;
 
-use crate::core::auth::AuthHandler;
+use crate::core::auth::{is_api_token, AuthHandler};
 #[mockall_double::double]
 use crate::core::db::Database;
-use crate::models::auth::User;
-use crate::models::generic::Error;
+use crate::core::local::{self, LoginLimiter};
+use crate::models::auth::{ApiTokenInfo, LocalLogin, NewApiToken, PasswordChange, User};
+use crate::models::generic::{Error, Generic};
 
 pub fn auth_service(cfg: &mut ServiceConfig) {
-    cfg.service(scope("/auth").service(services![login, authorize, user]));
+    cfg.service(scope("/auth").service(services![
+        login,
+        authorize,
+        logout,
+        user,
+        providers,
+        mint_token,
+        get_tokens,
+        revoke_token,
+        local_login,
+        change_password
+    ]));
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 struct Referrer {
+    /// Where to go after logging in.
     referrer: Option<String>,
+    /// The name of the provider to log in with, the first one listed at `/auth/providers` by default.
+    provider: Option<String>,
 }
 
+#[utoipa::path(
+    context_path = "/api/v1/auth",
+    tag = "auth",
+    params(Referrer),
+    responses(
+        (status = 302, description = "Redirects to the identity provider, not for programmatic use"),
+        (status = 400, description = "Unknown provider, `details.providers` lists the known ones", body = Error),
+    )
+)]
 #[get("/login")]
 async fn login(
     auth: Data<AuthHandler>,
     db: Data<Database>,
     referrer: Query<Referrer>,
 ) -> Result<HttpResponse, Error> {
-    let r = referrer
-        .0
-        .referrer
-        .unwrap_or_else(|| "/api/v1/".to_string());
-    let url = auth.login(db.get_ref(), &r).await?;
+    let Referrer { referrer, provider } = referrer.into_inner();
+    let referrer = local_path(referrer.as_deref());
+    let url = auth
+        .login(db.get_ref(), provider.as_deref(), referrer)
+        .await?;
 
     // Redirect to login
     Ok(HttpResponse::Found()
@@ -36,48 +62,291 @@ async fn login(
         .finish())
 }
 
-#[derive(Serialize, Deserialize)]
-struct AuthResponse {
-    id_token: String,
+/// The names of the providers users can log in with, the default one first.
+/// `local` is listed last when local accounts are enabled, and logs in at `/auth/local/login`.
+#[utoipa::path(
+    context_path = "/api/v1/auth",
+    tag = "auth",
+    responses(
+        (status = 200, description = "The provider names", body = [String]),
+    )
+)]
+#[get("/providers")]
+async fn providers(auth: Data<AuthHandler>) -> Json<Vec<String>> {
+    let mut names = auth.provider_names();
+    if auth.local_accounts() {
+        names.push(local::PROVIDER.to_string());
+    }
+    Json(names)
+}
+
+/// Where the provider sends users back to, as the query of the redirect.
+#[derive(Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AuthResponse {
+    /// Exchanged for the tokens.
+    code: Option<String>,
     state: String,
+    /// Why the provider did not log the user in.
+    error: Option<String>,
+    error_description: Option<String>,
 }
 
-#[post("/authorize")]
+#[utoipa::path(
+    context_path = "/api/v1/auth",
+    tag = "auth",
+    params(AuthResponse),
+    responses(
+        (status = 302, description = "Logged in, redirects to the referrer"),
+        (status = 401, description = "Login refused, or an invalid code or ID token", body = Error),
+    )
+)]
+#[get("/authorize")]
 async fn authorize(
-    q: Form<AuthResponse>,
+    q: Query<AuthResponse>,
     auth: Data<AuthHandler>,
     db: Data<Database>,
     id: Identity,
 ) -> Result<HttpResponse, Error> {
-    let state_split: Vec<&str> = q.state.split('&').collect(); // First is the ID and the second element is the referrer
-    let state = state_split
-        .get(0)
-        .ok_or_else(|| Error::default("Failed to get the state"))?
-        .trim_start_matches("State=");
-    let referrer = state_split
-        .get(1)
-        .unwrap_or(&"/api/v1/")
-        .trim_start_matches("Referrer=");
-    auth.get_ref()
-        .validate_token(db.get_ref(), &q.id_token, state)
+    let code = match (&q.code, &q.error) {
+        (Some(code), None) => code,
+        _ => {
+            return Err(Error::unauthorized(format!(
+                "The login provider refused the login: {}",
+                q.error_description
+                    .as_deref()
+                    .or(q.error.as_deref())
+                    .unwrap_or("no code")
+            )))
+        }
+    };
+    let referrer = auth
+        .get_ref()
+        .authorize(db.get_ref(), code, &q.state)
         .await?;
-    id.remember(state.to_string());
+    id.remember(q.state.clone());
+    Ok(HttpResponse::Found()
+        .append_header(("location", local_path(referrer.as_deref())))
+        .finish())
+}
+
+/// The referrer if it is a path on this server, so logging in or out cannot redirect elsewhere.
+fn local_path(referrer: Option<&str>) -> &str {
+    referrer
+        .filter(|r| r.starts_with('/') && !r.starts_with("//") && !r.starts_with("/\\"))
+        .unwrap_or("/api/v1/")
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct LogoutParams {
+    /// Where to go after logging out, a path on this server.
+    referrer: Option<String>,
+    /// Also log out at the provider, when it supports it.
+    #[serde(default)]
+    end_session: bool,
+}
+
+/// Log out, deleting the session and clearing the session cookie.
+#[utoipa::path(
+    context_path = "/api/v1/auth",
+    tag = "auth",
+    params(LogoutParams),
+    responses(
+        (status = 302, description = "Logged out, redirects to the provider to log out there too, or to the referrer"),
+    )
+)]
+#[get("/logout")]
+async fn logout(
+    q: Query<LogoutParams>,
+    auth: Data<AuthHandler>,
+    db: Data<Database>,
+    id: Identity,
+) -> Result<HttpResponse, Error> {
+    let referrer = local_path(q.referrer.as_deref());
+    let end_session = match id.identity() {
+        Some(session) => {
+            auth.get_ref()
+                .logout(db.get_ref(), session, q.end_session, referrer)
+                .await?
+        }
+        None => None,
+    };
+    id.forget();
+    let location = end_session.map_or_else(|| referrer.to_string(), String::from);
     Ok(HttpResponse::Found()
-        .append_header(("location", referrer))
+        .append_header(("location", location))
         .finish())
 }
 
+#[utoipa::path(
+    context_path = "/api/v1/auth",
+    tag = "auth",
+    responses(
+        (status = 200, description = "The logged in user", body = User),
+        (status = 401, description = "Not logged in", body = Error),
+    ),
+    security(("session" = []), ("token" = []))
+)]
 #[get("/user")]
 async fn user(
     auth: Data<AuthHandler>,
     db: Data<Database>,
     id: Identity,
 ) -> Result<Json<User>, Error> {
-    let session: String = id
+    current_user(&auth, &db, &id).await.map(Json)
+}
+
+/// Mint a personal API token, for scripts to send as `Authorization: Bearer`.
+/// The token is only shown in this response.
+#[utoipa::path(
+    context_path = "/api/v1/auth",
+    tag = "auth",
+    request_body = NewApiToken,
+    responses(
+        (status = 201, description = "The minted token", body = MintedToken),
+        (status = 400, description = "No name, or an invalid expiry", body = Error),
+        (status = 401, description = "Not logged in", body = Error),
+        (status = 403, description = "The scope is above the role of the user, or minted with a token", body = Error),
+    ),
+    security(("session" = []))
+)]
+#[post("/tokens")]
+async fn mint_token(
+    new: Json<NewApiToken>,
+    auth: Data<AuthHandler>,
+    db: Data<Database>,
+    id: Identity,
+) -> Result<HttpResponse, Error> {
+    let session = id
         .identity()
-        .ok_or_else(|| Error::new("Not logged in!", actix_web::http::StatusCode::UNAUTHORIZED))?;
-    auth.get_ref()
-        .get_user(db.get_ref(), session)
-        .await
-        .map(Json)
+        .ok_or_else(|| Error::unauthorized("Not logged in!"))?;
+    let minted = auth
+        .get_ref()
+        .mint_token(db.get_ref(), session, new.into_inner())
+        .await?;
+    Ok(HttpResponse::Created().json(minted))
+}
+
+/// The API tokens of the logged in user, the latest first.
+#[utoipa::path(
+    context_path = "/api/v1/auth",
+    tag = "auth",
+    responses(
+        (status = 200, description = "The tokens, without the tokens themselves", body = [ApiTokenInfo]),
+        (status = 401, description = "Not logged in", body = Error),
+    ),
+    security(("session" = []), ("token" = []))
+)]
+#[get("/tokens")]
+async fn get_tokens(
+    auth: Data<AuthHandler>,
+    db: Data<Database>,
+    id: Identity,
+) -> Result<Json<Vec<ApiTokenInfo>>, Error> {
+    let me = current_user(&auth, &db, &id).await?;
+    let tokens = db.get_api_tokens(&me.email).await?;
+    Ok(Json(tokens.into_iter().map(ApiTokenInfo::from).collect()))
+}
+
+/// Revoke an API token of the logged in user.
+#[utoipa::path(
+    context_path = "/api/v1/auth",
+    tag = "auth",
+    params(("key" = String, Path, description = "The key of the token")),
+    responses(
+        (status = 200, description = "Revoked the token", body = Generic),
+        (status = 401, description = "Not logged in", body = Error),
+        (status = 404, description = "The user has no such token", body = Error),
+    ),
+    security(("session" = []), ("token" = []))
+)]
+#[delete("/tokens/{key}")]
+async fn revoke_token(
+    key: Path<String>,
+    auth: Data<AuthHandler>,
+    db: Data<Database>,
+    id: Identity,
+) -> Result<Generic, Error> {
+    let me = current_user(&auth, &db, &id).await?;
+    db.delete_api_token(&key, &me.email).await?;
+    Ok(Generic::new(format!("Revoked the token {}", key)))
+}
+
+/// The logged in user, by session or API token.
+async fn current_user(auth: &AuthHandler, db: &Database, id: &Identity) -> Result<User, Error> {
+    let session = id
+        .identity()
+        .ok_or_else(|| Error::unauthorized("Not logged in!"))?;
+    auth.get_user(db, session).await
+}
+
+/// Log in with a local account, setting the session cookie.
+#[utoipa::path(
+    context_path = "/api/v1/auth",
+    tag = "auth",
+    request_body = LocalLogin,
+    responses(
+        (status = 200, description = "Logged in", body = User),
+        (status = 401, description = "Wrong username or password", body = Error),
+        (status = 404, description = "Local accounts are disabled", body = Error),
+        (status = 429, description = "Too many failed logins, with `details.retry_after` in seconds", body = Error),
+    )
+)]
+#[post("/local/login")]
+async fn local_login(
+    credentials: Json<LocalLogin>,
+    auth: Data<AuthHandler>,
+    limiter: Data<LoginLimiter>,
+    db: Data<Database>,
+    id: Identity,
+) -> Result<Json<User>, Error> {
+    if !auth.local_accounts() {
+        return Err(Error::not_found("Local accounts are disabled"));
+    }
+    let session = local::login(&db, &limiter, &credentials.username, &credentials.password).await?;
+    id.remember(session.clone());
+    auth.get_user(&db, session).await.map(Json)
+}
+
+/// Change the password of the local account of the logged in user.
+/// Every session of the user is logged out, this one included.
+#[utoipa::path(
+    context_path = "/api/v1/auth",
+    tag = "auth",
+    request_body = PasswordChange,
+    responses(
+        (status = 200, description = "Changed the password", body = Generic),
+        (status = 400, description = "The new password is too short, or the user has no local account", body = Error),
+        (status = 401, description = "Not logged in", body = Error),
+        (status = 403, description = "Wrong current password, or sent with an API token", body = Error),
+        (status = 404, description = "Local accounts are disabled", body = Error),
+    ),
+    security(("session" = []))
+)]
+#[post("/local/password")]
+async fn change_password(
+    change: Json<PasswordChange>,
+    auth: Data<AuthHandler>,
+    db: Data<Database>,
+    id: Identity,
+) -> Result<Generic, Error> {
+    if !auth.local_accounts() {
+        return Err(Error::not_found("Local accounts are disabled"));
+    }
+    if id.identity().is_some_and(|i| is_api_token(&i)) {
+        return Err(Error::forbidden("API tokens cannot change passwords"));
+    }
+    let me = current_user(&auth, &db, &id).await?;
+    local::change_password(
+        &db,
+        &me.email,
+        &change.current_password,
+        &change.new_password,
+    )
+    .await?;
+    id.forget();
+    Ok(Generic::new(
+        "Changed the password, log in again".to_string(),
+    ))
 }
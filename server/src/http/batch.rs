@@ -0,0 +1,330 @@
+use crate::core::auth::AuthHandler;
+use actix_identity::Identity;
+use actix_web::http::StatusCode;
+use actix_web::web::{scope, Data, Json, ServiceConfig};
+use actix_web::{post, HttpResponse};
+use actix_web_lab::middleware::from_fn;
+use serde_json::json;
+
+use super::topics::current_email;
+#[mockall_double::double]
+use crate::core::db::Database;
+use crate::models::auth::Role;
+use crate::models::batch::{Batch, Operation};
+use crate::models::generic::Error;
+
+pub fn batch_service(cfg: &mut ServiceConfig) {
+    cfg.service(
+        scope("/batch")
+            .service(run_batch)
+            .wrap(from_fn(AuthHandler::editor_middleware)),
+    );
+}
+
+/// Run a list of operations in a single transaction.
+/// Either all of them are applied or none are, the result has the status of each.
+/// Unlinking is a `DELETE` on the other routes, so batches that unlink need an admin.
+#[utoipa::path(
+    context_path = "/api/v1/batch",
+    tag = "batch",
+    request_body = Batch,
+    responses(
+        (status = 200, description = "Applied every operation", body = BatchResult),
+        (status = 400, description = "An invalid operation, `details.index` holds its position", body = Error),
+        (status = 401, description = "Not logged in"),
+        (status = 403, description = "Not an editor, or not an admin for a batch that unlinks", body = Error),
+        (status = 404, description = "An operation refers to a missing topic or reference, nothing was applied", body = BatchResult),
+        (status = 409, description = "An operation conflicts with an existing topic, nothing was applied", body = BatchResult),
+    ),
+    security(("session" = []), ("token" = []))
+)]
+#[post("/")]
+async fn run_batch(
+    batch: Json<Batch>,
+    db: Data<Database>,
+    auth: Data<AuthHandler>,
+    id: Identity,
+) -> Result<HttpResponse, Error> {
+    let ops = batch.into_inner().operations;
+    if ops.is_empty() {
+        return Err(Error::validation("A batch needs at least one operation"));
+    }
+    if ops.len() > Batch::MAX_OPERATIONS {
+        return Err(Error::validation(format!(
+            "A batch has at most {} operations",
+            Batch::MAX_OPERATIONS
+        )));
+    }
+    // Catch what we can before starting a transaction
+    for (index, op) in ops.iter().enumerate() {
+        if let Operation::LinkQRef { qref, .. } | Operation::UnlinkQRef { qref, .. } = op {
+            qref.validate()
+                .map_err(|e| e.with_details(json!({ "index": index })))?;
+        }
+    }
+
+    if ops.iter().any(Operation::unlinks) {
+        let session = id
+            .identity()
+            .ok_or_else(|| Error::unauthorized("Not logged in!"))?;
+        if auth.get_user(&db, session).await?.role < Role::Admin {
+            return Err(Error::forbidden(format!(
+                "Unlinking requires the {} role",
+                Role::Admin.name()
+            )));
+        }
+    }
+
+    let user = current_email(&auth, &db, &id).await;
+    let result = db.run_batch(ops, user).await?;
+    let status = result.error().map_or(StatusCode::OK, |e| e.kind().status());
+    Ok(HttpResponse::build(status).json(result))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::batch::{BatchResult, OpResult, OpStatus};
+    use crate::models::refs::{HRef, QRef};
+    use actix_service::Service;
+    use actix_web::{
+        test,
+        test::{init_service, read_body_json, TestRequest},
+        App,
+    };
+
+    fn auth_handler() -> AuthHandler {
+        AuthHandler::new("http://localhost".to_string())
+    }
+
+    fn qref(chapter: usize, verse: usize) -> QRef {
+        QRef {
+            chapter,
+            init_verse: verse,
+            final_verse: verse,
+        }
+    }
+
+    #[test]
+    async fn test_batch() {
+        let ops = vec![
+            Operation::CreateTopic {
+                name: "mercy".to_string(),
+            },
+            Operation::LinkQRef {
+                topic: "mercy".to_string(),
+                qref: qref(1, 1),
+            },
+            Operation::LinkHRef {
+                topic: "mercy".to_string(),
+                href: HRef {
+                    collection: "bukhari".to_string(),
+                    number: "1".to_string(),
+                },
+            },
+        ];
+        let expected = ops.clone();
+        let mut db = Database::default();
+        db.expect_run_batch()
+            .withf(move |ops, user| ops == &expected && user.is_none())
+            .returning(|ops, _user| {
+                Ok(BatchResult {
+                    committed: true,
+                    results: (0..ops.len())
+                        .map(|i| OpResult::new(i, OpStatus::Ok))
+                        .collect(),
+                })
+            });
+        let app = init_service(
+            App::new()
+                .service(run_batch)
+                .app_data(Data::new(db))
+                .app_data(Data::new(auth_handler())),
+        )
+        .await;
+        let req = TestRequest::post()
+            .uri("/")
+            .set_json(&Batch { operations: ops })
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: BatchResult = read_body_json(resp).await;
+        assert!(body.committed);
+        assert_eq!(body.results.len(), 3);
+    }
+
+    #[test]
+    async fn test_batch_rolled_back() {
+        let mut db = Database::default();
+        db.expect_run_batch().returning(|_ops, _user| {
+            Ok(BatchResult {
+                committed: false,
+                results: vec![
+                    OpResult::new(0, OpStatus::RolledBack),
+                    OpResult::failed(1, Error::not_found("Topic missing not found")),
+                    OpResult::new(2, OpStatus::Skipped),
+                ],
+            })
+        });
+        let app = init_service(
+            App::new()
+                .service(run_batch)
+                .app_data(Data::new(db))
+                .app_data(Data::new(auth_handler())),
+        )
+        .await;
+        let req = TestRequest::post()
+            .uri("/")
+            .set_json(json!({ "operations": [
+                { "op": "create_topic", "name": "mercy" },
+                { "op": "link_qref", "topic": "missing", "qref": { "chapter": 1, "init_verse": 1, "final_verse": 1 } },
+                { "op": "rename_topic", "topic": "mercy", "name": "Mercy" },
+            ]}))
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body: BatchResult = read_body_json(resp).await;
+        assert!(!body.committed);
+        let statuses: Vec<OpStatus> = body.results.iter().map(|r| r.status).collect();
+        assert_eq!(
+            statuses,
+            vec![OpStatus::RolledBack, OpStatus::Failed, OpStatus::Skipped]
+        );
+    }
+
+    #[test]
+    async fn test_batch_invalid_qref() {
+        // Nothing reaches the database
+        let db = Database::default();
+        let app = init_service(
+            App::new()
+                .service(run_batch)
+                .app_data(Data::new(db))
+                .app_data(Data::new(auth_handler())),
+        )
+        .await;
+        let batch = Batch {
+            operations: vec![
+                Operation::CreateTopic {
+                    name: "mercy".to_string(),
+                },
+                Operation::UnlinkQRef {
+                    topic: "mercy".to_string(),
+                    qref: qref(115, 1),
+                },
+            ],
+        };
+        let req = TestRequest::post().uri("/").set_json(&batch).to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: Error = read_body_json(resp).await;
+        assert_eq!(body.details(), Some(&json!({ "index": 1 })));
+    }
+
+    #[test]
+    async fn test_batch_unlink_needs_admin() {
+        use crate::models::auth::{RoleAssignment, SessionRecord, Token};
+        use actix_identity::{CookieIdentityPolicy, IdentityService};
+        use actix_web::http::header::SET_COOKIE;
+        use chrono::{Duration, Utc};
+
+        #[actix_web::get("/login")]
+        async fn login(id: Identity) -> HttpResponse {
+            id.remember("session".to_string());
+            HttpResponse::Ok().finish()
+        }
+
+        for (role, status) in [
+            (Role::Editor, StatusCode::FORBIDDEN),
+            (Role::Admin, StatusCode::OK),
+        ] {
+            let mut db = Database::default();
+            db.expect_get_session().returning(|_| {
+                Ok(SessionRecord {
+                    nonce: String::new(),
+                    token: Some(Token {
+                        token: None,
+                        name: "User".to_string(),
+                        preferred_username: "user@example.org".to_string(),
+                        exp: Utc::now() + Duration::hours(1),
+                    }),
+                    provider: None,
+                    verifier: None,
+                    access_token: None,
+                    refresh_token: None,
+                    created_at: None,
+                    groups: vec![],
+                    referrer: None,
+                })
+            });
+            db.expect_get_role().returning(move |email| {
+                Ok(Some(RoleAssignment {
+                    email: email.to_string(),
+                    role,
+                    assigned_by: None,
+                    assigned_at: None,
+                }))
+            });
+            db.expect_run_batch()
+                .times(usize::from(role == Role::Admin))
+                .returning(|_ops, _user| {
+                    Ok(BatchResult {
+                        committed: true,
+                        results: vec![OpResult::new(0, OpStatus::Ok)],
+                    })
+                });
+            let app = init_service(
+                App::new()
+                    .wrap(IdentityService::new(
+                        CookieIdentityPolicy::new(&[0; 32]).secure(false),
+                    ))
+                    .service(login)
+                    .service(run_batch)
+                    .app_data(Data::new(db))
+                    .app_data(Data::new(auth_handler())),
+            )
+            .await;
+            let resp = app
+                .call(TestRequest::with_uri("/login").to_request())
+                .await
+                .unwrap();
+            let cookie = resp.headers().get(SET_COOKIE).unwrap().to_str().unwrap();
+            let batch = Batch {
+                operations: vec![Operation::UnlinkQRef {
+                    topic: "mercy".to_string(),
+                    qref: qref(1, 1),
+                }],
+            };
+            let req = TestRequest::post()
+                .uri("/")
+                .cookie(actix_web::cookie::Cookie::parse(cookie.to_string()).unwrap())
+                .set_json(&batch)
+                .to_request();
+            let resp = app.call(req).await.unwrap();
+
+            assert_eq!(resp.status(), status, "{:?}", role);
+        }
+    }
+
+    #[test]
+    async fn test_batch_empty() {
+        let db = Database::default();
+        let app = init_service(
+            App::new()
+                .service(run_batch)
+                .app_data(Data::new(db))
+                .app_data(Data::new(auth_handler())),
+        )
+        .await;
+        let req = TestRequest::post()
+            .uri("/")
+            .set_json(&Batch { operations: vec![] })
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,395 @@
+use actix_web::web::ServiceConfig;
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, Http, HttpAuthScheme, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+use utoipa_swagger_ui::SwaggerUi;
+
+use super::{auth, batch, events, export, graph, import, refs, root, topics, users, webhooks};
+use crate::models::{
+    auth::{
+        ApiTokenInfo, LocalLogin, MintedToken, NewApiToken, NewRole, PasswordChange, Role,
+        RoleAssignment, SessionInfo, User,
+    },
+    batch::{Batch, BatchResult, OpResult, OpStatus, Operation},
+    events::{Event, EventKind},
+    export::{Document, Entry, ExportEdge, ExportFormat, ExportRef, Header},
+    generic::{Error, ErrorKind, Generic, Health, HealthStatus, QRefPage, TopicPage},
+    graph::GraphFormat,
+    import::{ImportFormat, ImportReport, RowError},
+    refs::{BRef, HRef, Lookup, QRef, RefEnum, RefTopics},
+    topics::{SortOrder, Topic, TopicMatch, TopicSort},
+    webhooks::{Attempt, DeliveryStatus, NewWebhook, WebhookDelivery, WebhookInfo},
+};
+
+/// The OpenAPI document, generated from the handlers and models.
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        root::root,
+        root::health,
+        topics::get_topics,
+        topics::search_topics,
+        topics::get_topic,
+        topics::add_topic,
+        topics::put_topic,
+        topics::delete_topic,
+        refs::lookup,
+        refs::get_references,
+        refs::add_qref,
+        refs::get_qrefs,
+        refs::add_href,
+        refs::get_topics_for_verse,
+        refs::get_topics_for_hadith,
+        batch::run_batch,
+        import::import_file,
+        export::export_graph,
+        graph::get_graph,
+        events::get_events,
+        webhooks::get_webhooks,
+        webhooks::add_webhook,
+        webhooks::delete_webhook,
+        webhooks::get_deliveries,
+        webhooks::test_webhook,
+        auth::login,
+        auth::authorize,
+        auth::logout,
+        auth::user,
+        auth::providers,
+        auth::mint_token,
+        auth::get_tokens,
+        auth::revoke_token,
+        auth::local_login,
+        auth::change_password,
+        users::get_roles,
+        users::set_role,
+        users::delete_role,
+        users::get_sessions,
+        users::revoke_sessions,
+    ),
+    components(schemas(
+        Error,
+        ErrorKind,
+        Generic,
+        Health,
+        HealthStatus,
+        TopicPage,
+        QRefPage,
+        Topic,
+        TopicMatch,
+        TopicSort,
+        SortOrder,
+        QRef,
+        HRef,
+        BRef,
+        RefEnum,
+        Lookup,
+        RefTopics,
+        Batch,
+        Operation,
+        BatchResult,
+        OpResult,
+        OpStatus,
+        ImportFormat,
+        ImportReport,
+        RowError,
+        Document,
+        Header,
+        Entry,
+        ExportRef,
+        ExportEdge,
+        ExportFormat,
+        GraphFormat,
+        Event,
+        EventKind,
+        NewWebhook,
+        WebhookInfo,
+        WebhookDelivery,
+        DeliveryStatus,
+        Attempt,
+        User,
+        SessionInfo,
+        Role,
+        RoleAssignment,
+        NewRole,
+        NewApiToken,
+        MintedToken,
+        ApiTokenInfo,
+        LocalLogin,
+        PasswordChange,
+    )),
+    tags(
+        (name = "root", description = "Server status."),
+        (name = "topics", description = "Endpoints related to topics."),
+        (name = "refs", description = "Endpoints related to references."),
+        (name = "batch", description = "Atomic bulk changes to topics and references."),
+        (name = "import", description = "Importing topic–reference mappings from spreadsheets."),
+        (name = "export", description = "Backing up and visualizing the whole graph."),
+        (name = "events", description = "A live feed of changes to the graph."),
+        (name = "webhooks", description = "Posting changes to other systems, for admins."),
+        (name = "roles", description = "The roles of users, for admins."),
+        (name = "sessions", description = "The sessions of users, for admins."),
+        (name = "auth", description = "Login related endpoints."),
+    ),
+    modifiers(&SessionCookie)
+)]
+pub struct ApiDoc;
+
+/// The session cookie set by `/auth/authorize`, or an API token minted at `/auth/tokens`.
+struct SessionCookie;
+
+impl Modify for SessionCookie {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "session",
+                SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::new("ir_session"))),
+            );
+            components.add_security_scheme(
+                "token",
+                SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+            );
+        }
+    }
+}
+
+/// Serves the OpenAPI document at `/api/v1/openapi.json`, and a UI for it at `/api/v1/docs/`.
+pub fn docs_service(cfg: &mut ServiceConfig) {
+    cfg.service(
+        SwaggerUi::new("/api/v1/docs/{_:.*}").url("/api/v1/openapi.json", ApiDoc::openapi()),
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::auth::AuthHandler;
+    #[mockall_double::double]
+    use crate::core::db::Database;
+    use crate::core::local::LoginLimiter;
+    use crate::http::api_service;
+    use crate::models::auth::{SessionRecord, Token};
+    use actix_identity::{IdentityPolicy, IdentityService};
+    use actix_service::Service;
+    use actix_web::{
+        dev::{ServiceRequest, ServiceResponse},
+        http::{header::CONTENT_TYPE, Method, StatusCode},
+        test,
+        test::{init_service, TestRequest},
+        web,
+        web::Data,
+        App, HttpResponse,
+    };
+    use chrono::{Duration, Utc};
+    use futures::{
+        future::{ready, Ready},
+        FutureExt,
+    };
+    use std::panic::AssertUnwindSafe;
+    use utoipa::openapi::PathItemType;
+
+    const ADMIN: &str = "admin@example.org";
+
+    fn method(item: &PathItemType) -> Method {
+        match item {
+            PathItemType::Get => Method::GET,
+            PathItemType::Post => Method::POST,
+            PathItemType::Put => Method::PUT,
+            PathItemType::Delete => Method::DELETE,
+            PathItemType::Options => Method::OPTIONS,
+            PathItemType::Head => Method::HEAD,
+            PathItemType::Patch => Method::PATCH,
+            PathItemType::Trace => Method::TRACE,
+            PathItemType::Connect => Method::CONNECT,
+        }
+    }
+
+    /// Every request is made by a logged in admin, so it gets past the auth middlewares.
+    struct AdminPolicy;
+
+    impl IdentityPolicy for AdminPolicy {
+        type Future = Ready<Result<Option<String>, actix_web::Error>>;
+        type ResponseFuture = Ready<Result<(), actix_web::Error>>;
+
+        fn from_request(&self, _: &mut ServiceRequest) -> Self::Future {
+            ready(Ok(Some("session".to_string())))
+        }
+
+        fn to_response<B>(
+            &self,
+            _: Option<String>,
+            _: bool,
+            _: &mut ServiceResponse<B>,
+        ) -> Self::ResponseFuture {
+            ready(Ok(()))
+        }
+    }
+
+    /// A database that only knows the session of the admin.
+    fn admin_db() -> Database {
+        let mut db = Database::default();
+        db.expect_get_session().returning(|_| {
+            Ok(SessionRecord {
+                nonce: String::new(),
+                token: Some(Token {
+                    token: None,
+                    name: "Admin".to_string(),
+                    preferred_username: ADMIN.to_string(),
+                    exp: Utc::now() + Duration::hours(1),
+                }),
+                provider: None,
+                verifier: None,
+                access_token: None,
+                refresh_token: None,
+                created_at: Some(Utc::now()),
+                groups: vec![],
+                referrer: None,
+            })
+        });
+        db
+    }
+
+    /// The method and path of each handler, as its route macro in the source of this module.
+    /// Routes are only declared with these macros, and never in tests.
+    fn routed_operations() -> Vec<(Method, String)> {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/src/http");
+        let mut routes = vec![];
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            // GraphQL is documented by its own schema, at the playground
+            if path.ends_with("graphql.rs") {
+                continue;
+            }
+            let source = std::fs::read_to_string(path).unwrap();
+            let source = source.split("#[cfg(test)]").next().unwrap();
+            let mut lines = source.lines().map(str::trim);
+            while let Some(line) = lines.next() {
+                for (attr, method) in [
+                    ("#[get(\"", Method::GET),
+                    ("#[post(\"", Method::POST),
+                    ("#[put(\"", Method::PUT),
+                    ("#[delete(\"", Method::DELETE),
+                    ("#[patch(\"", Method::PATCH),
+                ] {
+                    if let Some(rest) = line.strip_prefix(attr) {
+                        let path = rest.split('"').next().unwrap().to_string();
+                        let handler = lines.by_ref().find(|l| l.contains("fn ")).unwrap();
+                        // The stand-in webhook receiver is served on its own
+                        if !handler.contains("fn receive(") {
+                            routes.push((method, path));
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+        routes
+    }
+
+    /// Every documented operation must be routed, with its method, to a resource with the
+    /// same path, and every routed handler must be documented.
+    /// Requests get past the middlewares, then fail in the handlers: the database
+    /// only knows the session, so handlers using it panic, which tells they were reached.
+    #[test]
+    async fn test_spec_matches_router() {
+        let spec = ApiDoc::openapi();
+        let mut documented = vec![];
+        for (path, item) in spec.paths.paths.iter() {
+            for op in item.operations.keys() {
+                documented.push((method(op), path.clone()));
+            }
+        }
+        assert!(!documented.is_empty());
+
+        for (method, path) in &documented {
+            // Fill in every path parameter
+            let uri = path
+                .split('/')
+                .map(|s| if s.starts_with('{') { "1" } else { s })
+                .collect::<Vec<_>>()
+                .join("/");
+            // Anew for each request, as a panic poisons the mocks
+            let app = init_service(
+                App::new()
+                    .wrap(IdentityService::new(AdminPolicy))
+                    .app_data(Data::new(admin_db()))
+                    .app_data(Data::new(
+                        AuthHandler::new("http://localhost".to_string())
+                            .with_admins(vec![ADMIN.to_string()]),
+                    ))
+                    .app_data(Data::new(LoginLimiter::default()))
+                    .configure(api_service)
+                    .default_service(web::to(HttpResponse::NotFound)),
+            )
+            .await;
+            let req = TestRequest::default()
+                .method(method.clone())
+                .uri(&uri)
+                .to_request();
+            let resp = match AssertUnwindSafe(app.call(req)).catch_unwind().await {
+                Ok(resp) => resp.unwrap(),
+                Err(panic) => {
+                    let message = panic
+                        .downcast_ref::<String>()
+                        .map(String::as_str)
+                        .or_else(|| panic.downcast_ref::<&str>().copied())
+                        .unwrap_or_default();
+                    assert!(
+                        message.contains("No matching expectation"),
+                        "{} {} panicked: {}",
+                        method,
+                        path,
+                        message
+                    );
+                    continue;
+                }
+            };
+            assert_eq!(
+                resp.request().match_pattern().as_deref(),
+                Some(path.as_str()),
+                "{} {} is documented but not routed",
+                method,
+                path
+            );
+            // The errors of handlers are JSON, unlike those of the router
+            assert!(
+                !matches!(
+                    resp.status(),
+                    StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED
+                ) || resp.headers().contains_key(CONTENT_TYPE),
+                "{} {} is documented but routed for another method",
+                method,
+                path
+            );
+        }
+
+        let routed = routed_operations();
+        for (method, route) in &routed {
+            assert!(
+                documented
+                    .iter()
+                    .any(|(m, path)| m == method && path.ends_with(route.as_str())),
+                "{} {} is routed but not documented",
+                method,
+                route
+            );
+        }
+        assert_eq!(
+            routed.len(),
+            documented.len(),
+            "Every route is documented once:\n{:?}\n{:?}",
+            routed,
+            documented
+        );
+    }
+
+    #[test]
+    async fn test_serves_spec() {
+        let app = init_service(App::new().configure(docs_service)).await;
+        let req = TestRequest::with_uri("/api/v1/openapi.json").to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert!(resp.status().is_success());
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert!(body["paths"]["/api/v1/topics/"].is_object());
+        assert!(body["components"]["securitySchemes"]["session"].is_object());
+    }
+}
@@ -0,0 +1,132 @@
+use crate::core::auth::AuthHandler;
+use actix_web::http::header::{CACHE_CONTROL, CONTENT_TYPE};
+use actix_web::rt::time::timeout;
+use actix_web::web::{scope, Bytes, Data, Query, ServiceConfig};
+use actix_web::{get, HttpRequest, HttpResponse};
+use actix_web_lab::middleware::from_fn;
+use futures::{stream, StreamExt};
+use std::time::Duration;
+
+use crate::core::events::{self, EventBus};
+use crate::models::events::EventParams;
+use crate::models::generic::Error;
+
+/// How long the stream may stay silent before a comment keeps proxies from closing it.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Unlike the rest of the reads, the feed needs a login: each client holds a connection
+/// open for as long as it likes, which anonymous clients could exhaust.
+pub fn events_service(cfg: &mut ServiceConfig) {
+    cfg.service(
+        scope("/events")
+            .service(get_events)
+            .wrap(from_fn(AuthHandler::auth_middleware)),
+    );
+}
+
+/// Stream the changes to topics and references as Server-Sent Events.
+///
+/// Each event is named after its type, and its data is the event as JSON.
+/// A client reconnecting with `Last-Event-ID` first gets the events it missed,
+/// as long as the server still has them, or else a `reset` event telling it to reload.
+#[utoipa::path(
+    context_path = "/api/v1/events",
+    tag = "events",
+    params(
+        EventParams,
+        ("Last-Event-ID" = Option<u64>, Header, description = "Resume after this event"),
+    ),
+    responses(
+        (status = 200, description = "An endless stream of events", body = Event, content_type = "text/event-stream"),
+        (status = 400, description = "Invalid last event id", body = Error),
+        (status = 401, description = "Not logged in"),
+    ),
+    security(("session" = []), ("token" = []))
+)]
+#[get("/")]
+async fn get_events(
+    req: HttpRequest,
+    params: Query<EventParams>,
+    bus: Data<EventBus>,
+) -> Result<HttpResponse, Error> {
+    let last_id = match req.headers().get("Last-Event-ID") {
+        Some(id) => Some(
+            id.to_str()
+                .ok()
+                .and_then(|id| id.trim().parse().ok())
+                .ok_or_else(|| Error::validation("Invalid Last-Event-ID"))?,
+        ),
+        None => params.last_event_id,
+    };
+    let events = Box::pin(bus.listen(last_id, params.topic.clone()));
+    let body = stream::unfold(events, |mut events| async move {
+        let chunk = match timeout(KEEP_ALIVE, events.next()).await {
+            Ok(Some(event)) => events::message(&event),
+            Ok(None) => return None,
+            Err(_) => Bytes::from_static(b": keep-alive\n\n"),
+        };
+        Some((Ok::<_, Error>(chunk), events))
+    });
+    Ok(HttpResponse::Ok()
+        .insert_header((CONTENT_TYPE, "text/event-stream"))
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .streaming(body))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::events::{Event, EventKind};
+    use actix_service::Service;
+    use actix_web::{
+        body::MessageBody,
+        http::StatusCode,
+        test,
+        test::{init_service, TestRequest},
+        App,
+    };
+    use std::pin::Pin;
+
+    #[test]
+    async fn test_get_events_resume() {
+        let bus = EventBus::default();
+        bus.publish(Event::topic(EventKind::TopicCreated, "Mercy"));
+        bus.publish(Event::topic(EventKind::TopicCreated, "Patience"));
+        bus.publish(Event::topic(EventKind::TopicDeleted, "Mercy"));
+        let last = bus.last_id();
+        let app = init_service(App::new().service(get_events).app_data(Data::new(bus))).await;
+        let req = TestRequest::with_uri("/?topic=Mercy")
+            .insert_header(("Last-Event-ID", (last - 2).to_string()))
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("Content-Type").unwrap(),
+            "text/event-stream"
+        );
+        let mut body = resp.into_body();
+        let chunk = futures::future::poll_fn(|cx| Pin::new(&mut body).poll_next(cx))
+            .await
+            .unwrap()
+            .unwrap();
+        let msg = String::from_utf8(chunk.to_vec()).unwrap();
+        assert!(msg.starts_with(&format!("id: {}\nevent: topic_deleted\n", last)));
+    }
+
+    #[test]
+    async fn test_get_events_invalid_last_id() {
+        let app = init_service(
+            App::new()
+                .service(get_events)
+                .app_data(Data::new(EventBus::default())),
+        )
+        .await;
+        let req = TestRequest::with_uri("/")
+            .insert_header(("Last-Event-ID", "abc"))
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,136 @@
+use crate::core::auth::AuthHandler;
+use actix_web::web::{scope, Data, Query, ServiceConfig};
+use actix_web::{get, HttpResponse};
+use actix_web_lab::middleware::from_fn;
+
+#[mockall_double::double]
+use crate::core::db::Database;
+use crate::core::export;
+use crate::models::export::ExportParams;
+
+/// Unlike most reads, exports need a login: each one dumps the whole graph,
+/// so anonymous clients could keep the database busy for free.
+pub fn export_service(cfg: &mut ServiceConfig) {
+    cfg.service(
+        scope("/export")
+            .service(export_graph)
+            .wrap(from_fn(AuthHandler::auth_middleware)),
+    );
+}
+
+/// Export every topic, reference and edge, with their ids, to back up or move the data.
+/// The export is streamed, and can be restored with the `import` command of the server.
+#[utoipa::path(
+    context_path = "/api/v1/export",
+    tag = "export",
+    params(ExportParams),
+    responses(
+        (status = 200, description = "The export, as one document or one entry per line", body = Document),
+        (status = 401, description = "Not logged in"),
+    ),
+    security(("session" = []), ("token" = []))
+)]
+#[get("/")]
+async fn export_graph(db: Data<Database>, params: Query<ExportParams>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(params.format.content_type())
+        .streaming(export::stream(db, params.format))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::export::{Entry, EntryKind, ExportEdge, ExportFormat, ExportRef};
+    use crate::models::refs::{QRef, RefEnum};
+    use crate::models::topics::Topic;
+    use actix_service::Service;
+    use actix_web::{
+        http::StatusCode,
+        test,
+        test::{init_service, read_body, TestRequest},
+        App,
+    };
+
+    fn entries() -> Vec<Entry> {
+        vec![
+            Entry::Topic(Topic {
+                aliases: vec!["Rahma".to_string()],
+                ..Topic::new("Mercy")
+            }),
+            Entry::Topic(Topic::new("Throne")),
+            Entry::Ref(ExportRef {
+                id: "QRef/1".to_string(),
+                reference: RefEnum::Q(QRef {
+                    chapter: 7,
+                    init_verse: 156,
+                    final_verse: 156,
+                }),
+            }),
+            Entry::Edge(ExportEdge {
+                id: "1".to_string(),
+                topic: "Mercy".to_string(),
+                reference: "QRef/1".to_string(),
+                note: Some("Encompasses all things".to_string()),
+            }),
+        ]
+    }
+
+    /// Serves `entries` one at a time, to cover entries spread over batches.
+    fn database() -> Database {
+        let mut db = Database::default();
+        db.expect_export_entries().returning(|kind, after, _size| {
+            let of_kind = entries().into_iter().filter(|e| {
+                matches!(
+                    (kind, e),
+                    (EntryKind::Topics, Entry::Topic(_))
+                        | (EntryKind::Refs, Entry::Ref(_))
+                        | (EntryKind::Edges, Entry::Edge(_))
+                )
+            });
+            Ok(of_kind
+                .skip_while(|e| after.is_some() && e.id() != after.as_deref())
+                .skip(after.is_some() as usize)
+                .take(1)
+                .collect())
+        });
+        db
+    }
+
+    #[test]
+    async fn test_export_round_trip() {
+        for format in [ExportFormat::Json, ExportFormat::Ndjson] {
+            let app = init_service(
+                App::new()
+                    .service(export_graph)
+                    .app_data(Data::new(database())),
+            )
+            .await;
+            let uri = format!(
+                "/?format={}",
+                serde_json::to_value(format).unwrap().as_str().unwrap()
+            );
+            let req = TestRequest::with_uri(&uri).to_request();
+            let resp = app.call(req).await.unwrap();
+
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(
+                resp.headers().get("content-type").unwrap(),
+                format.content_type()
+            );
+            let body = read_body(resp).await;
+            let read = export::read(&body[..], format)
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert_eq!(read, entries());
+        }
+    }
+
+    #[test]
+    async fn test_read_rejects_newer_version() {
+        let doc = r#"{"format":"islamic-references-graph","version":99,"exported_at":"2022-01-01T00:00:00Z","entries":[]}"#;
+        assert!(export::read(doc.as_bytes(), ExportFormat::Json).is_err());
+        let lines = "{\"type\":\"topic\",\"_key\":\"Mercy\",\"name\":\"Mercy\"}\n";
+        assert!(export::read(lines.as_bytes(), ExportFormat::Ndjson).is_err());
+    }
+}
@@ -0,0 +1,164 @@
+use crate::core::auth::AuthHandler;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::Error as AError;
+use actix_web::web::{scope, Data, Query, ServiceConfig};
+use actix_web::{get, HttpRequest, HttpResponse};
+use actix_web_lab::middleware::{from_fn, Next};
+
+#[mockall_double::double]
+use crate::core::db::Database;
+use crate::core::graph;
+use crate::core::linked::LinkedData;
+use crate::models::generic::Error;
+use crate::models::graph::GraphParams;
+
+pub fn graph_service(cfg: &mut ServiceConfig) {
+    cfg.service(
+        scope("/graph")
+            .service(get_graph)
+            .wrap(from_fn(graph_middleware)),
+    );
+}
+
+/// Lets anyone read the graph around a `root` topic. The whole graph needs a login,
+/// as it is built from every topic and reference, like an export.
+async fn graph_middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, AError> {
+    let rooted = Query::<GraphParams>::from_query(req.query_string())
+        .is_ok_and(|params| params.root.is_some());
+    if rooted {
+        AuthHandler::curation_middleware(req, next)
+            .await
+            .map(ServiceResponse::map_into_boxed_body)
+    } else {
+        AuthHandler::auth_middleware(req, next)
+            .await
+            .map(ServiceResponse::map_into_boxed_body)
+    }
+}
+
+/// Export the topic graph, or the part of it around a topic, for Gephi, yEd or Graphviz,
+/// or as SKOS concepts in Turtle.
+#[utoipa::path(
+    context_path = "/api/v1/graph",
+    tag = "export",
+    params(GraphParams),
+    responses(
+        (status = 200, description = "The graph as GraphML, DOT or Turtle", body = String),
+        (status = 401, description = "Not logged in, and no root topic"),
+        (status = 404, description = "No such root topic", body = Error),
+    )
+)]
+#[get("/")]
+async fn get_graph(
+    db: Data<Database>,
+    params: Query<GraphParams>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let depth = params.depth.min(GraphParams::MAX_DEPTH);
+    let g = db.get_graph(params.root.clone(), depth).await?;
+    let linked = LinkedData::from_request(&req)?;
+    Ok(HttpResponse::Ok()
+        .content_type(params.format.content_type())
+        .body(graph::render(&g, params.format, linked)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::graph::{Graph, GraphTopic};
+    use actix_service::Service;
+    use actix_web::{
+        http::StatusCode,
+        test,
+        test::{init_service, read_body, TestRequest},
+        App,
+    };
+    use std::collections::HashMap;
+
+    #[test]
+    async fn test_get_graph() {
+        let mut db = Database::default();
+        db.expect_get_graph()
+            .withf(|root, depth| {
+                root.as_deref() == Some("Mercy") && *depth == GraphParams::MAX_DEPTH
+            })
+            .returning(|_root, _depth| {
+                Ok(Graph {
+                    topics: vec![GraphTopic {
+                        id: "Topic/Mercy".to_string(),
+                        name: "Mercy".to_string(),
+                        aliases: vec![],
+                        names: HashMap::new(),
+                        parent: None,
+                    }],
+                    ..Default::default()
+                })
+            });
+        let app = init_service(
+            App::new()
+                .service(get_graph)
+                .app_data(Data::new(db))
+                .app_data(Data::new(LinkedData::new("http://localhost:8000"))),
+        )
+        .await;
+        let req = TestRequest::with_uri("/?format=dot&root=Mercy&depth=100").to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("content-type").unwrap(),
+            "text/vnd.graphviz"
+        );
+        let body = read_body(resp).await;
+        assert!(body.starts_with(b"digraph Topics {"));
+    }
+
+    #[test]
+    async fn test_get_graph_unknown_root() {
+        let mut db = Database::default();
+        db.expect_get_graph()
+            .returning(|_root, _depth| Err(Error::not_found("Topic missing not found")));
+        let app = init_service(
+            App::new()
+                .service(get_graph)
+                .app_data(Data::new(db))
+                .app_data(Data::new(LinkedData::new("http://localhost:8000"))),
+        )
+        .await;
+        let req = TestRequest::with_uri("/?root=missing").to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    async fn test_whole_graph_needs_login() {
+        let mut db = Database::default();
+        db.expect_get_graph()
+            .withf(|root, _depth| root.is_some())
+            .returning(|_root, _depth| Ok(Graph::default()));
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(db))
+                .app_data(Data::new(LinkedData::new("http://localhost:8000")))
+                .service(
+                    scope("/graph")
+                        .service(get_graph)
+                        .wrap(from_fn(graph_middleware)),
+                ),
+        )
+        .await;
+
+        let req = TestRequest::with_uri("/graph/").to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let req = TestRequest::with_uri("/graph/?root=Mercy").to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,555 @@
+use actix_identity::Identity;
+use actix_web::web::{Data, ServiceConfig};
+use actix_web::{get, post, services, HttpResponse};
+use async_graphql::http::{playground_source, GraphQLPlaygroundConfig};
+use async_graphql::{
+    Context, EmptySubscription, Error as GError, ErrorExtensions, Object, OutputType, Result,
+    Schema, SimpleObject, Union,
+};
+use async_graphql_actix_web::{GraphQLRequest, GraphQLResponse};
+
+use crate::core::auth::AuthHandler;
+#[mockall_double::double]
+use crate::core::db::Database;
+use crate::models::auth::{Role, User};
+use crate::models::generic::{Cursor, Error, Page, Pagination};
+use crate::models::refs::{BRef, HRef, QRef, RefEnum};
+use crate::models::topics::{Search, Topic, TopicFilter};
+
+pub type TopicSchema = Schema<QueryRoot, MutationRoot, EmptySubscription>;
+
+pub fn schema() -> TopicSchema {
+    Schema::build(QueryRoot, MutationRoot, EmptySubscription).finish()
+}
+
+pub fn graphql_service(cfg: &mut ServiceConfig) {
+    cfg.service(services![graphql, playground]);
+}
+
+/// The logged in user making the request, if any.
+struct Viewer(Option<User>);
+
+#[post("/graphql")]
+async fn graphql(
+    schema: Data<TopicSchema>,
+    db: Data<Database>,
+    auth: Data<AuthHandler>,
+    id: Identity,
+    req: GraphQLRequest,
+) -> GraphQLResponse {
+    let user = match id.identity() {
+        Some(session) => auth.get_user(&db, session).await.ok(),
+        None => None,
+    };
+    schema
+        .execute(req.into_inner().data(db).data(Viewer(user)))
+        .await
+        .into()
+}
+
+#[get("/graphql")]
+async fn playground() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(playground_source(GraphQLPlaygroundConfig::new(
+            "/api/v1/graphql",
+        )))
+}
+
+fn db<'a>(ctx: &Context<'a>) -> Result<&'a Database> {
+    ctx.data::<Data<Database>>().map(|d| d.get_ref())
+}
+
+/// The logged in user, with at least the role, as on the REST routes.
+fn require_role<'a>(ctx: &Context<'a>, role: Role) -> Result<&'a User> {
+    let user = ctx
+        .data::<Viewer>()?
+        .0
+        .as_ref()
+        .ok_or_else(|| to_gql(Error::unauthorized("Not logged in!")))?;
+    if user.role < role {
+        return Err(to_gql(Error::forbidden(format!(
+            "Requires the {} role",
+            role.name()
+        ))));
+    }
+    Ok(user)
+}
+
+/// Keeps the error code of our errors in the GraphQL error extensions.
+fn to_gql(e: Error) -> GError {
+    let code = serde_json::to_value(e.kind()).unwrap_or_default();
+    GError::new(e.message()).extend_with(|_, ext| {
+        if let Some(code) = code.as_str() {
+            ext.set("code", code)
+        }
+    })
+}
+
+fn pagination(first: Option<u32>, after: Option<String>) -> Result<(u32, Option<Cursor>)> {
+    let p = Pagination {
+        cursor: after,
+        size: first.unwrap_or(50),
+    };
+    Ok((p.size(), p.cursor().map_err(to_gql)?))
+}
+
+/// A page of a listing, as `Page` on the REST routes.
+#[derive(SimpleObject)]
+#[graphql(concrete(name = "TopicConnection", params(TopicNode)))]
+#[graphql(concrete(name = "QRefConnection", params(QRefNode)))]
+struct Connection<T: OutputType> {
+    items: Vec<T>,
+    total: u64,
+    next_cursor: Option<String>,
+    prev_cursor: Option<String>,
+}
+
+impl<T: OutputType> Connection<T> {
+    fn from_page<I, F: Fn(I) -> T>(page: Page<I>, f: F) -> Self {
+        Connection {
+            items: page.items.into_iter().map(f).collect(),
+            total: page.total,
+            next_cursor: page.next_cursor,
+            prev_cursor: page.prev_cursor,
+        }
+    }
+}
+
+pub struct TopicNode {
+    key: String,
+    name: String,
+}
+
+impl TopicNode {
+    /// Listings only hold names, which are the keys of topics created by name.
+    fn from_name(name: String) -> Self {
+        TopicNode {
+            key: name.clone(),
+            name,
+        }
+    }
+}
+
+impl From<Topic> for TopicNode {
+    fn from(t: Topic) -> Self {
+        TopicNode {
+            key: t.key.unwrap_or_else(|| t.name.clone()),
+            name: t.name,
+        }
+    }
+}
+
+#[Object(name = "Topic")]
+impl TopicNode {
+    async fn key(&self) -> &str {
+        &self.key
+    }
+
+    async fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// All the Quran and Hadith references of the topic.
+    async fn refs(&self, ctx: &Context<'_>) -> Result<Vec<RefNode>> {
+        let refs = db(ctx)?.get_refs(&self.key).await.map_err(to_gql)?;
+        Ok(refs.into_iter().map(RefNode::from).collect())
+    }
+
+    /// A page of the Quran references of the topic, in mushaf order.
+    async fn qrefs(
+        &self,
+        ctx: &Context<'_>,
+        first: Option<u32>,
+        after: Option<String>,
+    ) -> Result<Connection<QRefNode>> {
+        let (size, cursor) = pagination(first, after)?;
+        let page = db(ctx)?
+            .get_qrefs(&self.key, size, cursor)
+            .await
+            .map_err(to_gql)?;
+        Ok(Connection::from_page(page, QRefNode))
+    }
+}
+
+pub struct QRefNode(QRef);
+
+#[Object(name = "QRef")]
+impl QRefNode {
+    async fn chapter(&self) -> usize {
+        self.0.chapter
+    }
+
+    async fn init_verse(&self) -> usize {
+        self.0.init_verse
+    }
+
+    async fn final_verse(&self) -> usize {
+        self.0.final_verse
+    }
+
+    /// The topics pointing at these verses.
+    async fn topics(
+        &self,
+        ctx: &Context<'_>,
+        first: Option<u32>,
+        after: Option<String>,
+    ) -> Result<Connection<TopicNode>> {
+        let (size, cursor) = pagination(first, after)?;
+        let page = db(ctx)?
+            .get_topics_from_qref(self.0.clone(), size, cursor)
+            .await
+            .map_err(to_gql)?;
+        Ok(Connection::from_page(page, TopicNode::from_name))
+    }
+}
+
+pub struct HRefNode(HRef);
+
+#[Object(name = "HRef")]
+impl HRefNode {
+    async fn collection(&self) -> &str {
+        &self.0.collection
+    }
+
+    async fn number(&self) -> &str {
+        &self.0.number
+    }
+
+    /// The topics pointing at this Hadith.
+    async fn topics(
+        &self,
+        ctx: &Context<'_>,
+        first: Option<u32>,
+        after: Option<String>,
+    ) -> Result<Connection<TopicNode>> {
+        let (size, cursor) = pagination(first, after)?;
+        let page = db(ctx)?
+            .get_topics_from_href(self.0.clone(), size, cursor)
+            .await
+            .map_err(to_gql)?;
+        Ok(Connection::from_page(page, TopicNode::from_name))
+    }
+}
+
+pub struct BRefNode(BRef);
+
+#[Object(name = "BRef")]
+impl BRefNode {
+    async fn isbn(&self) -> &str {
+        &self.0.isbn
+    }
+
+    async fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    async fn page(&self) -> i64 {
+        self.0.page
+    }
+}
+
+#[derive(Union)]
+#[graphql(name = "Ref")]
+pub enum RefNode {
+    Q(QRefNode),
+    H(HRefNode),
+    B(BRefNode),
+}
+
+impl From<RefEnum> for RefNode {
+    fn from(r: RefEnum) -> Self {
+        match r {
+            RefEnum::Q(q) => RefNode::Q(QRefNode(q)),
+            RefEnum::H(h) => RefNode::H(HRefNode(h)),
+            RefEnum::B(b) => RefNode::B(BRefNode(b)),
+        }
+    }
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// A page of topics, ordered by name.
+    async fn topics(
+        &self,
+        ctx: &Context<'_>,
+        first: Option<u32>,
+        after: Option<String>,
+    ) -> Result<Connection<TopicNode>> {
+        let (size, cursor) = pagination(first, after)?;
+        let page = db(ctx)?
+            .get_topics(size, cursor, TopicFilter::default())
+            .await
+            .map_err(to_gql)?;
+        Ok(Connection::from_page(page, TopicNode::from_name))
+    }
+
+    async fn topic(&self, ctx: &Context<'_>, key: String) -> Result<TopicNode> {
+        db(ctx)?
+            .get_topic(&key)
+            .await
+            .map(TopicNode::from)
+            .map_err(to_gql)
+    }
+
+    /// Topics matching a search term, most relevant first.
+    async fn search(
+        &self,
+        ctx: &Context<'_>,
+        q: String,
+        limit: Option<u32>,
+    ) -> Result<Vec<TopicNode>> {
+        let limit = limit.unwrap_or(10).min(Search::MAX_LIMIT);
+        let matches = db(ctx)?.search_topics(&q, limit).await.map_err(to_gql)?;
+        Ok(matches
+            .into_iter()
+            .map(|m| TopicNode {
+                key: m.key,
+                name: m.name,
+            })
+            .collect())
+    }
+
+    /// A verse, or a range of verses with `to`.
+    async fn verse(&self, chapter: usize, verse: usize, to: Option<usize>) -> Result<QRefNode> {
+        let qref = QRef {
+            chapter,
+            init_verse: verse,
+            final_verse: to.unwrap_or(verse),
+        };
+        qref.validate().map_err(to_gql)?;
+        Ok(QRefNode(qref))
+    }
+
+    async fn hadith(&self, collection: String, number: String) -> HRefNode {
+        HRefNode(HRef { collection, number })
+    }
+}
+
+pub struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+    async fn create_topic(&self, ctx: &Context<'_>, name: String) -> Result<TopicNode> {
+        let user = require_role(ctx, Role::Contributor)?;
+        let topic = Topic {
+            created_by: Some(user.email.clone()),
+            ..Topic::new(&name)
+        };
+        db(ctx)?.add_topic(topic).await.map_err(to_gql)?;
+        Ok(TopicNode::from_name(name))
+    }
+
+    async fn delete_topic(&self, ctx: &Context<'_>, key: String) -> Result<bool> {
+        require_role(ctx, Role::Admin)?;
+        db(ctx)?.delete_topic(&key).await.map_err(to_gql)?;
+        Ok(true)
+    }
+
+    async fn link_qref(
+        &self,
+        ctx: &Context<'_>,
+        topic: String,
+        chapter: usize,
+        init_verse: usize,
+        final_verse: usize,
+    ) -> Result<TopicNode> {
+        require_role(ctx, Role::Contributor)?;
+        let qref = QRef {
+            chapter,
+            init_verse,
+            final_verse,
+        };
+        qref.validate().map_err(to_gql)?;
+        db(ctx)?
+            .add_qref_to_topic(&topic, qref)
+            .await
+            .map_err(to_gql)?;
+        Ok(TopicNode::from_name(topic))
+    }
+
+    async fn link_href(
+        &self,
+        ctx: &Context<'_>,
+        topic: String,
+        collection: String,
+        number: String,
+    ) -> Result<TopicNode> {
+        require_role(ctx, Role::Contributor)?;
+        db(ctx)?
+            .add_href_to_topic(&topic, HRef { collection, number })
+            .await
+            .map_err(to_gql)?;
+        Ok(TopicNode::from_name(topic))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_graphql::Request;
+    use serde_json::json;
+
+    fn user(role: Role) -> User {
+        User {
+            name: "user".to_string(),
+            email: "user@example.com".to_string(),
+            role,
+        }
+    }
+
+    fn page<T>(items: Vec<T>) -> Page<T> {
+        Page {
+            total: items.len() as u64,
+            items,
+            next_cursor: None,
+            prev_cursor: None,
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_nested_traversal() {
+        let mut db = Database::default();
+        db.expect_get_topic()
+            .withf(|key| key == "mercy")
+            .returning(|key| Ok(Topic::new(key)));
+        db.expect_get_refs().returning(|_topic| {
+            Ok(vec![RefEnum::Q(QRef {
+                chapter: 1,
+                init_verse: 1,
+                final_verse: 1,
+            })])
+        });
+        db.expect_get_topics_from_qref()
+            .returning(|_qref, _size, _cursor| {
+                Ok(page(vec!["mercy".to_string(), "names".to_string()]))
+            });
+        let query = r#"{
+            topic(key: "mercy") {
+                name
+                refs { ... on QRef { chapter topics(first: 10) { items { name } total } } }
+            }
+        }"#;
+        let resp = schema()
+            .execute(Request::new(query).data(Data::new(db)).data(Viewer(None)))
+            .await;
+
+        assert!(resp.errors.is_empty(), "{:?}", resp.errors);
+        assert_eq!(
+            resp.data.into_json().unwrap(),
+            json!({
+                "topic": {
+                    "name": "mercy",
+                    "refs": [{
+                        "chapter": 1,
+                        "topics": { "items": [{ "name": "mercy" }, { "name": "names" }], "total": 2 }
+                    }]
+                }
+            })
+        );
+    }
+
+    /// The error code of the only error of the response.
+    fn error_code(resp: &async_graphql::Response) -> async_graphql::Value {
+        assert_eq!(resp.errors.len(), 1, "{:?}", resp.errors);
+        let ext = resp.errors[0].extensions.as_ref().unwrap();
+        ext.get("code").unwrap().clone()
+    }
+
+    #[actix_web::test]
+    async fn test_mutations_require_role() {
+        let cases = [
+            (
+                None,
+                r#"mutation { createTopic(name: "mercy") { key } }"#,
+                "unauthorized",
+            ),
+            (
+                Some(Role::Viewer),
+                r#"mutation { createTopic(name: "mercy") { key } }"#,
+                "forbidden",
+            ),
+            (
+                Some(Role::Editor),
+                r#"mutation { deleteTopic(key: "mercy") }"#,
+                "forbidden",
+            ),
+        ];
+        for (role, mutation, code) in cases {
+            let resp = schema()
+                .execute(
+                    Request::new(mutation)
+                        .data(Data::new(Database::default()))
+                        .data(Viewer(role.map(user))),
+                )
+                .await;
+
+            assert_eq!(
+                error_code(&resp),
+                async_graphql::Value::from(code),
+                "{}",
+                mutation
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_delete_topic_admin() {
+        let mut db = Database::default();
+        db.expect_delete_topic()
+            .withf(|key| key == "mercy")
+            .times(1)
+            .returning(|_| Ok(()));
+        let resp = schema()
+            .execute(
+                Request::new(r#"mutation { deleteTopic(key: "mercy") }"#)
+                    .data(Data::new(db))
+                    .data(Viewer(Some(user(Role::Admin)))),
+            )
+            .await;
+
+        assert!(resp.errors.is_empty(), "{:?}", resp.errors);
+    }
+
+    #[actix_web::test]
+    async fn test_verse_topics_public() {
+        let mut db = Database::default();
+        db.expect_get_topics_from_qref()
+            .withf(|qref, size, _cursor| qref.final_verse == 257 && *size == 5)
+            .returning(|_qref, _size, _cursor| Ok(page(vec!["Throne".to_string()])));
+        let resp = schema()
+            .execute(
+                Request::new("{ verse(chapter: 2, verse: 255, to: 257) { topics(first: 5) { items { key } } } }")
+                    .data(Data::new(db))
+                    .data(Viewer(None)),
+            )
+            .await;
+
+        assert!(resp.errors.is_empty(), "{:?}", resp.errors);
+        assert_eq!(
+            resp.data.into_json().unwrap(),
+            json!({ "verse": { "topics": { "items": [{ "key": "Throne" }] } } })
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_link_qref() {
+        let mut db = Database::default();
+        db.expect_add_qref_to_topic()
+            .withf(|topic, qref| topic == "mercy" && qref.chapter == 7)
+            .returning(|_topic, _qref| Ok(()));
+        let resp = schema()
+            .execute(
+                Request::new(
+                    r#"mutation { linkQref(topic: "mercy", chapter: 7, initVerse: 156, finalVerse: 156) { key } }"#,
+                )
+                .data(Data::new(db))
+                .data(Viewer(Some(user(Role::Contributor)))),
+            )
+            .await;
+
+        assert!(resp.errors.is_empty(), "{:?}", resp.errors);
+    }
+}
@@ -0,0 +1,198 @@
+use crate::core::auth::AuthHandler;
+use actix_identity::Identity;
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::web::{scope, Bytes, Data, Json, PayloadConfig, Query, ServiceConfig};
+use actix_web::{post, HttpRequest};
+use actix_web_lab::middleware::from_fn;
+
+use super::topics::current_email;
+#[mockall_double::double]
+use crate::core::db::Database;
+use crate::core::import;
+use crate::models::generic::Error;
+use crate::models::import::{ImportFormat, ImportParams, ImportReport};
+
+/// Spreadsheets of a few thousand rows fit comfortably.
+const MAX_IMPORT_SIZE: usize = 8 * 1024 * 1024;
+
+pub fn import_service(cfg: &mut ServiceConfig) {
+    cfg.service(
+        scope("/import")
+            .app_data(PayloadConfig::new(MAX_IMPORT_SIZE))
+            .service(import_file)
+            .wrap(from_fn(AuthHandler::editor_middleware)),
+    );
+}
+
+/// Import a CSV or TSV file with `topic`, `citation` and `note` columns.
+/// Missing topics are created and importing the same file twice changes nothing.
+#[utoipa::path(
+    context_path = "/api/v1/import",
+    tag = "import",
+    params(ImportParams),
+    request_body(content = String, description = "The file, with a header row", content_type = "text/csv"),
+    responses(
+        (status = 200, description = "What was imported, with the line of each row that failed", body = ImportReport),
+        (status = 400, description = "The header is missing the topic or citation column", body = Error),
+        (status = 401, description = "Not logged in"),
+        (status = 403, description = "Not an editor", body = Error),
+    ),
+    security(("session" = []), ("token" = []))
+)]
+#[post("/")]
+async fn import_file(
+    body: Bytes,
+    params: Query<ImportParams>,
+    req: HttpRequest,
+    db: Data<Database>,
+    auth: Data<AuthHandler>,
+    id: Identity,
+) -> Result<Json<ImportReport>, Error> {
+    let format = params
+        .format
+        .unwrap_or_else(|| match req.headers().get(CONTENT_TYPE) {
+            Some(t) if t.as_bytes().starts_with(b"text/tab-separated-values") => ImportFormat::Tsv,
+            _ => ImportFormat::Csv,
+        });
+    let user = current_email(&auth, &db, &id).await;
+    import::run(&db, &body, format, user, params.dry_run)
+        .await
+        .map(Json)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::import::{ImportRow, RowError};
+    use crate::models::refs::{HRef, QRef, RefEnum};
+    use actix_service::Service;
+    use actix_web::{
+        http::StatusCode,
+        test,
+        test::{init_service, read_body_json, TestRequest},
+        App,
+    };
+
+    fn auth_handler() -> AuthHandler {
+        AuthHandler::new("http://localhost".to_string())
+    }
+
+    #[test]
+    async fn test_import() {
+        let mut db = Database::default();
+        db.expect_import_rows()
+            .withf(|rows, _user, dry_run| {
+                !dry_run
+                    && rows
+                        == &vec![
+                            ImportRow {
+                                line: 2,
+                                topic: "Mercy".to_string(),
+                                reference: RefEnum::Q(QRef {
+                                    chapter: 7,
+                                    init_verse: 156,
+                                    final_verse: 156,
+                                }),
+                                note: Some("Encompasses all things".to_string()),
+                            },
+                            ImportRow {
+                                line: 4,
+                                topic: "Intention".to_string(),
+                                reference: RefEnum::H(HRef {
+                                    collection: "bukhari".to_string(),
+                                    number: "1".to_string(),
+                                }),
+                                note: None,
+                            },
+                        ]
+            })
+            .returning(|_rows, _user, dry_run| {
+                Ok(ImportReport {
+                    dry_run,
+                    topics_created: 2,
+                    refs_linked: 2,
+                    ..Default::default()
+                })
+            });
+        let app = init_service(
+            App::new()
+                .service(import_file)
+                .app_data(Data::new(db))
+                .app_data(Data::new(auth_handler())),
+        )
+        .await;
+        let csv = "Topic,Citation,Note\n\
+                   Mercy,7:156,Encompasses all things\n\
+                   Mercy,7:999-1\n\
+                   Intention,Bukhari 1,\n";
+        let req = TestRequest::post()
+            .uri("/")
+            .insert_header((CONTENT_TYPE, "text/csv"))
+            .set_payload(csv)
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: ImportReport = read_body_json(resp).await;
+        assert_eq!(body.rows, 3);
+        assert_eq!(body.topics_created, 2);
+        assert_eq!(
+            body.errors,
+            vec![RowError {
+                line: 3,
+                message: "Final verse is before initial verse".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    async fn test_import_tsv_dry_run() {
+        let mut db = Database::default();
+        db.expect_import_rows()
+            .withf(|rows, _user, dry_run| *dry_run && rows.len() == 1)
+            .returning(|_rows, _user, dry_run| {
+                Ok(ImportReport {
+                    dry_run,
+                    unchanged: 1,
+                    ..Default::default()
+                })
+            });
+        let app = init_service(
+            App::new()
+                .service(import_file)
+                .app_data(Data::new(db))
+                .app_data(Data::new(auth_handler())),
+        )
+        .await;
+        let req = TestRequest::post()
+            .uri("/?dry_run=true")
+            .insert_header((CONTENT_TYPE, "text/tab-separated-values"))
+            .set_payload("citation\ttopic\nQuran 2:255\tThrone\n")
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: ImportReport = read_body_json(resp).await;
+        assert!(body.dry_run);
+        assert_eq!(body.unchanged, 1);
+    }
+
+    #[test]
+    async fn test_import_missing_column() {
+        let db = Database::default();
+        let app = init_service(
+            App::new()
+                .service(import_file)
+                .app_data(Data::new(db))
+                .app_data(Data::new(auth_handler())),
+        )
+        .await;
+        let req = TestRequest::post()
+            .uri("/")
+            .set_payload("topic,verse\nMercy,7:156\n")
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -1,4 +1,34 @@
 pub mod auth;
+pub mod batch;
+pub mod docs;
+pub mod events;
+pub mod export;
+pub mod graph;
+pub mod graphql;
+pub mod import;
 pub mod refs;
 pub mod root;
 pub mod topics;
+pub mod users;
+pub mod webhooks;
+
+use actix_web::web::{scope, ServiceConfig};
+
+/// All the API routes, under `/api/v1`.
+pub fn api_service(cfg: &mut ServiceConfig) {
+    cfg.service(
+        scope("/api/v1")
+            .configure(topics::topics_service)
+            .configure(refs::refs_service)
+            .configure(batch::batch_service)
+            .configure(import::import_service)
+            .configure(export::export_service)
+            .configure(graph::graph_service)
+            .configure(events::events_service)
+            .configure(webhooks::webhooks_service)
+            .configure(users::users_service)
+            .configure(auth::auth_service)
+            .configure(graphql::graphql_service)
+            .configure(root::root_service),
+    );
+}
@@ -1,55 +1,177 @@
+use crate::core::auth::AuthHandler;
 use actix_web::web::{scope, Data, Json, Path, Query, ServiceConfig};
-use actix_web::{get, post, services, Result};
+use actix_web::{get, post, services, Either, HttpRequest, HttpResponse, Result};
+use actix_web_lab::middleware::from_fn;
 
 #[mockall_double::double]
 use crate::core::db::Database;
-use crate::models::generic::{Generic, Pagination};
-use crate::models::refs::{HRef, QRef, RefEnum};
+use crate::core::linked::{self, LinkedData};
+use crate::models::generic::{Error, Generic, List, Page, Pagination};
+use crate::models::refs::{
+    HRef, HadithPath, Lookup, QRef, RefEnum, RefTopics, VersePath, VerseRange,
+};
 
 pub fn refs_service(cfg: &mut ServiceConfig) {
-    cfg.service(scope("/refs").service(services![
-        get_references,
-        add_qref,
-        get_qrefs,
-        get_topics_for_href,
-        get_topics_for_qref
-    ]));
+    // Lookups are reads, however they are posted
+    cfg.service(
+        scope("/refs").service(lookup).service(
+            scope("")
+                .service(services![get_references, add_qref, get_qrefs, add_href])
+                .wrap(from_fn(AuthHandler::curation_middleware)),
+        ),
+    )
+    .service(scope("/verses").service(get_topics_for_verse))
+    .service(scope("/hadith").service(get_topics_for_hadith));
 }
 
-#[get("/qref")]
-async fn get_topics_for_qref(
-    qref: Json<QRef>,
+#[utoipa::path(
+    context_path = "/api/v1/verses",
+    tag = "refs",
+    params(
+        ("chapter" = usize, Path, description = "The chapter (surah)"),
+        ("verse" = usize, Path, description = "The verse (ayah)"),
+        VerseRange,
+        Pagination,
+    ),
+    responses(
+        (status = 200, description = "A page of the topics pointing at the verses", body = TopicPage, content_type = ["application/json", "application/ld+json", "text/csv", "application/x-ndjson"]),
+        (status = 400, description = "Invalid verse", body = Error),
+    )
+)]
+#[get("/{chapter}/{verse}/topics")]
+async fn get_topics_for_verse(
+    verse: Path<VersePath>,
+    range: Query<VerseRange>,
     q: Query<Pagination>,
+    req: HttpRequest,
     db: Data<Database>,
-) -> Result<Json<Vec<String>>> {
-    db.get_ref()
-        .get_topics_from_qref(qref.0, q.page, q.size)
-        .await
-        .map_err(Into::into)
-        .map(Json)
+) -> Result<Either<Page<String>, HttpResponse>> {
+    let qref = QRef {
+        chapter: verse.chapter,
+        init_verse: verse.verse,
+        final_verse: range.to.unwrap_or(verse.verse),
+    };
+    qref.validate()?;
+    let page = db
+        .get_topics_from_qref(qref.clone(), q.size(), q.cursor()?)
+        .await?;
+    referenced_by(&req, RefEnum::Q(qref), page)
 }
 
-#[get("/href")]
-async fn get_topics_for_href(
-    href: Json<HRef>,
+/// With `Accept: application/ld+json`, a page of topics pointing at a reference
+/// is the reference with the topics as `dcterms:isReferencedBy`.
+fn referenced_by(
+    req: &HttpRequest,
+    r: RefEnum,
+    page: Page<String>,
+) -> Result<Either<Page<String>, HttpResponse>> {
+    if !linked::wants_json_ld(req) {
+        return Ok(Either::Left(page));
+    }
+    let doc = LinkedData::from_request(req)?.referenced_by(&r, &page.items);
+    Ok(Either::Right(LinkedData::respond(doc)))
+}
+
+#[utoipa::path(
+    context_path = "/api/v1/hadith",
+    tag = "refs",
+    params(
+        ("collection" = String, Path, description = "The Hadith collection"),
+        ("number" = String, Path, description = "The Hadith number in the collection"),
+        Pagination,
+    ),
+    responses(
+        (status = 200, description = "A page of the topics pointing at the Hadith", body = TopicPage, content_type = ["application/json", "application/ld+json", "text/csv", "application/x-ndjson"]),
+    )
+)]
+#[get("/{collection}/{number}/topics")]
+async fn get_topics_for_hadith(
+    hadith: Path<HadithPath>,
     q: Query<Pagination>,
+    req: HttpRequest,
     db: Data<Database>,
-) -> Result<Json<Vec<String>>> {
-    db.get_ref()
-        .get_topics_from_href(href.0, q.page, q.size)
+) -> Result<Either<Page<String>, HttpResponse>> {
+    let hadith = hadith.into_inner();
+    let href = HRef {
+        collection: hadith.collection,
+        number: hadith.number,
+    };
+    let page = db
+        .get_topics_from_href(href.clone(), q.size(), q.cursor()?)
+        .await?;
+    referenced_by(&req, RefEnum::H(href), page)
+}
+
+#[utoipa::path(
+    context_path = "/api/v1/refs",
+    tag = "refs",
+    request_body = Lookup,
+    responses(
+        (status = 200, description = "The topics of each reference, in request order", body = [RefTopics]),
+        (status = 400, description = "Too many or invalid references", body = Error),
+    )
+)]
+#[post("/lookup")]
+async fn lookup(refs: Json<Lookup>, db: Data<Database>) -> Result<Json<Vec<RefTopics>>> {
+    if refs.refs.len() > Lookup::MAX_REFS {
+        return Err(Error::validation(format!(
+            "At most {} references can be looked up at once",
+            Lookup::MAX_REFS
+        ))
+        .into());
+    }
+    for r in refs.refs.iter() {
+        if let RefEnum::Q(qref) = r {
+            qref.validate()?;
+        }
+    }
+    db.lookup_topics(refs.into_inner().refs)
         .await
-        .map_err(Into::into)
         .map(Json)
+        .map_err(Into::into)
 }
 
+#[utoipa::path(
+    context_path = "/api/v1/refs",
+    tag = "refs",
+    params(("topic" = String, Path, description = "The key of the topic")),
+    responses(
+        (status = 200, description = "The Quran and Hadith references of the topic", body = [RefEnum], content_type = ["application/json", "application/ld+json", "text/csv", "application/x-ndjson"]),
+    )
+)]
 #[get("/{topic}")]
-async fn get_references(topic: Path<String>, db: Data<Database>) -> Result<Json<Vec<RefEnum>>> {
-    db.get_refs(&topic)
-        .await
-        .map(|v| Json(v.into_iter().filter(|r| !r.is_book()).collect()))
-        .map_err(Into::into)
+async fn get_references(
+    topic: Path<String>,
+    req: HttpRequest,
+    db: Data<Database>,
+) -> Result<Either<List<RefEnum>, HttpResponse>> {
+    let refs: Vec<RefEnum> = db
+        .get_refs(&topic)
+        .await?
+        .into_iter()
+        .filter(|r| !r.is_book())
+        .collect();
+    if !linked::wants_json_ld(&req) {
+        return Ok(Either::Left(List(refs)));
+    }
+    let doc = LinkedData::from_request(&req)?.topic_refs(&topic, &refs);
+    Ok(Either::Right(LinkedData::respond(doc)))
 }
 
+#[utoipa::path(
+    context_path = "/api/v1/refs",
+    tag = "refs",
+    params(("topic" = String, Path, description = "The key of the topic")),
+    request_body = QRef,
+    responses(
+        (status = 200, description = "Added the Quran reference", body = Generic),
+        (status = 400, description = "Invalid reference", body = Error),
+        (status = 401, description = "Not logged in"),
+        (status = 403, description = "Not a contributor", body = Error),
+        (status = 404, description = "No such topic", body = Error),
+    ),
+    security(("session" = []), ("token" = []))
+)]
 #[post("/{topic}/qref")]
 async fn add_qref(topic: Path<String>, qref: Json<QRef>, db: Data<Database>) -> Result<Generic> {
     qref.validate()?;
@@ -59,18 +181,41 @@ async fn add_qref(topic: Path<String>, qref: Json<QRef>, db: Data<Database>) ->
         .map_err(Into::into)
 }
 
+#[utoipa::path(
+    context_path = "/api/v1/refs",
+    tag = "refs",
+    params(
+        ("topic" = String, Path, description = "The key of the topic"),
+        Pagination,
+    ),
+    responses(
+        (status = 200, description = "A page of the Quran references of the topic, in mushaf order", body = QRefPage, content_type = ["application/json", "text/csv", "application/x-ndjson"]),
+    )
+)]
 #[get("/{topic}/qref")]
 async fn get_qrefs(
     topic: Path<String>,
     q: Query<Pagination>,
     db: Data<Database>,
-) -> Result<Json<Vec<QRef>>> {
-    db.get_qrefs(&topic, q.page, q.size)
+) -> Result<Page<QRef>> {
+    db.get_qrefs(&topic, q.size(), q.cursor()?)
         .await
-        .map(Json)
         .map_err(Into::into)
 }
 
+#[utoipa::path(
+    context_path = "/api/v1/refs",
+    tag = "refs",
+    params(("topic" = String, Path, description = "The key of the topic")),
+    request_body = HRef,
+    responses(
+        (status = 200, description = "Added the Hadith reference", body = Generic),
+        (status = 401, description = "Not logged in"),
+        (status = 403, description = "Not a contributor", body = Error),
+        (status = 404, description = "No such topic", body = Error),
+    ),
+    security(("session" = []), ("token" = []))
+)]
 #[post("/{topic}/href")]
 async fn add_href(topic: Path<String>, href: Json<HRef>, db: Data<Database>) -> Result<Generic> {
     db.add_href_to_topic(topic.as_str(), href.0)
@@ -82,7 +227,6 @@ async fn add_href(topic: Path<String>, href: Json<HRef>, db: Data<Database>) ->
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::models::generic::Error;
     use actix_service::Service;
     use actix_web::{
         http::StatusCode,
@@ -91,7 +235,7 @@ mod test {
         App,
     };
     use aragog::error::Error as AError;
-    use serde_json::to_string;
+    use serde_json::{to_string, Value};
 
     #[test]
     async fn test_get_refs() {
@@ -107,6 +251,276 @@ mod test {
         assert!(body.is_empty());
     }
 
+    #[test]
+    async fn test_get_refs_csv() {
+        let mut db = Database::default();
+        db.expect_get_refs().returning(|_topic| {
+            Ok(vec![
+                RefEnum::Q(QRef {
+                    chapter: 2,
+                    init_verse: 255,
+                    final_verse: 255,
+                }),
+                RefEnum::H(HRef {
+                    collection: "bukhari".to_string(),
+                    number: "1".to_string(),
+                }),
+            ])
+        });
+        let app = init_service(App::new().service(get_references).app_data(Data::new(db))).await;
+        let req = TestRequest::with_uri("/topic1")
+            .insert_header(("Accept", "text/csv"))
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = read_body(resp).await;
+        assert_eq!(body, "type,citation\nqref,2:255\nhref,bukhari 1\n");
+    }
+
+    fn page(items: Vec<String>) -> Page<String> {
+        Page {
+            total: items.len() as u64,
+            items,
+            next_cursor: None,
+            prev_cursor: None,
+        }
+    }
+
+    #[test]
+    async fn test_get_topics_for_verse() {
+        let mut db = Database::default();
+        db.expect_get_topics_from_qref()
+            .withf(|qref, _size, _cursor| {
+                qref == &QRef {
+                    chapter: 2,
+                    init_verse: 255,
+                    final_verse: 257,
+                }
+            })
+            .returning(|_qref, _size, _cursor| Ok(page(vec!["Throne".to_string()])));
+        let app = init_service(
+            App::new()
+                .service(scope("/verses").service(get_topics_for_verse))
+                .app_data(Data::new(db)),
+        )
+        .await;
+        let req = TestRequest::with_uri("/verses/2/255/topics?to=257").to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Page<String> = read_body_json(resp).await;
+        assert_eq!(body.items, vec!["Throne".to_string()]);
+    }
+
+    #[test]
+    async fn test_get_topics_for_verse_ndjson() {
+        let mut db = Database::default();
+        db.expect_get_topics_from_qref()
+            .returning(|_qref, _size, _cursor| {
+                Ok(page(vec!["Throne".to_string(), "Knowledge".to_string()]))
+            });
+        let app = init_service(
+            App::new()
+                .service(scope("/verses").service(get_topics_for_verse))
+                .app_data(Data::new(db)),
+        )
+        .await;
+        let req = TestRequest::with_uri("/verses/2/255/topics")
+            .insert_header(("Accept", "application/x-ndjson"))
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("Content-Type").unwrap(),
+            "application/x-ndjson"
+        );
+        let body = read_body(resp).await;
+        assert_eq!(body, "\"Throne\"\n\"Knowledge\"\n");
+    }
+
+    #[test]
+    async fn test_get_topics_for_verse_invalid() {
+        let db = Database::default();
+        let app = init_service(
+            App::new()
+                .service(scope("/verses").service(get_topics_for_verse))
+                .app_data(Data::new(db)),
+        )
+        .await;
+        let req = TestRequest::with_uri("/verses/2/255/topics?to=1").to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    async fn test_get_topics_for_verse_json_ld() {
+        let mut db = Database::default();
+        db.expect_get_topics_from_qref()
+            .returning(|_qref, _size, _cursor| Ok(page(vec!["Throne".to_string()])));
+        let app = init_service(
+            App::new()
+                .service(scope("/verses").service(get_topics_for_verse))
+                .app_data(Data::new(db))
+                .app_data(Data::new(LinkedData::new("https://example.org"))),
+        )
+        .await;
+        let req = TestRequest::with_uri("/verses/2/255/topics")
+            .insert_header(("Accept", "application/ld+json, application/json;q=0.5"))
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("content-type").unwrap(),
+            "application/ld+json"
+        );
+        let body: Value = read_body_json(resp).await;
+        assert_eq!(body["@id"], "https://example.org/api/v1/verses/2/255");
+        assert_eq!(
+            body["dcterms:isReferencedBy"],
+            serde_json::json!([{ "@id": "https://example.org/api/v1/topics/Throne" }])
+        );
+    }
+
+    #[test]
+    async fn test_get_topics_for_hadith() {
+        let mut db = Database::default();
+        db.expect_get_topics_from_href()
+            .withf(|href, _size, _cursor| href.collection == "bukhari" && href.number == "1")
+            .returning(|_href, _size, _cursor| Ok(page(vec!["Intention".to_string()])));
+        let app = init_service(
+            App::new()
+                .service(scope("/hadith").service(get_topics_for_hadith))
+                .app_data(Data::new(db)),
+        )
+        .await;
+        let req = TestRequest::with_uri("/hadith/bukhari/1/topics").to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Page<String> = read_body_json(resp).await;
+        assert_eq!(body.items, vec!["Intention".to_string()]);
+    }
+
+    #[test]
+    async fn test_lookup() {
+        let qref = RefEnum::Q(QRef {
+            chapter: 1,
+            init_verse: 1,
+            final_verse: 7,
+        });
+        let href = RefEnum::H(HRef {
+            collection: "muslim".to_string(),
+            number: "8".to_string(),
+        });
+        let mut db = Database::default();
+        db.expect_lookup_topics()
+            .withf(|refs| refs.len() == 2)
+            .returning(|refs| {
+                Ok(refs
+                    .into_iter()
+                    .map(|reference| RefTopics {
+                        reference,
+                        topics: vec!["Prayer".to_string()],
+                    })
+                    .collect())
+            });
+        let app = init_service(App::new().service(lookup).app_data(Data::new(db))).await;
+        let req = TestRequest::post()
+            .uri("/lookup")
+            .set_json(&Lookup {
+                refs: vec![qref.clone(), href.clone()],
+            })
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Vec<RefTopics> = read_body_json(resp).await;
+        assert_eq!(body.len(), 2);
+        assert_eq!(body[0].reference, qref);
+        assert_eq!(body[1].reference, href);
+    }
+
+    #[test]
+    async fn test_lookup_too_many() {
+        let db = Database::default();
+        let app = init_service(App::new().service(lookup).app_data(Data::new(db))).await;
+        let refs = (0..=Lookup::MAX_REFS)
+            .map(|i| {
+                RefEnum::Q(QRef {
+                    chapter: 2,
+                    init_verse: i,
+                    final_verse: i,
+                })
+            })
+            .collect();
+        let req = TestRequest::post()
+            .uri("/lookup")
+            .set_json(&Lookup { refs })
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    async fn test_get_qrefs() {
+        let mut db = Database::default();
+        db.expect_get_qrefs()
+            .withf(|topic, size, cursor| topic == "topic1" && *size == 10 && cursor.is_none())
+            .returning(|_topic, _size, _cursor| {
+                Ok(Page {
+                    items: vec![QRef {
+                        chapter: 2,
+                        init_verse: 255,
+                        final_verse: 255,
+                    }],
+                    total: 11,
+                    next_cursor: Some("next".to_string()),
+                    prev_cursor: None,
+                })
+            });
+        let app = init_service(App::new().service(get_qrefs).app_data(Data::new(db))).await;
+        let req = TestRequest::with_uri("/topic1/qref?size=10").to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Page<QRef> = read_body_json(resp).await;
+        assert_eq!(body.total, 11);
+        assert_eq!(body.items.len(), 1);
+        assert_eq!(body.next_cursor, Some("next".to_string()));
+    }
+
+    #[test]
+    async fn test_get_qrefs_csv() {
+        let mut db = Database::default();
+        db.expect_get_qrefs().returning(|_topic, _size, _cursor| {
+            Ok(Page {
+                items: vec![QRef {
+                    chapter: 7,
+                    init_verse: 156,
+                    final_verse: 157,
+                }],
+                total: 1,
+                next_cursor: None,
+                prev_cursor: None,
+            })
+        });
+        let app = init_service(App::new().service(get_qrefs).app_data(Data::new(db))).await;
+        let req = TestRequest::with_uri("/topic1/qref")
+            .insert_header(("Accept", "text/csv"))
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().get("Link").is_none());
+        let body = read_body(resp).await;
+        assert_eq!(body, "chapter,init_verse,final_verse\n7,156,157\n");
+    }
+
     #[test]
     async fn test_add_qref() {
         let mut db = Database::default();
@@ -136,7 +550,7 @@ mod test {
     async fn test_add_qref_invalid_topic() {
         let mut db = Database::default();
         let e = || {
-            Err(Error::default(AError::NotFound {
+            Err(Error::from(AError::NotFound {
                 item: "".to_string(),
                 id: "".to_string(),
                 source: None,
@@ -156,11 +570,55 @@ mod test {
             .to_request();
         let resp = app.call(req).await.unwrap();
 
-        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
         let b = read_body(resp).await;
         assert_eq!(b, to_string(&e().err()).unwrap());
     }
 
+    #[test]
+    async fn test_add_qref_invalid_chapter() {
+        let db = Database::default();
+        let app = init_service(App::new().service(add_qref).app_data(Data::new(db))).await;
+        let qref = QRef {
+            chapter: 115,
+            init_verse: 1,
+            final_verse: 1,
+        };
+        let req = TestRequest::post()
+            .uri("/topic1/qref")
+            .set_json(&qref)
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let b: Value = read_body_json(resp).await;
+        assert_eq!(b["code"], "validation");
+        assert_eq!(b["message"], "Invalid chapter number");
+    }
+
+    #[test]
+    async fn test_add_qref_storage_error_hidden() {
+        let mut db = Database::default();
+        db.expect_add_qref_to_topic()
+            .returning(|_topic, _qref| Err(Error::storage("connection refused to 10.0.0.1")));
+        let app = init_service(App::new().service(add_qref).app_data(Data::new(db))).await;
+        let qref = QRef {
+            chapter: 1,
+            init_verse: 1,
+            final_verse: 1,
+        };
+        let req = TestRequest::post()
+            .uri("/topic1/qref")
+            .set_json(&qref)
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let b: Value = read_body_json(resp).await;
+        assert_eq!(b["code"], "storage");
+        assert!(!b.to_string().contains("10.0.0.1"));
+    }
+
     #[test]
     async fn test_add_href() {
         let mut db = Database::default();
@@ -189,7 +647,7 @@ mod test {
     async fn test_add_href_invalid_topic() {
         let mut db = Database::default();
         let e = || {
-            Err(Error::default(AError::NotFound {
+            Err(Error::from(AError::NotFound {
                 item: "topic1".to_string(),
                 id: "Topic/topic1".to_string(),
                 source: None,
@@ -208,7 +666,7 @@ mod test {
             .to_request();
         let resp = app.call(req).await.unwrap();
 
-        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
         let b = read_body(resp).await;
         assert_eq!(b, to_string(&e().err()).unwrap());
     }
@@ -11,6 +11,14 @@ pub fn root_service(cfg: &mut ServiceConfig) {
     cfg.service(scope("").service(services![health, root]));
 }
 
+#[utoipa::path(
+    context_path = "/api/v1",
+    tag = "root",
+    responses(
+        (status = 200, description = "Healthy server", body = Health),
+        (status = 500, description = "Unhealthy server", body = Health),
+    )
+)]
 #[get("/healthz")]
 async fn health(db: Data<Database>) -> Result<Health, Health> {
     let mut health_status: Vec<HealthStatus> = vec![];
@@ -34,6 +42,11 @@ async fn health(db: Data<Database>) -> Result<Health, Health> {
     }
 }
 
+#[utoipa::path(
+    context_path = "/api/v1",
+    tag = "root",
+    responses((status = 200, description = "Nothing to see here!", body = String, content_type = "text/plain"))
+)]
 #[get("/")]
 async fn root() -> impl Responder {
     "Nothing to see here!"
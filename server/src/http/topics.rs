@@ -1,39 +1,200 @@
 use crate::core::auth::AuthHandler;
-use actix_web::web::{scope, Data, Json, Query, ServiceConfig};
-use actix_web::{delete, get, http::StatusCode, post, services, Result};
+use crate::core::linked::{self, LinkedData};
+use actix_identity::Identity;
+use actix_web::http::header::{IF_NONE_MATCH, LOCATION};
+use actix_web::web::{scope, Data, Json, Path, Query, ServiceConfig};
+use actix_web::{delete, get, post, put, services, Either, HttpRequest, HttpResponse, Result};
 use actix_web_lab::middleware::from_fn;
 
 #[mockall_double::double]
 use crate::core::db::Database;
-use crate::models::generic::{Error, Generic, Pagination};
-use crate::models::topics::Topic;
+use crate::models::generic::{Error, Generic, Page, Pagination};
+use crate::models::topics::{Search, Topic, TopicFilter, TopicMatch};
 
 pub fn topics_service(cfg: &mut ServiceConfig) {
     cfg.service(
         scope("/topics")
-            .service(services![get_topics, add_topic, delete_topic])
-            .wrap(from_fn(AuthHandler::auth_middleware)),
+            .service(services![
+                get_topics,
+                search_topics,
+                get_topic,
+                add_topic,
+                put_topic,
+                delete_topic
+            ])
+            .wrap(from_fn(AuthHandler::curation_middleware)),
     );
 }
 
+#[utoipa::path(
+    context_path = "/api/v1/topics",
+    tag = "topics",
+    params(Pagination, TopicFilter),
+    responses(
+        (status = 200, description = "A page of topic names", body = TopicPage, content_type = ["application/json", "text/csv", "application/x-ndjson"]),
+        (status = 400, description = "Invalid query or cursor", body = Error),
+    )
+)]
 #[get("/")]
-async fn get_topics(db: Data<Database>, q: Query<Pagination>) -> Result<Json<Vec<String>>, Error> {
-    db.get_topics(q.page, q.size).await.map(Json).map_err(|e| {
-        Error::new(
-            format!("Database error: {:?}", e),
-            StatusCode::INTERNAL_SERVER_ERROR,
-        )
-    })
+async fn get_topics(
+    db: Data<Database>,
+    q: Query<Pagination>,
+    filter: Query<TopicFilter>,
+) -> Result<Page<String>, Error> {
+    db.get_topics(q.size(), q.cursor()?, filter.into_inner())
+        .await
+}
+
+#[utoipa::path(
+    context_path = "/api/v1/topics",
+    tag = "topics",
+    params(Search),
+    responses(
+        (status = 200, description = "Matching topics, most relevant first", body = [TopicMatch]),
+        (status = 400, description = "Empty search query", body = Error),
+    )
+)]
+#[get("/search")]
+async fn search_topics(
+    db: Data<Database>,
+    q: Query<Search>,
+) -> Result<Json<Vec<TopicMatch>>, Error> {
+    if q.q.trim().is_empty() {
+        return Err(Error::validation("Search query must not be empty"));
+    }
+    db.search_topics(&q.q, q.limit.min(Search::MAX_LIMIT))
+        .await
+        .map(Json)
 }
 
+/// A topic, or with `Accept: application/ld+json` the topic as a SKOS concept.
+#[utoipa::path(
+    context_path = "/api/v1/topics",
+    tag = "topics",
+    params(("key" = String, Path, description = "The key of the topic")),
+    responses(
+        (status = 200, description = "The topic", body = Topic, content_type = ["application/json", "application/ld+json"]),
+        (status = 404, description = "No such topic", body = Error),
+    )
+)]
+#[get("/{key}")]
+async fn get_topic(
+    key: Path<String>,
+    req: HttpRequest,
+    db: Data<Database>,
+) -> Result<Either<Json<Topic>, HttpResponse>, Error> {
+    let topic = db.get_topic(&key).await?;
+    if !linked::wants_json_ld(&req) {
+        return Ok(Either::Left(Json(topic)));
+    }
+    let children = db.get_topic_children(&key).await?;
+    let refs = db.get_refs(&key).await?;
+    let doc = LinkedData::from_request(&req)?.concept(&topic, &children, &refs);
+    Ok(Either::Right(LinkedData::respond(doc)))
+}
+
+/// The email of the logged in user, if any.
+pub(crate) async fn current_email(
+    auth: &AuthHandler,
+    db: &Database,
+    id: &Identity,
+) -> Option<String> {
+    match id.identity() {
+        Some(session) => auth.get_user(db, session).await.ok().map(|u| u.email),
+        None => None,
+    }
+}
+
+#[utoipa::path(
+    context_path = "/api/v1/topics",
+    tag = "topics",
+    request_body = Topic,
+    responses(
+        (status = 200, description = "Created the topic", body = Generic),
+        (status = 401, description = "Not logged in"),
+        (status = 403, description = "Not a contributor", body = Error),
+        (status = 409, description = "The name is taken, `details.key` holds the existing key", body = Error),
+    ),
+    security(("session" = []), ("token" = []))
+)]
 #[post("/")]
-async fn add_topic(topic: Json<Topic>, db: Data<Database>) -> Result<Generic> {
-    db.add_topic(topic.name.as_str())
+async fn add_topic(
+    topic: Json<Topic>,
+    db: Data<Database>,
+    auth: Data<AuthHandler>,
+    id: Identity,
+) -> Result<Generic> {
+    let topic = Topic {
+        created_by: current_email(&auth, &db, &id).await,
+        ..topic.into_inner()
+    };
+    let name = topic.name.clone();
+    db.add_topic(topic)
         .await
-        .map(|_| Generic::new(format!("Successfully created {}", topic.name)))
-        .map_err(|e| Error::new(e, StatusCode::INTERNAL_SERVER_ERROR).into())
+        .map(|_| Generic::new(format!("Successfully created {}", name)))
+        .map_err(Into::into)
 }
 
+/// Create or replace a topic under a client chosen key.
+/// With `If-None-Match: *` an existing topic is never replaced, so creation can be retried safely.
+#[utoipa::path(
+    context_path = "/api/v1/topics",
+    tag = "topics",
+    params(
+        ("key" = String, Path, description = "The key of the topic"),
+        ("If-None-Match" = Option<String>, Header, description = "`*` to only create the topic, never replace it"),
+    ),
+    request_body = Topic,
+    responses(
+        (status = 200, description = "Replaced the topic", body = Generic),
+        (status = 201, description = "Created the topic", body = Generic),
+        (status = 401, description = "Not logged in"),
+        (status = 403, description = "Not an editor", body = Error),
+        (status = 409, description = "Another topic has this name", body = Error),
+        (status = 412, description = "The topic exists and `If-None-Match: *` was set", body = Error),
+    ),
+    security(("session" = []), ("token" = []))
+)]
+#[put("/{key}")]
+async fn put_topic(
+    key: Path<String>,
+    topic: Json<Topic>,
+    req: HttpRequest,
+    db: Data<Database>,
+    auth: Data<AuthHandler>,
+    id: Identity,
+) -> Result<HttpResponse, Error> {
+    let create_only = req
+        .headers()
+        .get(IF_NONE_MATCH)
+        .is_some_and(|v| v.as_bytes() == b"*");
+    let topic = Topic {
+        created_by: current_email(&auth, &db, &id).await,
+        ..topic.into_inner()
+    };
+    let name = topic.name.clone();
+    let created = db.upsert_topic(&key, topic, create_only).await?;
+    if created {
+        Ok(HttpResponse::Created()
+            .append_header((LOCATION, format!("/api/v1/topics/{}", key)))
+            .json(Generic::new(format!("Successfully created {}", name))))
+    } else {
+        Ok(HttpResponse::Ok().json(Generic::new(format!("Successfully updated {}", name))))
+    }
+}
+
+#[utoipa::path(
+    context_path = "/api/v1/topics",
+    tag = "topics",
+    request_body = Topic,
+    responses(
+        (status = 200, description = "Deleted the topic", body = Generic),
+        (status = 401, description = "Not logged in"),
+        (status = 403, description = "Not an admin", body = Error),
+        (status = 404, description = "No such topic", body = Error),
+    ),
+    security(("session" = []), ("token" = []))
+)]
 #[delete("/")]
 async fn delete_topic(topic: Json<Topic>, db: Data<Database>) -> Result<Generic> {
     db.delete_topic(topic.name.as_str())
@@ -43,18 +204,16 @@ async fn delete_topic(topic: Json<Topic>, db: Data<Database>) -> Result<Generic>
             Generic::new(format!("Successfully deleted {}", topic.name))
         })
         .map_err(|e| {
-            // error!("Failed to delete topic: {:?}", e);
-            Error::new(
-                format!("Failed to delete topic: {:?}", e),
-                StatusCode::INTERNAL_SERVER_ERROR,
-            )
-            .into()
+            log::debug!("Failed to delete topic: {:?}", e);
+            e.into()
         })
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::models::generic::Cursor;
+    use crate::models::topics::{SortOrder, TopicSort};
     use actix_service::Service;
     use actix_web::{
         http::StatusCode,
@@ -63,19 +222,70 @@ mod test {
         App,
     };
 
+    fn auth_handler() -> AuthHandler {
+        AuthHandler::new("http://localhost".to_string())
+    }
+
+    fn page(items: Vec<String>) -> Page<String> {
+        Page {
+            total: items.len() as u64,
+            items,
+            next_cursor: None,
+            prev_cursor: None,
+        }
+    }
+
     #[test]
     async fn test_get_topics() {
         let mut db = Database::default();
         db.expect_get_topics()
-            .returning(|_page, _size| Ok(vec!["topic1".to_string(), "topic2".to_string()]));
+            .withf(|size, cursor, _filter| *size == 50 && cursor.is_none())
+            .returning(|_size, _cursor, _filter| {
+                Ok(page(vec!["topic1".to_string(), "topic2".to_string()]))
+            });
 
         let app = init_service(App::new().service(get_topics).app_data(Data::new(db))).await;
         let req = TestRequest::with_uri("/").to_request();
         let resp = app.call(req).await.unwrap();
 
         assert_eq!(resp.status(), StatusCode::OK);
-        let body: Vec<String> = read_body_json(resp).await;
-        assert_eq!(body, vec!["topic1".to_string(), "topic2".to_string()]);
+        let body: Page<String> = read_body_json(resp).await;
+        assert_eq!(body, page(vec!["topic1".to_string(), "topic2".to_string()]));
+    }
+
+    #[test]
+    async fn test_get_topics_csv() {
+        let mut db = Database::default();
+        db.expect_get_topics().returning(|_size, _cursor, _filter| {
+            Ok(Page {
+                next_cursor: Some("abc".to_string()),
+                total: 3,
+                ..page(vec![
+                    "Mercy".to_string(),
+                    "Patience, in trials".to_string(),
+                    "=HYPERLINK(\"http://evil.example.org\")".to_string(),
+                ])
+            })
+        });
+        let app = init_service(App::new().service(get_topics).app_data(Data::new(db))).await;
+        let req = TestRequest::with_uri("/?size=2")
+            .insert_header(("Accept", "text/csv, application/json;q=0.5"))
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("Content-Type").unwrap(), "text/csv");
+        assert_eq!(resp.headers().get("X-Total-Count").unwrap(), "3");
+        assert_eq!(
+            resp.headers().get("Link").unwrap(),
+            "</?size=2&cursor=abc>; rel=\"next\""
+        );
+        let body = read_body(resp).await;
+        assert_eq!(
+            body,
+            "topic\nMercy\n\"Patience, in trials\"\n\"'=HYPERLINK(\"\"http://evil.example.org\"\")\"\n",
+            "Formulas are quoted as text"
+        );
     }
 
     #[test]
@@ -97,21 +307,197 @@ mod test {
     async fn test_get_topics_partial_query() {
         let mut db = Database::default();
         db.expect_get_topics()
-            .returning(|_page, _size| Ok(vec!["topic1".to_string(), "topic2".to_string()]));
+            .withf(|size, _cursor, _filter| *size == 2)
+            .returning(|_size, _cursor, _filter| {
+                Ok(page(vec!["topic1".to_string(), "topic2".to_string()]))
+            });
+        let app = init_service(App::new().service(get_topics).app_data(Data::new(db))).await;
+        let req = TestRequest::with_uri("/?size=2").to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Page<String> = read_body_json(resp).await;
+        assert_eq!(body.items, vec!["topic1".to_string(), "topic2".to_string()]);
+    }
+
+    #[test]
+    async fn test_get_topics_size_clamped() {
+        let mut db = Database::default();
+        db.expect_get_topics()
+            .withf(|size, _cursor, _filter| *size == Pagination::MAX_SIZE)
+            .returning(|_size, _cursor, _filter| Ok(page(vec![])));
+        let app = init_service(App::new().service(get_topics).app_data(Data::new(db))).await;
+        let req = TestRequest::with_uri("/?size=100000&page=0").to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[test]
+    async fn test_get_topics_cursor() {
+        let cursor = Cursor {
+            value: "topic2".into(),
+            key: "topic2".to_string(),
+            backward: false,
+        };
+        let expected = cursor.clone();
+        let mut db = Database::default();
+        db.expect_get_topics()
+            .withf(move |_size, cursor, _filter| cursor.as_ref() == Some(&expected))
+            .returning(|_size, _cursor, _filter| Ok(page(vec!["topic3".to_string()])));
+        let app = init_service(App::new().service(get_topics).app_data(Data::new(db))).await;
+        let req = TestRequest::with_uri(&format!("/?cursor={}", cursor.encode())).to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Page<String> = read_body_json(resp).await;
+        assert_eq!(body.items, vec!["topic3".to_string()]);
+    }
+
+    #[test]
+    async fn test_get_topics_bad_cursor() {
+        let db = Database::default();
+        let app = init_service(App::new().service(get_topics).app_data(Data::new(db))).await;
+        let req = TestRequest::with_uri("/?cursor=not-a-cursor").to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    async fn test_get_topics_sorted_filtered() {
+        let mut db = Database::default();
+        db.expect_get_topics()
+            .withf(|_size, _cursor, filter| {
+                filter
+                    == &TopicFilter {
+                        sort: TopicSort::RefCount,
+                        order: SortOrder::Desc,
+                        has_refs: Some(true),
+                        parent: Some("faith".to_string()),
+                        created_by: None,
+                        updated_since: None,
+                    }
+            })
+            .returning(|_size, _cursor, _filter| Ok(page(vec!["topic1".to_string()])));
         let app = init_service(App::new().service(get_topics).app_data(Data::new(db))).await;
-        let req = TestRequest::with_uri("/?page=1").to_request();
+        let req = TestRequest::with_uri("/?sort=ref_count&order=desc&has_refs=true&parent=faith")
+            .to_request();
         let resp = app.call(req).await.unwrap();
 
         assert_eq!(resp.status(), StatusCode::OK);
-        let body: Vec<String> = read_body_json(resp).await;
-        assert_eq!(body, vec!["topic1".to_string(), "topic2".to_string()]);
+        let body: Page<String> = read_body_json(resp).await;
+        assert_eq!(body.items, vec!["topic1".to_string()]);
+    }
+
+    #[test]
+    async fn test_get_topics_bad_sort() {
+        let db = Database::default();
+        let app = init_service(App::new().service(get_topics).app_data(Data::new(db))).await;
+        let req = TestRequest::with_uri("/?sort=random").to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    async fn test_get_topic() {
+        let mut db = Database::default();
+        db.expect_get_topic()
+            .withf(|key| key == "Mercy")
+            .returning(|key| Ok(Topic::new(key)));
+        let app = init_service(App::new().service(get_topic).app_data(Data::new(db))).await;
+        let req = TestRequest::with_uri("/Mercy").to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Topic = read_body_json(resp).await;
+        assert_eq!(body, Topic::new("Mercy"));
+    }
+
+    #[test]
+    async fn test_get_topic_json_ld() {
+        let mut db = Database::default();
+        db.expect_get_topic().returning(|key| {
+            Ok(Topic {
+                parent: Some("Attributes".to_string()),
+                ..Topic::new(key)
+            })
+        });
+        db.expect_get_topic_children()
+            .returning(|_key| Ok(vec!["Forgiveness".to_string()]));
+        db.expect_get_refs().returning(|_topic| Ok(vec![]));
+        let app = init_service(
+            App::new()
+                .service(get_topic)
+                .app_data(Data::new(db))
+                .app_data(Data::new(LinkedData::new("https://example.org"))),
+        )
+        .await;
+        let req = TestRequest::with_uri("/Mercy")
+            .insert_header(("Accept", "application/ld+json"))
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["@type"], "skos:Concept");
+        assert_eq!(
+            body["skos:broader"]["@id"],
+            "https://example.org/api/v1/topics/Attributes"
+        );
+        assert_eq!(
+            body["skos:narrower"][0]["@id"],
+            "https://example.org/api/v1/topics/Forgiveness"
+        );
+    }
+
+    #[test]
+    async fn test_search_topics() {
+        let mut db = Database::default();
+        db.expect_search_topics()
+            .withf(|q, limit| q == "mer" && *limit == Search::MAX_LIMIT)
+            .returning(|_q, _limit| {
+                Ok(vec![TopicMatch {
+                    key: "mercy".to_string(),
+                    name: "Mercy".to_string(),
+                    matched: "Mercy".to_string(),
+                    ref_count: 3,
+                }])
+            });
+        let app = init_service(App::new().service(search_topics).app_data(Data::new(db))).await;
+        let req = TestRequest::with_uri("/search?q=mer&limit=500").to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Vec<TopicMatch> = read_body_json(resp).await;
+        assert_eq!(body.len(), 1);
+        assert_eq!(body[0].key, "mercy");
+    }
+
+    #[test]
+    async fn test_search_topics_empty_query() {
+        let db = Database::default();
+        let app = init_service(App::new().service(search_topics).app_data(Data::new(db))).await;
+        let req = TestRequest::with_uri("/search?q=%20").to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
     }
 
     #[test]
     async fn test_add_topic() {
         let mut db = Database::default();
-        db.expect_add_topic().returning(|_page| Ok(()));
-        let app = init_service(App::new().service(add_topic).app_data(Data::new(db))).await;
+        db.expect_add_topic()
+            .withf(|topic| topic.name == "topic1" && topic.created_by.is_none())
+            .returning(|_topic| Ok(()));
+        let app = init_service(
+            App::new()
+                .service(add_topic)
+                .app_data(Data::new(db))
+                .app_data(Data::new(auth_handler())),
+        )
+        .await;
         let topic = Topic::new("topic1");
         let req = TestRequest::post().uri("/").set_json(&topic).to_request();
         let resp = app.call(req).await.unwrap();
@@ -124,34 +510,103 @@ mod test {
         )
     }
 
-    // #[test]
-    // async fn test_add_topic_dup() {
-    //     let mut db = Database::default();
-    //     let response = "";
-    //     let request = "";
-    //     db.expect_add_topic()
-    //         .returning(move |_page| Err(unexpected(response, request)));
-    //     let app = init_service(App::new().service(add_topic).app_data(Data::new(db))).await;
-    //     let topic = NewTopic {
-    //         id: None,
-    //         name: "topic1".to_string(),
-    //     };
-    //     let req = TestRequest::post().uri("/").set_json(&topic).to_request();
-    //     let resp = app.call(req).await.unwrap();
+    #[test]
+    async fn test_add_topic_dup() {
+        let mut db = Database::default();
+        db.expect_add_topic().returning(|_topic| {
+            Err(Error::conflict("Topic topic1 already exists")
+                .with_details(serde_json::json!({ "key": "topic1" })))
+        });
+        let app = init_service(
+            App::new()
+                .service(add_topic)
+                .app_data(Data::new(db))
+                .app_data(Data::new(auth_handler())),
+        )
+        .await;
+        let topic = Topic::new("topic1");
+        let req = TestRequest::post().uri("/").set_json(&topic).to_request();
+        let resp = app.call(req).await.unwrap();
 
-    //     assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
-    //     let body = read_body(resp).await;
-    //     assert_eq!(
-    //         body,
-    //         format!(
-    //             "{}",
-    //             actix_web::Error::from(Error::new(
-    //                 unexpected(response, request),
-    //                 StatusCode::INTERNAL_SERVER_ERROR
-    //             ))
-    //         )
-    //     )
-    // }
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["code"], "conflict");
+        assert_eq!(body["details"]["key"], "topic1");
+    }
+
+    #[test]
+    async fn test_put_topic_created() {
+        let mut db = Database::default();
+        db.expect_upsert_topic()
+            .withf(|key, topic, create_only| key == "t1" && topic.name == "topic1" && !create_only)
+            .returning(|_key, _topic, _create_only| Ok(true));
+        let app = init_service(
+            App::new()
+                .service(put_topic)
+                .app_data(Data::new(db))
+                .app_data(Data::new(auth_handler())),
+        )
+        .await;
+        let req = TestRequest::put()
+            .uri("/t1")
+            .set_json(Topic::new("topic1"))
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(resp.headers().get(LOCATION).unwrap(), "/api/v1/topics/t1");
+    }
+
+    #[test]
+    async fn test_put_topic_updated() {
+        let mut db = Database::default();
+        db.expect_upsert_topic()
+            .returning(|_key, _topic, _create_only| Ok(false));
+        let app = init_service(
+            App::new()
+                .service(put_topic)
+                .app_data(Data::new(db))
+                .app_data(Data::new(auth_handler())),
+        )
+        .await;
+        let req = TestRequest::put()
+            .uri("/topic1")
+            .set_json(Topic::new("topic1"))
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Generic = read_body_json(resp).await;
+        assert_eq!(
+            body,
+            Generic::new("Successfully updated topic1".to_string())
+        );
+    }
+
+    #[test]
+    async fn test_put_topic_if_none_match() {
+        let mut db = Database::default();
+        db.expect_upsert_topic()
+            .withf(|_key, _topic, create_only| *create_only)
+            .returning(|_key, _topic, _create_only| {
+                Err(Error::precondition_failed("Topic topic1 already exists"))
+            });
+        let app = init_service(
+            App::new()
+                .service(put_topic)
+                .app_data(Data::new(db))
+                .app_data(Data::new(auth_handler())),
+        )
+        .await;
+        let req = TestRequest::put()
+            .uri("/topic1")
+            .insert_header((IF_NONE_MATCH, "*"))
+            .set_json(Topic::new("topic1"))
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::PRECONDITION_FAILED);
+    }
 
     // #[test]
     // async fn test_delete_topic() {
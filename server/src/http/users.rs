@@ -0,0 +1,253 @@
+use crate::core::auth::AuthHandler;
+use actix_identity::Identity;
+use actix_web::web::{scope, Data, Json, Path, ServiceConfig};
+use actix_web::{delete, get, put, services};
+use actix_web_lab::middleware::from_fn;
+use chrono::Utc;
+
+use super::topics::current_email;
+#[mockall_double::double]
+use crate::core::db::Database;
+use crate::models::auth::{NewRole, RoleAssignment, SessionInfo};
+use crate::models::generic::{Error, Generic};
+
+pub fn users_service(cfg: &mut ServiceConfig) {
+    cfg.service(
+        scope("/users")
+            .service(services![
+                get_roles,
+                set_role,
+                delete_role,
+                get_sessions,
+                revoke_sessions
+            ])
+            .wrap(from_fn(AuthHandler::admin_middleware)),
+    );
+}
+
+/// The roles assigned to users locally, by email.
+/// Users without one get the role of their provider groups.
+#[utoipa::path(
+    context_path = "/api/v1/users",
+    tag = "roles",
+    responses(
+        (status = 200, description = "The assigned roles", body = [RoleAssignment]),
+        (status = 401, description = "Not logged in"),
+        (status = 403, description = "Not an admin", body = Error),
+    ),
+    security(("session" = []), ("token" = []))
+)]
+#[get("/roles")]
+async fn get_roles(db: Data<Database>) -> Result<Json<Vec<RoleAssignment>>, Error> {
+    db.get_roles().await.map(Json)
+}
+
+/// Assign a role to a user, over the one of their provider groups.
+#[utoipa::path(
+    context_path = "/api/v1/users",
+    tag = "roles",
+    params(("email" = String, Path, description = "The email of the user")),
+    request_body = NewRole,
+    responses(
+        (status = 200, description = "Assigned the role", body = RoleAssignment),
+        (status = 401, description = "Not logged in"),
+        (status = 403, description = "Not an admin", body = Error),
+    ),
+    security(("session" = []), ("token" = []))
+)]
+#[put("/{email}/role")]
+async fn set_role(
+    email: Path<String>,
+    role: Json<NewRole>,
+    db: Data<Database>,
+    auth: Data<AuthHandler>,
+    id: Identity,
+) -> Result<Json<RoleAssignment>, Error> {
+    let assignment = RoleAssignment {
+        email: email.to_lowercase(),
+        role: role.role,
+        assigned_by: current_email(&auth, &db, &id).await,
+        assigned_at: Some(Utc::now()),
+    };
+    db.set_role(assignment).await.map(Json)
+}
+
+/// Remove the role assigned to a user, so they get the one of their provider groups again.
+#[utoipa::path(
+    context_path = "/api/v1/users",
+    tag = "roles",
+    params(("email" = String, Path, description = "The email of the user")),
+    responses(
+        (status = 200, description = "Removed the role", body = Generic),
+        (status = 401, description = "Not logged in"),
+        (status = 403, description = "Not an admin", body = Error),
+        (status = 404, description = "The user has no assigned role", body = Error),
+    ),
+    security(("session" = []), ("token" = []))
+)]
+#[delete("/{email}/role")]
+async fn delete_role(email: Path<String>, db: Data<Database>) -> Result<Generic, Error> {
+    db.delete_role(&email).await?;
+    Ok(Generic::new(format!("Removed the role of {}", email)))
+}
+
+/// The sessions a user is logged in with, the latest to expire first.
+#[utoipa::path(
+    context_path = "/api/v1/users",
+    tag = "sessions",
+    params(("email" = String, Path, description = "The email of the user")),
+    responses(
+        (status = 200, description = "The sessions of the user", body = [SessionInfo]),
+        (status = 401, description = "Not logged in"),
+        (status = 403, description = "Not an admin", body = Error),
+    ),
+    security(("session" = []), ("token" = []))
+)]
+#[get("/{email}/sessions")]
+async fn get_sessions(
+    email: Path<String>,
+    db: Data<Database>,
+) -> Result<Json<Vec<SessionInfo>>, Error> {
+    let sessions = db.get_user_sessions(&email).await?;
+    Ok(Json(
+        sessions.into_iter().filter_map(SessionInfo::of).collect(),
+    ))
+}
+
+/// Log a user out everywhere, deleting all their sessions and API tokens.
+#[utoipa::path(
+    context_path = "/api/v1/users",
+    tag = "sessions",
+    params(("email" = String, Path, description = "The email of the user")),
+    responses(
+        (status = 200, description = "Revoked the sessions and API tokens of the user", body = Generic),
+        (status = 401, description = "Not logged in"),
+        (status = 403, description = "Not an admin", body = Error),
+    ),
+    security(("session" = []), ("token" = []))
+)]
+#[delete("/{email}/sessions")]
+async fn revoke_sessions(email: Path<String>, db: Data<Database>) -> Result<Generic, Error> {
+    let sessions = db.delete_user_sessions(&email).await?;
+    let tokens = db.delete_user_api_tokens(&email).await?;
+    Ok(Generic::new(format!(
+        "Revoked {} sessions and {} API tokens of {}",
+        sessions, tokens, email
+    )))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::auth::{Role, SessionRecord, Token};
+    use actix_service::Service;
+    use actix_web::{
+        http::StatusCode,
+        test,
+        test::{init_service, read_body_json, TestRequest},
+        App,
+    };
+
+    fn session(token: Option<Token>) -> SessionRecord {
+        SessionRecord {
+            nonce: String::new(),
+            token,
+            provider: Some("azure".to_string()),
+            verifier: None,
+            access_token: None,
+            refresh_token: Some("refresh".to_string()),
+            created_at: Some(Utc::now()),
+            groups: vec![],
+            referrer: None,
+        }
+    }
+
+    #[test]
+    async fn test_get_sessions() {
+        let mut db = Database::default();
+        db.expect_get_user_sessions()
+            .withf(|email| email == "user@example.org")
+            .returning(|email| {
+                let token = Token {
+                    token: Some("secret id token".to_string()),
+                    name: "User".to_string(),
+                    preferred_username: email.to_string(),
+                    exp: Utc::now(),
+                };
+                Ok(vec![session(Some(token)), session(None)])
+            });
+        let app = init_service(App::new().service(get_sessions).app_data(Data::new(db))).await;
+        let req = TestRequest::with_uri("/user@example.org/sessions").to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = read_body_json(resp).await;
+        let sessions = body.as_array().unwrap();
+        assert_eq!(sessions.len(), 1, "Logins in progress are not listed");
+        assert_eq!(sessions[0]["email"], "user@example.org");
+        assert_eq!(sessions[0]["renewable"], true);
+        assert!(
+            !body.to_string().contains("secret"),
+            "Tokens are not listed"
+        );
+    }
+
+    #[test]
+    async fn test_revoke_sessions() {
+        let mut db = Database::default();
+        db.expect_delete_user_sessions()
+            .withf(|email| email == "user@example.org")
+            .times(1)
+            .returning(|_| Ok(2));
+        db.expect_delete_user_api_tokens()
+            .withf(|email| email == "user@example.org")
+            .times(1)
+            .returning(|_| Ok(1));
+        let app = init_service(App::new().service(revoke_sessions).app_data(Data::new(db))).await;
+        let req = TestRequest::delete()
+            .uri("/user@example.org/sessions")
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[test]
+    async fn test_set_role() {
+        let mut db = Database::default();
+        db.expect_set_role()
+            .withf(|a| a.email == "user@example.org" && a.role == Role::Editor)
+            .times(1)
+            .returning(Ok);
+        let app = init_service(
+            App::new()
+                .service(set_role)
+                .app_data(Data::new(db))
+                .app_data(Data::new(AuthHandler::new("http://localhost".to_string()))),
+        )
+        .await;
+        let req = TestRequest::put()
+            .uri("/User@Example.org/role")
+            .set_json(&NewRole { role: Role::Editor })
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["role"], "editor");
+    }
+
+    #[test]
+    async fn test_delete_role_unassigned() {
+        let mut db = Database::default();
+        db.expect_delete_role()
+            .returning(|email| Err(Error::not_found(format!("{} has no assigned role", email))));
+        let app = init_service(App::new().service(delete_role).app_data(Data::new(db))).await;
+        let req = TestRequest::delete()
+            .uri("/user@example.org/role")
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
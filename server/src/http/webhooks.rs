@@ -0,0 +1,374 @@
+use crate::core::auth::AuthHandler;
+use actix_identity::Identity;
+use actix_web::http::header::LOCATION;
+use actix_web::web::{scope, Bytes, Data, Json, Path, Query, ServiceConfig};
+use actix_web::{delete, get, post, services, App, HttpRequest, HttpResponse, HttpServer};
+use actix_web_lab::middleware::from_fn;
+use chrono::Utc;
+use std::io::Write;
+use std::sync::Mutex;
+
+use super::topics::current_email;
+#[mockall_double::double]
+use crate::core::db::Database;
+use crate::core::webhooks::{self, RetryPolicy, SIGNATURE};
+use crate::models::events::{Event, EventKind};
+use crate::models::generic::{Error, Generic};
+use crate::models::webhooks::{DeliveryParams, NewWebhook, Webhook, WebhookDelivery, WebhookInfo};
+
+pub fn webhooks_service(cfg: &mut ServiceConfig) {
+    cfg.service(
+        scope("/webhooks")
+            .service(services![
+                get_webhooks,
+                add_webhook,
+                delete_webhook,
+                get_deliveries,
+                test_webhook
+            ])
+            .wrap(from_fn(AuthHandler::admin_middleware)),
+    );
+}
+
+#[utoipa::path(
+    context_path = "/api/v1/webhooks",
+    tag = "webhooks",
+    responses(
+        (status = 200, description = "The registered webhooks, without their secrets", body = [WebhookInfo]),
+        (status = 401, description = "Not logged in"),
+        (status = 403, description = "Not an admin", body = Error),
+    ),
+    security(("session" = []), ("token" = []))
+)]
+#[get("/")]
+async fn get_webhooks(db: Data<Database>) -> Result<Json<Vec<WebhookInfo>>, Error> {
+    let hooks = db.get_webhooks().await?;
+    Ok(Json(hooks.into_iter().map(Into::into).collect()))
+}
+
+/// Register a URL to post events to, signed with the secret.
+#[utoipa::path(
+    context_path = "/api/v1/webhooks",
+    tag = "webhooks",
+    request_body = NewWebhook,
+    responses(
+        (status = 201, description = "Registered the webhook", body = WebhookInfo),
+        (status = 400, description = "Invalid URL, secret or events", body = Error),
+        (status = 401, description = "Not logged in"),
+        (status = 403, description = "Not an admin", body = Error),
+    ),
+    security(("session" = []), ("token" = []))
+)]
+#[post("/")]
+async fn add_webhook(
+    hook: Json<NewWebhook>,
+    db: Data<Database>,
+    auth: Data<AuthHandler>,
+    id: Identity,
+) -> Result<HttpResponse, Error> {
+    hook.validate()?;
+    let hook = hook.into_inner();
+    let created = db
+        .add_webhook(Webhook {
+            key: None,
+            url: hook.url,
+            events: hook.events,
+            secret: hook.secret,
+            created_by: current_email(&auth, &db, &id).await,
+            created_at: Some(Utc::now()),
+        })
+        .await?;
+    let info = WebhookInfo::from(created);
+    Ok(HttpResponse::Created()
+        .append_header((LOCATION, format!("/api/v1/webhooks/{}", info.key)))
+        .json(info))
+}
+
+#[utoipa::path(
+    context_path = "/api/v1/webhooks",
+    tag = "webhooks",
+    params(("key" = String, Path, description = "The key of the webhook")),
+    responses(
+        (status = 200, description = "Deleted the webhook and its deliveries", body = Generic),
+        (status = 401, description = "Not logged in"),
+        (status = 403, description = "Not an admin", body = Error),
+        (status = 404, description = "No such webhook", body = Error),
+    ),
+    security(("session" = []), ("token" = []))
+)]
+#[delete("/{key}")]
+async fn delete_webhook(key: Path<String>, db: Data<Database>) -> Result<Generic, Error> {
+    db.delete_webhook(&key).await?;
+    Ok(Generic::new(format!(
+        "Successfully deleted webhook {}",
+        key
+    )))
+}
+
+/// The delivery log of a webhook, with every attempt of each delivery.
+#[utoipa::path(
+    context_path = "/api/v1/webhooks",
+    tag = "webhooks",
+    params(
+        ("key" = String, Path, description = "The key of the webhook"),
+        DeliveryParams,
+    ),
+    responses(
+        (status = 200, description = "The last deliveries, newest first", body = [WebhookDelivery]),
+        (status = 401, description = "Not logged in"),
+        (status = 403, description = "Not an admin", body = Error),
+        (status = 404, description = "No such webhook", body = Error),
+    ),
+    security(("session" = []), ("token" = []))
+)]
+#[get("/{key}/deliveries")]
+async fn get_deliveries(
+    key: Path<String>,
+    q: Query<DeliveryParams>,
+    db: Data<Database>,
+) -> Result<Json<Vec<WebhookDelivery>>, Error> {
+    db.get_webhook(&key).await?;
+    let limit = q.limit.min(DeliveryParams::MAX_LIMIT);
+    Ok(Json(db.get_deliveries(&key, limit).await?))
+}
+
+/// Send a `ping` event to the webhook once, and wait for the answer.
+#[utoipa::path(
+    context_path = "/api/v1/webhooks",
+    tag = "webhooks",
+    params(("key" = String, Path, description = "The key of the webhook")),
+    responses(
+        (status = 200, description = "The delivery, delivered or failed", body = WebhookDelivery),
+        (status = 401, description = "Not logged in"),
+        (status = 403, description = "Not an admin", body = Error),
+        (status = 404, description = "No such webhook", body = Error),
+    ),
+    security(("session" = []), ("token" = []))
+)]
+#[post("/{key}/test")]
+async fn test_webhook(
+    key: Path<String>,
+    db: Data<Database>,
+) -> Result<Json<WebhookDelivery>, Error> {
+    let hook = db.get_webhook(&key).await?;
+    let ping = Event::topic(EventKind::Ping, "");
+    let delivery = webhooks::deliver(&db, &hook, ping, &RetryPolicy::once()).await?;
+    Ok(Json(delivery))
+}
+
+/// Runs a stand-in receiver on localhost, which checks the signature of the
+/// deliveries it gets and writes them to `out`, to try webhooks without a real receiver.
+pub async fn run_receiver(
+    port: u16,
+    secret: String,
+    mut out: Box<dyn Write + Send>,
+) -> std::io::Result<()> {
+    writeln!(out, "Receiving webhooks on http://localhost:{}/", port)?;
+    let receiver = Data::new(Receiver {
+        secret,
+        out: Mutex::new(out),
+    });
+    HttpServer::new(move || App::new().app_data(receiver.clone()).service(receive))
+        .bind(("localhost", port))?
+        .run()
+        .await
+}
+
+struct Receiver {
+    secret: String,
+    /// Shared by the workers, so deliveries are written whole.
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Receiver {
+    fn write(&self, line: &str) {
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = writeln!(out, "{}", line) {
+            log::error!("Unable to write a delivery: {:?}", e);
+        }
+    }
+}
+
+#[post("/")]
+async fn receive(req: HttpRequest, body: Bytes, receiver: Data<Receiver>) -> HttpResponse {
+    let signature = req
+        .headers()
+        .get(SIGNATURE)
+        .and_then(|s| s.to_str().ok())
+        .unwrap_or_default();
+    if !webhooks::verify(&receiver.secret, &body, signature) {
+        receiver.write("Rejected a delivery with an invalid signature");
+        return HttpResponse::Unauthorized().finish();
+    }
+    receiver.write(&String::from_utf8_lossy(&body));
+    HttpResponse::NoContent().finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_service::Service;
+    use actix_web::{
+        http::StatusCode,
+        test,
+        test::{init_service, read_body_json, TestRequest},
+    };
+    use std::sync::Arc;
+
+    const SECRET: &str = "0123456789abcdef";
+
+    fn auth_handler() -> AuthHandler {
+        AuthHandler::new("http://localhost".to_string())
+    }
+
+    fn hook() -> Webhook {
+        Webhook {
+            key: Some("1".to_string()),
+            url: "https://example.org/hook".to_string(),
+            events: vec![EventKind::TopicCreated],
+            secret: SECRET.to_string(),
+            created_by: None,
+            created_at: None,
+        }
+    }
+
+    #[test]
+    async fn test_get_webhooks_hides_secret() {
+        let mut db = Database::default();
+        db.expect_get_webhooks().returning(|| Ok(vec![hook()]));
+        let app = init_service(App::new().service(get_webhooks).app_data(Data::new(db))).await;
+        let req = TestRequest::with_uri("/").to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body[0]["key"], "1");
+        assert_eq!(body[0]["events"], serde_json::json!(["topic_created"]));
+        assert!(body[0].get("secret").is_none());
+    }
+
+    #[test]
+    async fn test_add_webhook() {
+        let mut db = Database::default();
+        db.expect_add_webhook()
+            .withf(|h| h.url == "https://example.org/hook" && h.secret == SECRET)
+            .returning(|h| {
+                Ok(Webhook {
+                    key: Some("1".to_string()),
+                    ..h
+                })
+            });
+        let app = init_service(
+            App::new()
+                .service(add_webhook)
+                .app_data(Data::new(db))
+                .app_data(Data::new(auth_handler())),
+        )
+        .await;
+        let hook = NewWebhook {
+            url: "https://example.org/hook".to_string(),
+            events: vec![],
+            secret: SECRET.to_string(),
+        };
+        let req = TestRequest::post().uri("/").set_json(&hook).to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(resp.headers().get(LOCATION).unwrap(), "/api/v1/webhooks/1");
+    }
+
+    #[test]
+    async fn test_add_webhook_invalid() {
+        let db = Database::default();
+        let app = init_service(
+            App::new()
+                .service(add_webhook)
+                .app_data(Data::new(db))
+                .app_data(Data::new(auth_handler())),
+        )
+        .await;
+        for hook in [
+            NewWebhook {
+                url: "ftp://example.org/hook".to_string(),
+                events: vec![],
+                secret: SECRET.to_string(),
+            },
+            NewWebhook {
+                url: "https://example.org/hook".to_string(),
+                events: vec![],
+                secret: "short".to_string(),
+            },
+        ] {
+            let req = TestRequest::post().uri("/").set_json(&hook).to_request();
+            let resp = app.call(req).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[test]
+    async fn test_get_deliveries_unknown_webhook() {
+        let mut db = Database::default();
+        db.expect_get_webhook()
+            .returning(|key| Err(Error::not_found(format!("Webhook {} not found", key))));
+        let app = init_service(App::new().service(get_deliveries).app_data(Data::new(db))).await;
+        let req = TestRequest::with_uri("/2/deliveries").to_request();
+        let resp = app.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    /// A writer whose output the test can read.
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    async fn test_receive_checks_signature() {
+        let output = Output::default();
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(Receiver {
+                    secret: SECRET.to_string(),
+                    out: Mutex::new(Box::new(output.clone())),
+                }))
+                .service(receive),
+        )
+        .await;
+        let body = r#"{"id":1}"#;
+        let signed = TestRequest::post()
+            .uri("/")
+            .insert_header((SIGNATURE, webhooks::sign(SECRET, body.as_bytes())))
+            .set_payload(body)
+            .to_request();
+        assert_eq!(
+            app.call(signed).await.unwrap().status(),
+            StatusCode::NO_CONTENT
+        );
+
+        let forged = TestRequest::post()
+            .uri("/")
+            .insert_header((
+                SIGNATURE,
+                webhooks::sign("not the secret!!", body.as_bytes()),
+            ))
+            .set_payload(body)
+            .to_request();
+        assert_eq!(
+            app.call(forged).await.unwrap().status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            String::from_utf8(output.0.lock().unwrap().clone()).unwrap(),
+            "{\"id\":1}\nRejected a delivery with an invalid signature\n"
+        );
+    }
+}
@@ -3,15 +3,29 @@ mod core;
 mod http;
 mod models;
 
-use crate::core::auth::AuthHandler;
-use crate::core::db::{Config, Database};
-use crate::http::{
-    auth::auth_service, refs::refs_service, root::root_service, topics::topics_service,
-};
+use crate::core::auth::{self, AuthHandler};
+use crate::core::cookie::{CookieConfig, CookieKeys, KeyRingPolicy, SameSite};
+use crate::core::db::Config;
+#[mockall_double::double]
+use crate::core::db::Database;
+use crate::core::events::EventBus;
+use crate::core::linked::LinkedData;
+use crate::core::local::{self, LoginLimiter};
+use crate::core::oidc::{self, Provider, ProviderConfig};
+use crate::core::webhooks::{self, RetryPolicy};
+use crate::http::{api_service, docs::docs_service, graphql, webhooks::run_receiver};
+use models::auth::{Role, RoleAssignment};
+use models::export::ExportFormat;
 use models::generic::Error;
+use models::graph::{GraphFormat, GraphParams};
+use models::import::ImportFormat;
 
-use actix_web::{middleware::Logger, web, App, HttpServer};
+use actix_web::{middleware::Logger, web::Data, App, HttpServer};
 use clap::Parser;
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Write};
 
 #[cfg(debug_assertions)]
 use dotenv::{dotenv, from_filename};
@@ -39,12 +53,156 @@ struct Args {
     /// Path to the schema.
     #[clap(short, long, value_parser, env = "SCHEMA_PATH")]
     schema_path: String,
-    /// Azure AD Client Secret
+    /// The issuer of the OpenID Connect provider to log in with, when there is only one
+    #[clap(long, value_parser, env = "OIDC_ISSUER")]
+    oidc_issuer: Option<String>,
+    /// The client secret at the provider
     #[clap(long, value_parser, env = "CLIENT_SECRET")]
-    client_secret: String,
-    /// Azure AD Client ID
+    client_secret: Option<String>,
+    /// The client ID at the provider
     #[clap(long, value_parser, env = "CLIENT_ID")]
-    client_id: String,
+    client_id: Option<String>,
+    /// A JSON file listing the providers to log in with, instead of a single one
+    #[clap(long, value_parser, env = "OIDC_PROVIDERS")]
+    oidc_providers: Option<String>,
+    /// The URL the server is reached at, which linked data URIs are built from
+    #[clap(
+        long,
+        value_parser,
+        env = "PUBLIC_URL",
+        default_value = "http://localhost:8000"
+    )]
+    public_url: String,
+    /// The emails of the users who are always admins, comma separated
+    #[clap(long, value_parser, env = "ADMIN_EMAILS", value_delimiter = ',')]
+    admins: Vec<String>,
+    /// How many seconds the clocks of the login providers may be off
+    #[clap(long, value_parser, env = "TOKEN_LEEWAY", default_value_t = auth::DEFAULT_LEEWAY)]
+    token_leeway: u64,
+    /// The base64 key session cookies are signed with, at least 32 bytes, instead of the key file
+    #[clap(long, value_parser, env = "COOKIE_KEY", hide_env_values = true)]
+    cookie_key: Option<String>,
+    /// The file holding the cookie key, generated if it does not exist
+    #[clap(
+        long,
+        value_parser,
+        env = "COOKIE_KEY_FILE",
+        default_value = "cookie.key"
+    )]
+    cookie_key_file: String,
+    /// Previous cookie keys, comma separated, still accepted after a rotation
+    #[clap(
+        long,
+        value_parser,
+        env = "COOKIE_OLD_KEYS",
+        value_delimiter = ',',
+        hide_env_values = true
+    )]
+    cookie_old_keys: Vec<String>,
+    /// The name of the session cookie
+    #[clap(long, value_parser, env = "COOKIE_NAME", default_value = "ir_session")]
+    cookie_name: String,
+    /// The domain of the session cookie, the host of the request by default
+    #[clap(long, value_parser, env = "COOKIE_DOMAIN")]
+    cookie_domain: Option<String>,
+    #[clap(long, value_enum, env = "COOKIE_SAME_SITE", default_value_t)]
+    cookie_same_site: SameSite,
+    /// How many seconds the session cookie lasts, until the browser closes by default
+    #[clap(long, value_parser, env = "COOKIE_MAX_AGE")]
+    cookie_max_age: Option<i64>,
+    /// Only send the session cookie over HTTPS. Turn off for plain HTTP development
+    #[clap(
+        long,
+        value_parser,
+        env = "COOKIE_SECURE",
+        default_value_t = true,
+        action = clap::ArgAction::Set
+    )]
+    cookie_secure: bool,
+    /// Let users log in with local accounts, created with `create-account`.
+    /// No login provider needs to be configured then
+    #[clap(
+        long,
+        value_parser,
+        env = "LOCAL_ACCOUNTS",
+        default_value_t = false,
+        action = clap::ArgAction::Set
+    )]
+    local_accounts: bool,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Run a stand-in webhook receiver on localhost, which prints the deliveries
+    /// signed with the secret, until stopped
+    WebhookReceiver {
+        #[clap(long, value_parser, default_value_t = 9000)]
+        port: u16,
+        /// The secret the webhook was registered with
+        #[clap(long, value_parser)]
+        secret: String,
+    },
+    #[clap(flatten)]
+    Db(DbCommand),
+}
+
+/// The one-off commands run against the database instead of the server.
+#[derive(clap::Subcommand, Debug)]
+enum DbCommand {
+    /// Import topic–reference mappings from a CSV or TSV file, or restore a
+    /// `.json` or `.ndjson` export, then exit
+    Import {
+        /// The file, with topic, citation and note columns
+        #[clap(value_parser)]
+        file: String,
+        /// The format of a spreadsheet, guessed from its extension by default
+        #[clap(long, value_enum)]
+        format: Option<ImportFormat>,
+        /// Report what would change without changing anything
+        #[clap(long, action = clap::ArgAction::SetTrue)]
+        dry_run: bool,
+    },
+    /// Export the whole graph, then exit
+    Export {
+        #[clap(long, value_enum, default_value_t)]
+        format: ExportFormat,
+        /// The file to write, standard output by default
+        #[clap(long, short, value_parser)]
+        out: Option<String>,
+    },
+    /// Export the topic graph for visualization tools, then exit
+    Graph {
+        #[clap(long, value_enum, default_value_t)]
+        format: GraphFormat,
+        /// Only the part of the graph around this topic key
+        #[clap(long, value_parser)]
+        root: Option<String>,
+        /// How many edges away from the root to go
+        #[clap(long, value_parser, default_value_t = GraphParams::default_depth())]
+        depth: u32,
+        /// The file to write, standard output by default
+        #[clap(long, short, value_parser)]
+        out: Option<String>,
+    },
+    /// Create a local account, reading its password from ACCOUNT_PASSWORD or the first
+    /// line of standard input, then exit
+    CreateAccount {
+        #[clap(value_parser)]
+        username: String,
+        /// The email the user is known by, which roles are assigned to
+        #[clap(long, value_parser)]
+        email: String,
+        /// The display name, the username by default
+        #[clap(long, value_parser)]
+        name: Option<String>,
+        /// Make the user an admin, to bootstrap an installation without a login provider
+        #[clap(long, action = clap::ArgAction::SetTrue)]
+        admin: bool,
+        #[clap(long, value_parser, env = "ACCOUNT_PASSWORD", hide_env_values = true)]
+        password: Option<String>,
+    },
 }
 
 #[actix_web::main]
@@ -71,43 +229,252 @@ async fn main() -> std::io::Result<()> {
         schema_path: format!("{}/schema.yaml", args.schema_path),
     };
 
-    Database::migrate().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    let command = match args.command {
+        // The receiver stands in for another system, so needs no database
+        Some(Command::WebhookReceiver { port, secret }) => {
+            return run_receiver(port, secret, Box::new(std::io::stdout())).await;
+        }
+        Some(Command::Db(command)) => Some(command),
+        None => None,
+    };
+
+    Database::migrate().map_err(std::io::Error::other)?;
+
+    let linked = LinkedData::new(&args.public_url);
 
+    if let Some(command) = command {
+        return run_command(command, db_cfg, &linked).await;
+    }
+
+    // Shared by the databases of every worker, so listeners see all changes
+    let events = EventBus::default();
+    let bus = events.clone();
+    let background_db = Data::new(Database::new(db_cfg.clone(), EventBus::default()).await);
+    actix_web::rt::spawn(webhooks::dispatch(
+        background_db.clone(),
+        events.clone(),
+        RetryPolicy::default(),
+    ));
+    actix_web::rt::spawn(auth::sweeper(
+        background_db,
+        std::time::Duration::from_secs(3600),
+    ));
     let db_fact = move || {
         let cfg = db_cfg.clone();
+        let events = bus.clone();
         async move {
-            let db = Database::new(cfg).await;
+            let db = Database::new(cfg, events).await;
             Ok::<Database, ()>(db)
         }
     };
 
-    let client_secret: String = args.client_secret;
-    let client_id: String = args.client_id;
+    let local_accounts = args.local_accounts;
+    let configs = match (&args.oidc_providers, &args.oidc_issuer) {
+        // Local accounts are enough to log in
+        (None, None) if local_accounts => vec![],
+        _ => provider_configs(
+            args.oidc_providers,
+            args.oidc_issuer,
+            args.client_id,
+            args.client_secret,
+        )?,
+    };
+    if local_accounts && configs.iter().any(|c| c.name == local::PROVIDER) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "The provider name local is taken by local accounts",
+        ));
+    }
+    let mut providers = vec![];
+    for config in configs {
+        let name = config.name.clone();
+        let provider = Provider::discover(config).await.map_err(|e| {
+            std::io::Error::other(format!(
+                "Unable to discover login provider {}: {}",
+                name,
+                e.message()
+            ))
+        })?;
+        providers.push(provider);
+    }
+    let public_url = args.public_url.trim_end_matches('/').to_string();
+    let admins = args.admins;
+    let leeway = args.token_leeway;
+
+    let cookie_keys = CookieKeys {
+        current: match &args.cookie_key {
+            Some(key) => CookieKeys::decode(key)?,
+            None => CookieKeys::load_or_generate(&args.cookie_key_file)?,
+        },
+        old: args
+            .cookie_old_keys
+            .iter()
+            .map(|k| CookieKeys::decode(k))
+            .collect::<std::io::Result<_>>()?,
+    };
+    let cookie_config = CookieConfig {
+        name: args.cookie_name,
+        domain: args.cookie_domain,
+        same_site: args.cookie_same_site,
+        max_age: args.cookie_max_age,
+        secure: args.cookie_secure,
+    };
+    cookie_config.validate()?;
+
+    let schema = graphql::schema();
+    let limiter = LoginLimiter::default();
 
     println!("Running the server...");
     HttpServer::new(move || {
-        let policy = actix_identity::CookieIdentityPolicy::new(&[0; 32])
-            .name("ir_session")
-            .secure(true);
+        let policy = KeyRingPolicy::new(&cookie_keys, &cookie_config);
 
         App::new()
             .wrap(Logger::default())
             .data_factory(db_fact.clone())
-            .app_data(actix_web::web::Data::new(AuthHandler::new(
-                "http://localhost:8000".to_string(),
-                client_secret.clone(),
-                client_id.clone(),
-            )))
+            .app_data(actix_web::web::Data::new(
+                AuthHandler::new(public_url.clone())
+                    .with_providers(providers.clone())
+                    .with_admins(admins.clone())
+                    .with_leeway(leeway)
+                    .with_local_accounts(local_accounts),
+            ))
+            .app_data(actix_web::web::Data::new(schema.clone()))
+            .app_data(actix_web::web::Data::new(linked.clone()))
+            .app_data(actix_web::web::Data::new(events.clone()))
+            .app_data(actix_web::web::Data::new(limiter.clone()))
             .wrap(actix_identity::IdentityService::new(policy))
-            .service(
-                web::scope("/api/v1")
-                    .configure(topics_service)
-                    .configure(refs_service)
-                    .configure(auth_service)
-                    .configure(root_service),
-            )
+            .configure(docs_service)
+            .configure(api_service)
     })
     .bind(("localhost", 8000))?
     .run()
     .await
 }
+
+/// The login providers, from the providers file, or the single one configured by its issuer.
+fn provider_configs(
+    file: Option<String>,
+    issuer: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+) -> std::io::Result<Vec<ProviderConfig>> {
+    let invalid =
+        |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidInput, msg.to_string());
+    let configs = match (file, issuer) {
+        (Some(file), _) => oidc::read_providers(&file)?,
+        (None, Some(issuer)) => vec![ProviderConfig {
+            name: "default".to_string(),
+            issuer,
+            client_id: client_id
+                .ok_or_else(|| invalid("CLIENT_ID is required with OIDC_ISSUER"))?,
+            client_secret,
+            scopes: ProviderConfig::default_scopes(),
+            groups_claim: ProviderConfig::default_groups_claim(),
+            roles: HashMap::new(),
+        }],
+        (None, None) => {
+            return Err(invalid(
+                "Set OIDC_ISSUER, OIDC_PROVIDERS or LOCAL_ACCOUNTS to log in",
+            ))
+        }
+    };
+    if configs.is_empty() {
+        return Err(invalid("OIDC_PROVIDERS lists no provider"));
+    }
+    Ok(configs)
+}
+
+/// Runs a one-off command against the database instead of the server.
+async fn run_command(
+    command: DbCommand,
+    db_cfg: Config,
+    linked: &LinkedData,
+) -> std::io::Result<()> {
+    let to_io = |e: Error| std::io::Error::other(e);
+    // Nobody listens to a one-off command
+    let db = Database::new(db_cfg, EventBus::default()).await;
+    match command {
+        DbCommand::Import {
+            file,
+            format,
+            dry_run,
+        } => {
+            let report = match (format, ExportFormat::from_path(&file)) {
+                (None, Some(export)) => {
+                    let reader = BufReader::new(File::open(&file)?);
+                    let entries = crate::core::export::read(reader, export).map_err(to_io)?;
+                    let report = crate::core::export::restore(&db, entries, dry_run)
+                        .await
+                        .map_err(to_io)?;
+                    serde_json::to_string_pretty(&report)?
+                }
+                (format, _) => {
+                    let format = format.unwrap_or_else(|| ImportFormat::from_path(&file));
+                    let data = std::fs::read(&file)?;
+                    let report = crate::core::import::run(&db, &data, format, None, dry_run)
+                        .await
+                        .map_err(to_io)?;
+                    serde_json::to_string_pretty(&report)?
+                }
+            };
+            println!("{}", report);
+        }
+        DbCommand::Export { format, out } => {
+            let mut out: Box<dyn Write> = match out {
+                Some(path) => Box::new(File::create(path)?),
+                None => Box::new(std::io::stdout()),
+            };
+            let mut chunks = Box::pin(crate::core::export::stream(Data::new(db), format));
+            while let Some(chunk) = chunks.next().await {
+                out.write_all(&chunk.map_err(to_io)?)?;
+            }
+            out.flush()?;
+        }
+        DbCommand::Graph {
+            format,
+            root,
+            depth,
+            out,
+        } => {
+            let depth = depth.min(GraphParams::MAX_DEPTH);
+            let graph = db.get_graph(root, depth).await.map_err(to_io)?;
+            let rendered = crate::core::graph::render(&graph, format, linked);
+            match out {
+                Some(path) => std::fs::write(path, rendered)?,
+                None => print!("{}", rendered),
+            }
+        }
+        DbCommand::CreateAccount {
+            username,
+            email,
+            name,
+            admin,
+            password,
+        } => {
+            let password = match password {
+                Some(password) => password,
+                None => {
+                    let mut line = String::new();
+                    std::io::stdin().read_line(&mut line)?;
+                    line.trim_end_matches(&['\r', '\n'][..]).to_string()
+                }
+            };
+            let name = name.unwrap_or_else(|| username.clone());
+            let account = local::create_account(&db, &username, &name, &email, &password)
+                .await
+                .map_err(to_io)?;
+            if admin {
+                db.set_role(RoleAssignment {
+                    email: account.email.clone(),
+                    role: Role::Admin,
+                    assigned_by: None,
+                    assigned_at: Some(chrono::Utc::now()),
+                })
+                .await
+                .map_err(to_io)?;
+            }
+            println!("Created the local account {}", account.username);
+        }
+    }
+    Ok(())
+}
@@ -1,12 +1,65 @@
+use super::generic::Error;
 use aragog::Record;
-use chrono::{prelude::*, serde::ts_seconds};
+use chrono::{
+    prelude::*,
+    serde::{ts_seconds, ts_seconds_option},
+};
 use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
+use utoipa::ToSchema;
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Record)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Record, ToSchema)]
 pub struct User {
     pub name: String,
     pub email: String,
+    /// What the user may do.
+    #[serde(default)]
+    pub role: Role,
+}
+
+/// What a user may do, each role allowing all the ones before it do.
+#[derive(
+    Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Default, ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Reads, like anyone.
+    #[default]
+    Viewer,
+    /// Proposes topics and references, by adding them.
+    Contributor,
+    /// Changes topics, and runs batches and imports.
+    Editor,
+    /// Deletes topics, and manages webhooks, users and their roles.
+    Admin,
+}
+
+impl Role {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Contributor => "contributor",
+            Role::Editor => "editor",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+/// A role assigned to a user locally, over the one from their provider groups.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Record, ToSchema)]
+pub struct RoleAssignment {
+    /// Lowercase, as emails are matched regardless of case.
+    pub email: String,
+    pub role: Role,
+    /// The email of the admin who assigned the role.
+    pub assigned_by: Option<String>,
+    pub assigned_at: Option<DateTime<Utc>>,
+}
+
+/// A role to assign.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, ToSchema)]
+pub struct NewRole {
+    pub role: Role,
 }
 
 #[derive(Serialize, Deserialize, Clone, Record, Debug, PartialEq)]
@@ -14,6 +67,257 @@ pub struct SessionRecord {
     pub nonce: String,
     #[serde(flatten)]
     pub token: Option<Token>,
+    /// The name of the provider the user logged in with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    /// The PKCE code verifier, until the code is exchanged.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verifier: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access_token: Option<String>,
+    /// Renews the session before the ID token expires.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    /// When the login started, to sweep the ones never finished.
+    #[serde(
+        default,
+        with = "ts_seconds_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub created_at: Option<DateTime<Utc>>,
+    /// The groups of the user at the provider, mapped to roles.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+    /// Where to send the user once the login finishes, kept here rather than in the
+    /// state so the provider cannot change it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub referrer: Option<String>,
+}
+
+/// A personal API token, sent as `Authorization: Bearer`. Only its hash is stored.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Record)]
+pub struct ApiToken {
+    #[serde(rename = "_key", default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    pub name: String,
+    /// The hex SHA-256 of the token.
+    pub hash: String,
+    /// The most the token may do, never more than its owner.
+    pub scope: Role,
+    /// The email of the owner.
+    pub email: String,
+    pub user_name: String,
+    /// The provider and groups of the owner at their latest login, which give their role.
+    #[serde(default)]
+    pub provider: Option<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// An API token to mint.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, ToSchema)]
+pub struct NewApiToken {
+    /// What the token is for, e.g. the script using it.
+    pub name: String,
+    /// The most the token may do, at most the role of the user. `viewer` by default.
+    #[serde(default)]
+    pub scope: Role,
+    #[serde(default = "NewApiToken::default_days")]
+    pub expires_in_days: u32,
+}
+
+impl NewApiToken {
+    pub const MAX_DAYS: u32 = 365;
+
+    pub fn default_days() -> u32 {
+        30
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.name.trim().is_empty() {
+            return Err(Error::validation("Tokens must have a name"));
+        }
+        if !(1..=Self::MAX_DAYS).contains(&self.expires_in_days) {
+            return Err(Error::validation(format!(
+                "Tokens expire in 1 to {} days",
+                Self::MAX_DAYS
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// An API token, as listed to its owner.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, ToSchema)]
+pub struct ApiTokenInfo {
+    pub key: String,
+    pub name: String,
+    pub scope: Role,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl From<ApiToken> for ApiTokenInfo {
+    fn from(token: ApiToken) -> Self {
+        ApiTokenInfo {
+            key: token.key.unwrap_or_default(),
+            name: token.name,
+            scope: token.scope,
+            created_at: token.created_at,
+            expires_at: token.expires_at,
+        }
+    }
+}
+
+/// A newly minted API token. The token itself is only ever shown here.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, ToSchema)]
+pub struct MintedToken {
+    pub token: String,
+    pub key: String,
+    pub name: String,
+    pub scope: Role,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl MintedToken {
+    pub fn new(token: String, minted: ApiToken) -> Self {
+        MintedToken {
+            token,
+            key: minted.key.unwrap_or_default(),
+            name: minted.name,
+            scope: minted.scope,
+            expires_at: minted.expires_at,
+        }
+    }
+}
+
+/// A local account, for installations without a login provider.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Record)]
+pub struct LocalAccount {
+    /// Lowercase, and logged in with.
+    pub username: String,
+    pub name: String,
+    /// Who the user is to the rest of the server, as `preferred_username` is for providers.
+    pub email: String,
+    /// The Argon2 hash of the password, as a PHC string.
+    pub hash: String,
+    pub created_at: DateTime<Utc>,
+    pub password_changed_at: Option<DateTime<Utc>>,
+}
+
+/// The credentials of a local account.
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct LocalLogin {
+    pub username: String,
+    pub password: String,
+}
+
+/// A new password for the local account of the logged in user.
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct PasswordChange {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+/// A logged in session, as listed to admins.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, ToSchema)]
+pub struct SessionInfo {
+    /// The provider the user logged in with.
+    pub provider: Option<String>,
+    pub name: String,
+    pub email: String,
+    pub logged_in_at: Option<DateTime<Utc>>,
+    pub expires_at: DateTime<Utc>,
+    /// Whether the session is renewed with a refresh token when it expires.
+    pub renewable: bool,
+}
+
+impl SessionInfo {
+    /// The info of a logged in session, none for a login in progress.
+    pub fn of(session: SessionRecord) -> Option<Self> {
+        let token = session.token?;
+        Some(SessionInfo {
+            provider: session.provider,
+            name: token.name,
+            email: token.preferred_username,
+            logged_in_at: session.created_at,
+            expires_at: token.exp,
+            renewable: session.refresh_token.is_some(),
+        })
+    }
+}
+
+/// Why an ID token was rejected, as the `reason` in the details of the 401.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenError {
+    Malformed,
+    /// The header names no signing key.
+    MissingKid,
+    /// The provider has no key with the `kid` of the header, even after fetching its keys again.
+    UnknownKid,
+    UnsupportedAlgorithm,
+    /// Not signed with the key of the `kid`.
+    Signature,
+    /// Not issued to our client.
+    Audience,
+    /// Not issued by the provider the login started at.
+    Issuer,
+    /// Lacks `exp`, `iat`, `iss` or `aud`.
+    MissingClaim,
+    Expired,
+    /// Not valid before a time to come, by `nbf` or `iat`.
+    NotYetValid,
+    /// Any other failure to decode the claims.
+    Invalid,
+    /// Not the nonce of the login session.
+    Nonce,
+    /// The nonce of the login session was already used.
+    Replayed,
+}
+
+impl TokenError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            TokenError::Malformed => "Malformed ID token",
+            TokenError::MissingKid => "The ID token names no signing key",
+            TokenError::UnknownKid => "Unknown ID token signing key",
+            TokenError::UnsupportedAlgorithm => "Unsupported ID token algorithm",
+            TokenError::Signature => "Invalid ID token signature",
+            TokenError::Audience => "The ID token was issued to another client",
+            TokenError::Issuer => "The ID token was issued by another provider",
+            TokenError::MissingClaim => "The ID token lacks a required claim",
+            TokenError::Expired => "Expired ID token",
+            TokenError::NotYetValid => "The ID token is not valid yet",
+            TokenError::Invalid => "Invalid ID token",
+            TokenError::Nonce => "Failed to validate ID token nonce",
+            TokenError::Replayed => "The login session was already used",
+        }
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for TokenError {
+    fn from(e: jsonwebtoken::errors::Error) -> Self {
+        use jsonwebtoken::errors::ErrorKind;
+        match e.kind() {
+            ErrorKind::InvalidSignature => TokenError::Signature,
+            ErrorKind::InvalidAudience => TokenError::Audience,
+            ErrorKind::InvalidIssuer => TokenError::Issuer,
+            ErrorKind::MissingRequiredClaim(_) => TokenError::MissingClaim,
+            ErrorKind::ExpiredSignature => TokenError::Expired,
+            ErrorKind::ImmatureSignature => TokenError::NotYetValid,
+            ErrorKind::InvalidAlgorithm => TokenError::UnsupportedAlgorithm,
+            _ => TokenError::Invalid,
+        }
+    }
+}
+
+impl From<TokenError> for Error {
+    fn from(e: TokenError) -> Self {
+        Error::unauthorized(e.message()).with_details(serde_json::json!({ "reason": e }))
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
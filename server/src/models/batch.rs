@@ -0,0 +1,96 @@
+use super::generic::Error;
+use super::refs::{HRef, QRef};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// A change to the topic graph, run as part of a batch.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, ToSchema)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    /// Create a topic, keyed by its name.
+    CreateTopic { name: String },
+    /// Give the topic with this key a new name, the key is kept.
+    RenameTopic { topic: String, name: String },
+    #[serde(rename = "link_qref")]
+    LinkQRef { topic: String, qref: QRef },
+    #[serde(rename = "link_href")]
+    LinkHRef { topic: String, href: HRef },
+    #[serde(rename = "unlink_qref")]
+    UnlinkQRef { topic: String, qref: QRef },
+    #[serde(rename = "unlink_href")]
+    UnlinkHRef { topic: String, href: HRef },
+}
+
+impl Operation {
+    /// Whether the operation takes a reference off a topic.
+    pub fn unlinks(&self) -> bool {
+        matches!(
+            self,
+            Operation::UnlinkQRef { .. } | Operation::UnlinkHRef { .. }
+        )
+    }
+}
+
+/// Operations run in a single transaction, all or nothing.
+#[derive(Serialize, Deserialize, Debug, PartialEq, ToSchema)]
+pub struct Batch {
+    pub operations: Vec<Operation>,
+}
+
+impl Batch {
+    pub const MAX_OPERATIONS: usize = 500;
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum OpStatus {
+    /// Applied and committed.
+    Ok,
+    /// Applied, then undone because a later operation failed.
+    RolledBack,
+    /// The operation that failed the batch.
+    Failed,
+    /// Not run because an earlier operation failed.
+    Skipped,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, ToSchema)]
+pub struct OpResult {
+    /// The position of the operation in the batch.
+    pub index: usize,
+    pub status: OpStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<Error>,
+}
+
+impl OpResult {
+    pub fn new(index: usize, status: OpStatus) -> Self {
+        OpResult {
+            index,
+            status,
+            error: None,
+        }
+    }
+
+    pub fn failed(index: usize, error: Error) -> Self {
+        OpResult {
+            index,
+            status: OpStatus::Failed,
+            error: Some(error),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, ToSchema)]
+pub struct BatchResult {
+    /// Whether the changes were kept.
+    pub committed: bool,
+    pub results: Vec<OpResult>,
+}
+
+impl BatchResult {
+    /// The error that failed the batch, if any.
+    pub fn error(&self) -> Option<&Error> {
+        self.results.iter().find_map(|r| r.error.as_ref())
+    }
+}
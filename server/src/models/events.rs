@@ -0,0 +1,88 @@
+use super::refs::RefEnum;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    TopicCreated,
+    /// Renamed, or replaced with `PUT`.
+    TopicUpdated,
+    TopicDeleted,
+    RefLinked,
+    RefUnlinked,
+    /// Only sent to a webhook, to test it.
+    Ping,
+    /// Only sent to a client of `/events` resuming after an event that is no longer kept.
+    /// It missed events, so it should reload what it shows.
+    Reset,
+}
+
+impl EventKind {
+    /// The name of the event in the stream.
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::TopicCreated => "topic_created",
+            EventKind::TopicUpdated => "topic_updated",
+            EventKind::TopicDeleted => "topic_deleted",
+            EventKind::RefLinked => "ref_linked",
+            EventKind::RefUnlinked => "ref_unlinked",
+            EventKind::Ping => "ping",
+            EventKind::Reset => "reset",
+        }
+    }
+}
+
+/// A change to the graph, sent to the clients listening on `/events`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, ToSchema)]
+pub struct Event {
+    /// Increases by one with each event. It starts at the time the server started,
+    /// in microseconds, so it keeps increasing across restarts.
+    pub id: u64,
+    #[serde(rename = "type")]
+    pub kind: EventKind,
+    /// The key of the topic that changed, or whose references changed.
+    pub topic: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<RefEnum>,
+    pub at: DateTime<Utc>,
+}
+
+impl Event {
+    /// An event about a topic, numbered when it is published.
+    pub fn topic(kind: EventKind, topic: &str) -> Self {
+        Event {
+            id: 0,
+            kind,
+            topic: topic.to_string(),
+            reference: None,
+            at: Utc::now(),
+        }
+    }
+
+    /// A reset, with the id of the last event published, to resume after when reconnecting.
+    pub fn reset(last_id: u64) -> Self {
+        Event {
+            id: last_id,
+            ..Event::topic(EventKind::Reset, "")
+        }
+    }
+
+    /// An event about a reference of a topic, numbered when it is published.
+    pub fn reference(kind: EventKind, topic: &str, reference: RefEnum) -> Self {
+        Event {
+            reference: Some(reference),
+            ..Event::topic(kind, topic)
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct EventParams {
+    /// Only the events of the topic with this key.
+    pub topic: Option<String>,
+    /// Resume after this event, for clients that cannot send a `Last-Event-ID` header.
+    pub last_event_id: Option<u64>,
+}
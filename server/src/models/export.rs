@@ -0,0 +1,147 @@
+use super::refs::RefEnum;
+use super::topics::Topic;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+/// Identifies an export document, whatever backend wrote it.
+pub const EXPORT_FORMAT: &str = "islamic-references-graph";
+/// Bumped whenever an older reader could not read the document losslessly.
+pub const EXPORT_VERSION: u32 = 1;
+
+/// The first entry of an export.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, ToSchema)]
+pub struct Header {
+    pub format: String,
+    pub version: u32,
+    pub exported_at: DateTime<Utc>,
+}
+
+impl Header {
+    pub fn now() -> Self {
+        Header {
+            format: EXPORT_FORMAT.to_string(),
+            version: EXPORT_VERSION,
+            exported_at: Utc::now(),
+        }
+    }
+}
+
+/// A reference, with the id edges point at it by.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, ToSchema)]
+pub struct ExportRef {
+    pub id: String,
+    #[serde(flatten)]
+    pub reference: RefEnum,
+}
+
+/// A topic pointing at a reference.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, ToSchema)]
+pub struct ExportEdge {
+    pub id: String,
+    /// The key of the topic.
+    pub topic: String,
+    /// The id of the reference.
+    #[serde(rename = "ref")]
+    pub reference: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+/// One line of an NDJSON export, or one element of `entries` in a JSON export.
+/// Topics and references come before the edges between them.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Entry {
+    Header(Header),
+    Topic(Topic),
+    Ref(ExportRef),
+    Edge(ExportEdge),
+}
+
+impl Entry {
+    /// The stable id of the entry, which exports are ordered by within each kind.
+    pub fn id(&self) -> Option<&str> {
+        match self {
+            Entry::Header(_) => None,
+            Entry::Topic(t) => t.key.as_deref(),
+            Entry::Ref(r) => Some(&r.id),
+            Entry::Edge(e) => Some(&e.id),
+        }
+    }
+}
+
+/// The kinds of entries, in the order they are exported.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EntryKind {
+    Topics,
+    Refs,
+    Edges,
+}
+
+impl EntryKind {
+    pub fn next(&self) -> Option<EntryKind> {
+        match self {
+            EntryKind::Topics => Some(EntryKind::Refs),
+            EntryKind::Refs => Some(EntryKind::Edges),
+            EntryKind::Edges => None,
+        }
+    }
+}
+
+/// A whole export as one JSON document.
+#[derive(Serialize, Deserialize, Debug, PartialEq, ToSchema)]
+pub struct Document {
+    #[serde(flatten)]
+    pub header: Header,
+    pub entries: Vec<Entry>,
+}
+
+#[derive(
+    Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default, ToSchema, clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// One JSON document.
+    #[default]
+    Json,
+    /// A header line, then one entry per line.
+    Ndjson,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "application/json",
+            ExportFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    /// Guess the format from a file name, if it is an export at all.
+    pub fn from_path(path: &str) -> Option<Self> {
+        let path = path.to_lowercase();
+        if path.ends_with(".ndjson") || path.ends_with(".jsonl") {
+            Some(ExportFormat::Ndjson)
+        } else if path.ends_with(".json") {
+            Some(ExportFormat::Json)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExportParams {
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+/// What restoring an export wrote, or would write on a dry run.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct RestoreReport {
+    pub dry_run: bool,
+    pub topics: usize,
+    pub refs: usize,
+    pub edges: usize,
+}
@@ -1,25 +1,78 @@
 use actix_web::{
-    body::BoxBody, http::StatusCode, HttpRequest, HttpResponse, HttpResponseBuilder, Responder,
-    ResponseError,
+    body::BoxBody,
+    http::header::{Accept, Header, LINK},
+    http::StatusCode,
+    web::Bytes,
+    HttpRequest, HttpResponse, HttpResponseBuilder, Responder, ResponseError,
 };
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::fmt::Debug;
+use utoipa::{IntoParams, ToSchema};
 
-#[derive(Deserialize, Serialize, Debug, PartialEq)]
+use super::refs::QRef;
+
+/// The kind of an error.
+/// Serialized as the stable, machine readable `code` of the error body.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Copy, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// The requested item does not exist.
+    NotFound,
+    /// The item already exists or was changed concurrently.
+    Conflict,
+    /// A conditional request header, such as `If-None-Match`, did not hold.
+    PreconditionFailed,
+    /// The request is malformed or breaks a rule.
+    Validation,
+    /// The caller is not logged in.
+    Unauthorized,
+    /// The caller is logged in but not allowed to do this.
+    Forbidden,
+    /// The caller tried too often, and has to wait.
+    TooManyRequests,
+    /// A service we depend on, such as the identity provider, failed.
+    Upstream,
+    /// The database failed.
+    Storage,
+    /// Anything else, a bug on our side.
+    Internal,
+}
+
+impl ErrorKind {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::Conflict => StatusCode::CONFLICT,
+            ErrorKind::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            ErrorKind::Validation => StatusCode::BAD_REQUEST,
+            ErrorKind::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorKind::Forbidden => StatusCode::FORBIDDEN,
+            ErrorKind::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            ErrorKind::Upstream => StatusCode::BAD_GATEWAY,
+            ErrorKind::Storage | ErrorKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, ToSchema)]
 pub struct Error {
+    code: ErrorKind,
     message: String,
+    /// Structured information about the error, such as the offending item.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Object)]
+    details: Option<Value>,
     version: String,
-    #[serde(skip_serializing)]
-    code: u16,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, ToSchema)]
 pub struct Health {
     status: Vec<HealthStatus>,
     version: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, ToSchema)]
 pub struct HealthStatus {
     component: String,
     status: String,
@@ -27,27 +80,77 @@ pub struct HealthStatus {
     healthy: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, ToSchema)]
 pub struct Generic {
     message: String,
     version: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct Pagination {
-    #[serde(default = "Pagination::default_page")]
-    pub page: u32,
+    /// Opaque cursor from a previous page's `next_cursor` or `prev_cursor`.
+    pub cursor: Option<String>,
+    /// The page size, at most 200.
     #[serde(default = "Pagination::default_size")]
     pub size: u32,
 }
 
 impl Pagination {
-    fn default_page() -> u32 {
-        1
-    }
+    pub const MAX_SIZE: u32 = 200;
+
     fn default_size() -> u32 {
         50
     }
+
+    /// The page size, clamped to `1..=MAX_SIZE`.
+    pub fn size(&self) -> u32 {
+        self.size.clamp(1, Self::MAX_SIZE)
+    }
+
+    /// The decoded cursor, if any.
+    pub fn cursor(&self) -> Result<Option<Cursor>, Error> {
+        self.cursor.as_deref().map(Cursor::decode).transpose()
+    }
+}
+
+/// A position in an ordered listing.
+/// Points at an item by its sort value and key, so pages stay correct when
+/// items are added or removed before it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Cursor {
+    #[serde(rename = "v")]
+    pub value: Value,
+    #[serde(rename = "k")]
+    pub key: String,
+    /// Page backwards from the item instead of forwards.
+    #[serde(rename = "b")]
+    pub backward: bool,
+}
+
+impl Cursor {
+    pub fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).expect("Cursor is always serializable");
+        base64::encode_config(json, base64::URL_SAFE_NO_PAD)
+    }
+
+    pub fn decode(s: &str) -> Result<Self, Error> {
+        base64::decode_config(s, base64::URL_SAFE_NO_PAD)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or_else(|| Error::validation("Invalid cursor"))
+    }
+}
+
+/// A page of a listing.
+#[derive(Serialize, Deserialize, Debug, PartialEq, ToSchema)]
+#[aliases(TopicPage = Page<String>, QRefPage = Page<QRef>)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// The number of items in the whole listing.
+    pub total: u64,
+    pub next_cursor: Option<String>,
+    pub prev_cursor: Option<String>,
 }
 
 impl Health {
@@ -109,21 +212,95 @@ impl Generic {
 }
 
 impl Error {
-    pub fn new<E: Debug>(e: E, code: StatusCode) -> Error {
+    /// Creates an error with a message that is safe to show to clients.
+    pub fn new<M: Into<String>>(kind: ErrorKind, message: M) -> Error {
         let version = env!("CARGO_PKG_VERSION").to_string();
         Error {
-            message: format!("{:?}", e),
+            code: kind,
+            message: message.into(),
+            details: None,
             version,
-            code: code.as_u16(),
         }
     }
 
-    pub fn default<E: Debug>(e: E) -> Self {
-        let version = env!("CARGO_PKG_VERSION").to_string();
-        Error {
-            message: format!("{:?}", e),
-            version,
-            code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+    pub fn not_found<M: Into<String>>(message: M) -> Self {
+        Error::new(ErrorKind::NotFound, message)
+    }
+
+    pub fn conflict<M: Into<String>>(message: M) -> Self {
+        Error::new(ErrorKind::Conflict, message)
+    }
+
+    pub fn precondition_failed<M: Into<String>>(message: M) -> Self {
+        Error::new(ErrorKind::PreconditionFailed, message)
+    }
+
+    pub fn validation<M: Into<String>>(message: M) -> Self {
+        Error::new(ErrorKind::Validation, message)
+    }
+
+    pub fn unauthorized<M: Into<String>>(message: M) -> Self {
+        Error::new(ErrorKind::Unauthorized, message)
+    }
+
+    pub fn forbidden<M: Into<String>>(message: M) -> Self {
+        Error::new(ErrorKind::Forbidden, message)
+    }
+
+    pub fn too_many_requests<M: Into<String>>(message: M) -> Self {
+        Error::new(ErrorKind::TooManyRequests, message)
+    }
+
+    /// A failure of an external service.
+    /// The cause is logged, clients only get a generic message.
+    pub fn upstream<E: Debug>(e: E) -> Self {
+        log::error!("Upstream error: {:?}", e);
+        Error::new(ErrorKind::Upstream, "An upstream service failed")
+    }
+
+    /// A failure of the database.
+    /// The cause is logged, clients only get a generic message.
+    pub fn storage<E: Debug>(e: E) -> Self {
+        log::error!("Storage error: {:?}", e);
+        Error::new(ErrorKind::Storage, "A database operation failed")
+    }
+
+    /// A bug on our side.
+    /// The cause is logged, clients only get a generic message.
+    pub fn internal<E: Debug>(e: E) -> Self {
+        log::error!("Internal error: {:?}", e);
+        Error::new(ErrorKind::Internal, "Internal server error")
+    }
+
+    pub fn with_details(mut self, details: Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.code
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    #[cfg(test)]
+    pub fn details(&self) -> Option<&Value> {
+        self.details.as_ref()
+    }
+}
+
+impl From<aragog::Error> for Error {
+    fn from(e: aragog::Error) -> Self {
+        match e {
+            aragog::Error::NotFound { item, id, .. } => {
+                Error::not_found(format!("{} not found", item))
+                    .with_details(serde_json::json!({ "item": item, "id": id }))
+            }
+            aragog::Error::Conflict(_) => Error::conflict("The item already exists"),
+            aragog::Error::ValidationError(message) => Error::validation(message),
+            e => Error::storage(e),
         }
     }
 }
@@ -131,10 +308,157 @@ impl Error {
 impl Responder for Error {
     type Body = BoxBody;
     fn respond_to(self, _: &HttpRequest) -> HttpResponse<BoxBody> {
-        let code = StatusCode::from_u16(self.code).expect("Invalid response code");
-        HttpResponseBuilder::new(code).json(self)
+        HttpResponseBuilder::new(self.code.status()).json(self)
+    }
+}
+
+/// A representation a response can be negotiated into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    JsonLd,
+    Csv,
+    Ndjson,
+}
+
+impl Format {
+    /// The formats lists are offered in.
+    pub const LISTS: [Format; 3] = [Format::Json, Format::Csv, Format::Ndjson];
+
+    pub fn mime(&self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::JsonLd => "application/ld+json",
+            Format::Csv => "text/csv",
+            Format::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    /// The offered format the client ranks highest in `Accept`,
+    /// or the first one offered if it accepts none of them.
+    pub fn negotiate(req: &HttpRequest, offered: &[Format]) -> Format {
+        let ranked = Accept::parse(req).map(|a| a.ranked()).unwrap_or_default();
+        ranked
+            .iter()
+            .find_map(|m| offered.iter().find(|f| f.mime() == m.essence_str()))
+            .copied()
+            .unwrap_or(offered[0])
+    }
+}
+
+/// An item of a list, as a row of a CSV table.
+pub trait Row: Serialize {
+    fn headers() -> &'static [&'static str];
+    fn fields(&self) -> Vec<String>;
+}
+
+/// Topic names.
+impl Row for String {
+    fn headers() -> &'static [&'static str] {
+        &["topic"]
+    }
+
+    fn fields(&self) -> Vec<String> {
+        vec![self.clone()]
+    }
+}
+
+/// A list without pagination, negotiated like a page.
+pub struct List<T>(pub Vec<T>);
+
+impl<T: Row> Responder for List<T> {
+    type Body = BoxBody;
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse<BoxBody> {
+        match Format::negotiate(req, &Format::LISTS) {
+            Format::Json => HttpResponse::Ok().json(self.0),
+            format => rows(format, &self.0, HttpResponse::Ok()),
+        }
+    }
+}
+
+impl<T: Row> Responder for Page<T> {
+    type Body = BoxBody;
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse<BoxBody> {
+        let format = Format::negotiate(req, &Format::LISTS);
+        if format == Format::Json {
+            return HttpResponse::Ok().json(self);
+        }
+
+        // Rows have no envelope, so the rest of the page goes in headers
+        let mut builder = HttpResponse::Ok();
+        builder.insert_header(("X-Total-Count", self.total.to_string()));
+        let links: Vec<String> = [("next", &self.next_cursor), ("prev", &self.prev_cursor)]
+            .into_iter()
+            .filter_map(|(rel, cursor)| {
+                let url = page_url(req, cursor.as_ref()?);
+                Some(format!("<{}>; rel=\"{}\"", url, rel))
+            })
+            .collect();
+        if !links.is_empty() {
+            builder.insert_header((LINK, links.join(", ")));
+        }
+        rows(format, &self.items, builder)
+    }
+}
+
+/// The URL of the request, at another cursor.
+fn page_url(req: &HttpRequest, cursor: &str) -> String {
+    let query = url::form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(
+            url::form_urlencoded::parse(req.query_string().as_bytes())
+                .filter(|(k, _)| k != "cursor"),
+        )
+        .append_pair("cursor", cursor)
+        .finish();
+    format!("{}?{}", req.path(), query)
+}
+
+/// Responds with items as a CSV table, or one JSON document per line.
+fn rows<T: Row>(format: Format, items: &[T], mut builder: HttpResponseBuilder) -> HttpResponse {
+    builder.content_type(format.mime());
+    match format {
+        Format::Csv => match table(items) {
+            Ok(body) => builder.body(body),
+            Err(e) => e.error_response(),
+        },
+        Format::Ndjson => {
+            let lines: Vec<Result<Bytes, Error>> = items
+                .iter()
+                .map(|item| {
+                    let mut line = serde_json::to_vec(item).map_err(Error::internal)?;
+                    line.push(b'\n');
+                    Ok(Bytes::from(line))
+                })
+                .collect();
+            builder.streaming(futures::stream::iter(lines))
+        }
+        _ => builder.json(items),
+    }
+}
+
+/// The field as a CSV cell, quoted with `'` when a spreadsheet would run it as a formula.
+fn cell(field: String) -> String {
+    if field.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{}", field)
+    } else {
+        field
+    }
+}
+
+fn table<T: Row>(items: &[T]) -> Result<Bytes, Error> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    writer.write_record(T::headers()).map_err(Error::internal)?;
+    for item in items {
+        writer
+            .write_record(item.fields().into_iter().map(cell))
+            .map_err(Error::internal)?;
     }
+    writer
+        .into_inner()
+        .map(Bytes::from)
+        .map_err(Error::internal)
 }
+
 impl Responder for Generic {
     type Body = actix_web::body::BoxBody;
     fn respond_to(self, _: &HttpRequest) -> HttpResponse<actix_web::body::BoxBody> {
@@ -150,7 +474,7 @@ impl core::fmt::Display for Error {
 
 impl ResponseError for Error {
     fn status_code(&self) -> StatusCode {
-        StatusCode::from_u16(self.code).expect("Should have a valid status code")
+        self.code.status()
     }
 
     fn error_response(&self) -> HttpResponse<BoxBody> {
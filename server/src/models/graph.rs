@@ -0,0 +1,74 @@
+use super::export::ExportRef;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use utoipa::{IntoParams, ToSchema};
+
+/// A topic node, with the key of its parent topic if any.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct GraphTopic {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    #[serde(default)]
+    pub names: HashMap<String, String>,
+    pub parent: Option<String>,
+}
+
+/// A topic pointing at a reference.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct GraphEdge {
+    pub source: String,
+    pub target: String,
+}
+
+/// The topic graph, or part of it, with node ids as stored in the database.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub struct Graph {
+    pub topics: Vec<GraphTopic>,
+    pub refs: Vec<ExportRef>,
+    pub edges: Vec<GraphEdge>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default, ToSchema, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum GraphFormat {
+    /// For Gephi and yEd.
+    #[default]
+    #[clap(name = "graphml")]
+    GraphMl,
+    /// For Graphviz.
+    Dot,
+    /// The topics as SKOS concepts, for linked data tools.
+    Turtle,
+}
+
+impl GraphFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            GraphFormat::GraphMl => "application/graphml+xml",
+            GraphFormat::Dot => "text/vnd.graphviz",
+            GraphFormat::Turtle => "text/turtle",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct GraphParams {
+    #[serde(default)]
+    pub format: GraphFormat,
+    /// Only the part of the graph around this topic key.
+    pub root: Option<String>,
+    /// How many edges away from the root to go, at most 6.
+    #[serde(default = "GraphParams::default_depth")]
+    pub depth: u32,
+}
+
+impl GraphParams {
+    pub const MAX_DEPTH: u32 = 6;
+
+    pub fn default_depth() -> u32 {
+        2
+    }
+}
@@ -0,0 +1,70 @@
+use super::refs::RefEnum;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+/// A row of an import file, with its citation parsed.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ImportRow {
+    /// The line of the row in the file, starting at 1 for the header.
+    pub line: u64,
+    pub topic: String,
+    pub reference: RefEnum,
+    pub note: Option<String>,
+}
+
+/// A row that could not be imported.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, ToSchema)]
+pub struct RowError {
+    pub line: u64,
+    pub message: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default, ToSchema, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportFormat {
+    #[default]
+    Csv,
+    Tsv,
+}
+
+impl ImportFormat {
+    /// Guess the format from a file name, CSV unless it ends in `.tsv` or `.tab`.
+    pub fn from_path(path: &str) -> Self {
+        let path = path.to_lowercase();
+        if path.ends_with(".tsv") || path.ends_with(".tab") {
+            ImportFormat::Tsv
+        } else {
+            ImportFormat::Csv
+        }
+    }
+
+    pub fn delimiter(&self) -> u8 {
+        match self {
+            ImportFormat::Csv => b',',
+            ImportFormat::Tsv => b'\t',
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ImportParams {
+    /// Report what would change without changing anything.
+    #[serde(default)]
+    pub dry_run: bool,
+    /// The format of the body, `csv` unless the content type says otherwise.
+    pub format: Option<ImportFormat>,
+}
+
+/// What an import changed, or would change on a dry run.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default, ToSchema)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    /// The number of data rows in the file.
+    pub rows: usize,
+    pub topics_created: usize,
+    pub refs_linked: usize,
+    /// Rows that were already imported.
+    pub unchanged: usize,
+    pub errors: Vec<RowError>,
+}
@@ -1,4 +1,10 @@
 pub mod auth;
+pub mod batch;
+pub mod events;
+pub mod export;
 pub mod generic;
+pub mod graph;
+pub mod import;
 pub mod refs;
 pub mod topics;
+pub mod webhooks;
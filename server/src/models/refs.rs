@@ -1,40 +1,84 @@
-use super::generic::Error;
+use super::generic::{Error, Row};
 use crate::Result as CResult;
 use actix_web::{body::BoxBody, HttpRequest, HttpResponse, Responder};
 use aragog::Record;
 use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
+use utoipa::{IntoParams, ToSchema};
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Record)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Record, ToSchema)]
 pub struct QRef {
     pub chapter: usize,
     pub init_verse: usize,
     pub final_verse: usize,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Record)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Record, ToSchema)]
 pub struct HRef {
     pub collection: String,
     pub number: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, ToSchema)]
 pub struct BRef {
     pub isbn: String,
     pub name: String,
     pub page: i64,
 }
 
-#[derive(Serialize, Deserialize, Clone, Record)]
-pub struct RefEdge {}
+#[derive(Serialize, Deserialize, Clone, Default, Record)]
+pub struct RefEdge {
+    /// Why the topic points at the reference.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, ToSchema)]
 #[serde(untagged)] // Removes the tags when serialising and deserialising
 pub enum RefEnum {
     Q(QRef),
     H(HRef),
     B(BRef),
 }
+
+/// A batch of references to look up the topics of.
+#[derive(Serialize, Deserialize, Debug, PartialEq, ToSchema)]
+pub struct Lookup {
+    pub refs: Vec<RefEnum>,
+}
+
+impl Lookup {
+    pub const MAX_REFS: usize = 100;
+}
+
+/// The topics pointing at a reference.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, ToSchema)]
+pub struct RefTopics {
+    #[serde(rename = "ref")]
+    pub reference: RefEnum,
+    pub topics: Vec<String>,
+}
+
+/// A verse, or a range of verses with `to`, in the path of a lookup.
+#[derive(Deserialize)]
+pub struct VersePath {
+    pub chapter: usize,
+    pub verse: usize,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct VerseRange {
+    /// The last verse of the range.
+    pub to: Option<usize>,
+}
+
+/// A Hadith in the path of a lookup.
+#[derive(Deserialize)]
+pub struct HadithPath {
+    pub collection: String,
+    pub number: String,
+}
 impl Responder for RefEnum {
     type Body = BoxBody;
     fn respond_to(self, _: &HttpRequest) -> HttpResponse<BoxBody> {
@@ -46,15 +90,60 @@ impl RefEnum {
     pub fn is_book(&self) -> bool {
         matches!(self, RefEnum::B(_))
     }
+
+    /// The kind of reference: `qref`, `href` or `bref`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RefEnum::Q(_) => "qref",
+            RefEnum::H(_) => "href",
+            RefEnum::B(_) => "bref",
+        }
+    }
+
+    /// The reference as a citation, which the importer reads back.
+    pub fn citation(&self) -> String {
+        match self {
+            RefEnum::Q(q) if q.init_verse == q.final_verse => {
+                format!("{}:{}", q.chapter, q.init_verse)
+            }
+            RefEnum::Q(q) => format!("{}:{}-{}", q.chapter, q.init_verse, q.final_verse),
+            RefEnum::H(h) => format!("{} {}", h.collection, h.number),
+            RefEnum::B(b) => format!("{} p. {}", b.name, b.page),
+        }
+    }
+}
+
+impl Row for RefEnum {
+    fn headers() -> &'static [&'static str] {
+        &["type", "citation"]
+    }
+
+    fn fields(&self) -> Vec<String> {
+        vec![self.kind().to_string(), self.citation()]
+    }
+}
+
+impl Row for QRef {
+    fn headers() -> &'static [&'static str] {
+        &["chapter", "init_verse", "final_verse"]
+    }
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.chapter.to_string(),
+            self.init_verse.to_string(),
+            self.final_verse.to_string(),
+        ]
+    }
 }
 
 impl QRef {
     pub fn validate(&self) -> CResult<()> {
         if self.chapter > 114 {
-            return Err(Error::default("Invalid chapter number"));
+            return Err(Error::validation("Invalid chapter number"));
         }
         if self.final_verse < self.init_verse {
-            return Err(Error::default("Final verse is before initial verse"));
+            return Err(Error::validation("Final verse is before initial verse"));
         }
         Ok(())
     }
@@ -1,11 +1,26 @@
 use aragog::Record;
+use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use utoipa::{IntoParams, ToSchema};
 
-#[derive(Serialize, Clone, Deserialize, Debug, PartialEq, Record)]
+#[derive(Serialize, Clone, Deserialize, Debug, PartialEq, Record, ToSchema)]
 pub struct Topic {
     #[serde(rename = "_key")]
     pub key: Option<String>,
     pub name: String,
+    /// Alternative names the topic is known by.
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// Translated names, keyed by language code.
+    #[serde(default)]
+    pub names: HashMap<String, String>,
+    /// The key of the broader topic, if any.
+    pub parent: Option<String>,
+    /// The email of the user who created the topic.
+    pub created_by: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub updated_at: Option<DateTime<Utc>>,
 }
 
 impl Topic {
@@ -13,6 +28,84 @@ impl Topic {
         Topic {
             key: name.to_string().into(),
             name: name.to_string(),
+            aliases: vec![],
+            names: HashMap::new(),
+            parent: None,
+            created_by: None,
+            created_at: None,
+            updated_at: None,
         }
     }
 }
+
+/// A topic matching a search query.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, ToSchema)]
+pub struct TopicMatch {
+    pub key: String,
+    pub name: String,
+    /// The name, alias or translation that matched the query.
+    pub matched: String,
+    pub ref_count: u64,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct Search {
+    pub q: String,
+    /// The maximum number of matches, at most 50.
+    #[serde(default = "Search::default_limit")]
+    pub limit: u32,
+}
+
+impl Search {
+    pub const MAX_LIMIT: u32 = 50;
+
+    fn default_limit() -> u32 {
+        10
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TopicSort {
+    #[default]
+    Name,
+    CreatedAt,
+    RefCount,
+}
+
+impl TopicSort {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TopicSort::Name => "name",
+            TopicSort::CreatedAt => "created_at",
+            TopicSort::RefCount => "ref_count",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Sorting and filtering applied to the topic listing.
+#[derive(Deserialize, Debug, Clone, PartialEq, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TopicFilter {
+    #[serde(default)]
+    pub sort: TopicSort,
+    #[serde(default)]
+    pub order: SortOrder,
+    /// Only topics with (or without) references.
+    pub has_refs: Option<bool>,
+    /// Only direct children of this topic key.
+    pub parent: Option<String>,
+    /// Only topics created by this email.
+    pub created_by: Option<String>,
+    /// Only topics updated at or after this time.
+    pub updated_since: Option<DateTime<Utc>>,
+}
@@ -0,0 +1,155 @@
+use super::events::{Event, EventKind};
+use super::generic::Error;
+use aragog::Record;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use url::Url;
+use utoipa::{IntoParams, ToSchema};
+
+/// A URL notified of changes to the graph.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Record)]
+pub struct Webhook {
+    #[serde(rename = "_key", default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    pub url: String,
+    /// The events sent, all of them if empty.
+    #[serde(default)]
+    pub events: Vec<EventKind>,
+    /// Signs deliveries, and is never sent back by the API.
+    pub secret: String,
+    /// The email of the admin who registered the webhook.
+    pub created_by: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+impl Webhook {
+    /// Whether the webhook is sent events of this kind. Pings are always sent.
+    pub fn wants(&self, kind: EventKind) -> bool {
+        kind == EventKind::Ping || self.events.is_empty() || self.events.contains(&kind)
+    }
+}
+
+/// A webhook to register.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, ToSchema)]
+pub struct NewWebhook {
+    /// Where events are posted, over HTTP or HTTPS.
+    pub url: String,
+    /// The events to send, all of them if empty.
+    #[serde(default)]
+    pub events: Vec<EventKind>,
+    /// Deliveries are signed with this, in the `X-Webhook-Signature` header.
+    pub secret: String,
+}
+
+impl NewWebhook {
+    pub const MIN_SECRET_LEN: usize = 16;
+
+    pub fn validate(&self) -> Result<(), Error> {
+        let url = Url::parse(&self.url)
+            .map_err(|e| Error::validation(format!("Invalid URL {}: {}", self.url, e)))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(Error::validation("Webhook URLs must be HTTP or HTTPS"));
+        }
+        if self.secret.len() < Self::MIN_SECRET_LEN {
+            return Err(Error::validation(format!(
+                "The secret must be at least {} characters long",
+                Self::MIN_SECRET_LEN
+            )));
+        }
+        if self.events.contains(&EventKind::Ping) {
+            return Err(Error::validation("Pings are always sent"));
+        }
+        if self.events.contains(&EventKind::Reset) {
+            return Err(Error::validation("Resets are only sent to the change feed"));
+        }
+        Ok(())
+    }
+}
+
+/// A registered webhook, without its secret.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, ToSchema)]
+pub struct WebhookInfo {
+    pub key: String,
+    pub url: String,
+    pub events: Vec<EventKind>,
+    pub created_by: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+impl From<Webhook> for WebhookInfo {
+    fn from(hook: Webhook) -> Self {
+        WebhookInfo {
+            key: hook.key.unwrap_or_default(),
+            url: hook.url,
+            events: hook.events,
+            created_by: hook.created_by,
+            created_at: hook.created_at,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    /// Waiting to be tried again.
+    Pending,
+    Delivered,
+    /// Every attempt failed.
+    Failed,
+}
+
+/// One try at delivering an event.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, ToSchema)]
+pub struct Attempt {
+    pub at: DateTime<Utc>,
+    /// The status the receiver answered with, if it answered.
+    pub status_code: Option<u16>,
+    pub error: Option<String>,
+}
+
+impl Attempt {
+    pub fn succeeded(&self) -> bool {
+        matches!(self.status_code, Some(200..=299))
+    }
+}
+
+/// An event sent to a webhook, with every attempt at sending it.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Record, ToSchema)]
+pub struct WebhookDelivery {
+    #[serde(rename = "_key", default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// The key of the webhook.
+    pub webhook: String,
+    pub event: Event,
+    pub status: DeliveryStatus,
+    #[serde(default)]
+    pub attempts: Vec<Attempt>,
+}
+
+impl WebhookDelivery {
+    pub fn new(webhook: &str, event: Event) -> Self {
+        WebhookDelivery {
+            key: None,
+            webhook: webhook.to_string(),
+            event,
+            status: DeliveryStatus::Pending,
+            attempts: vec![],
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DeliveryParams {
+    /// The number of deliveries, newest first, at most 200.
+    #[serde(default = "DeliveryParams::default_limit")]
+    pub limit: u32,
+}
+
+impl DeliveryParams {
+    pub const MAX_LIMIT: u32 = 200;
+
+    pub fn default_limit() -> u32 {
+        50
+    }
+}